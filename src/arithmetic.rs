@@ -0,0 +1,135 @@
+use crate::ast::{BinaryOperator, LiteralValue};
+
+// Integer arithmetic that follows the semantics of the Java-based critter interpreter, so that a
+// value folded at compile time always matches the value computed in the critter world.
+
+/// Addition wraps around on overflow, like Java `int` addition.
+pub fn critter_plus(i1: i32, i2: i32) -> i32 {
+  i1.wrapping_add(i2)
+}
+
+/// Subtraction wraps around on overflow, like Java `int` subtraction.
+pub fn critter_minus(i1: i32, i2: i32) -> i32 {
+  i1.wrapping_sub(i2)
+}
+
+/// Multiplication wraps around on overflow, like Java `int` multiplication.
+pub fn critter_multiply(i1: i32, i2: i32) -> i32 {
+  i1.wrapping_mul(i2)
+}
+
+/// Division truncates toward zero. Division by zero evaluates to 0 in the critter world.
+pub fn critter_divide(i1: i32, i2: i32) -> i32 {
+  if i2 == 0 {
+    0
+  } else {
+    i1.wrapping_div(i2)
+  }
+}
+
+/// The remainder takes the sign of the dividend, like Java `%`. Modulo by zero evaluates to 0 in
+/// the critter world.
+pub fn critter_modulo(i1: i32, i2: i32) -> i32 {
+  if i2 == 0 {
+    0
+  } else {
+    i1.wrapping_rem(i2)
+  }
+}
+
+/// Applies `operator` to two literal operands. Returns `None` when the operand kinds do not match
+/// the operator, which can only happen for programs that are not well-typed.
+pub fn apply_binary_operator(
+  operator: BinaryOperator,
+  l1: LiteralValue,
+  l2: LiteralValue,
+) -> Option<LiteralValue> {
+  match (l1, l2) {
+    (LiteralValue::IntLiteral(i1), LiteralValue::IntLiteral(i2)) => match operator {
+      BinaryOperator::MUL => Some(LiteralValue::IntLiteral(critter_multiply(i1, i2))),
+      BinaryOperator::DIV => Some(LiteralValue::IntLiteral(critter_divide(i1, i2))),
+      BinaryOperator::MOD => Some(LiteralValue::IntLiteral(critter_modulo(i1, i2))),
+      BinaryOperator::PLUS => Some(LiteralValue::IntLiteral(critter_plus(i1, i2))),
+      BinaryOperator::MINUS => Some(LiteralValue::IntLiteral(critter_minus(i1, i2))),
+      BinaryOperator::LT => Some(LiteralValue::BoolLiteral(i1 < i2)),
+      BinaryOperator::LE => Some(LiteralValue::BoolLiteral(i1 <= i2)),
+      BinaryOperator::GT => Some(LiteralValue::BoolLiteral(i1 > i2)),
+      BinaryOperator::GE => Some(LiteralValue::BoolLiteral(i1 >= i2)),
+      BinaryOperator::EQ => Some(LiteralValue::BoolLiteral(i1 == i2)),
+      BinaryOperator::NE => Some(LiteralValue::BoolLiteral(i1 != i2)),
+      BinaryOperator::AND | BinaryOperator::OR => None,
+    },
+    (LiteralValue::BoolLiteral(b1), LiteralValue::BoolLiteral(b2)) => match operator {
+      BinaryOperator::AND => Some(LiteralValue::BoolLiteral(b1 && b2)),
+      BinaryOperator::OR => Some(LiteralValue::BoolLiteral(b1 || b2)),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn division_truncates_toward_zero() {
+    assert_eq!(critter_divide(7, 2), 3);
+    assert_eq!(critter_divide(-7, 2), -3);
+    assert_eq!(critter_divide(7, -2), -3);
+    assert_eq!(critter_divide(-7, -2), 3);
+  }
+
+  #[test]
+  fn remainder_takes_the_sign_of_the_dividend() {
+    assert_eq!(critter_modulo(7, 3), 1);
+    assert_eq!(critter_modulo(-7, 3), -1);
+    assert_eq!(critter_modulo(7, -3), 1);
+    assert_eq!(critter_modulo(-7, -3), -1);
+  }
+
+  #[test]
+  fn overflow_wraps_around() {
+    assert_eq!(critter_plus(i32::MAX, 1), i32::MIN);
+    assert_eq!(critter_minus(i32::MIN, 1), i32::MAX);
+    assert_eq!(critter_multiply(i32::MAX, 2), -2);
+    assert_eq!(critter_divide(i32::MIN, -1), i32::MIN);
+    assert_eq!(critter_modulo(i32::MIN, -1), 0);
+  }
+
+  #[test]
+  fn division_and_modulo_by_zero_evaluate_to_zero() {
+    assert_eq!(critter_divide(5, 0), 0);
+    assert_eq!(critter_divide(i32::MIN, 0), 0);
+    assert_eq!(critter_modulo(5, 0), 0);
+    assert_eq!(critter_modulo(-5, 0), 0);
+    assert_eq!(
+      apply_binary_operator(
+        BinaryOperator::DIV,
+        LiteralValue::IntLiteral(5),
+        LiteralValue::IntLiteral(0)
+      ),
+      Some(LiteralValue::IntLiteral(0))
+    );
+  }
+
+  #[test]
+  fn mismatched_operands_do_not_fold() {
+    assert_eq!(
+      apply_binary_operator(
+        BinaryOperator::PLUS,
+        LiteralValue::BoolLiteral(true),
+        LiteralValue::IntLiteral(1)
+      ),
+      None
+    );
+    assert_eq!(
+      apply_binary_operator(
+        BinaryOperator::AND,
+        LiteralValue::IntLiteral(1),
+        LiteralValue::IntLiteral(1)
+      ),
+      None
+    );
+  }
+}
@@ -28,6 +28,9 @@ pub enum ExpressionStaticType {
   VoidType,
   IntType,
   BoolType,
+  /// The type given to expressions whose type cannot be determined because of an earlier error.
+  /// It is compatible with every type so that one mistake produces only one diagnostic.
+  ErrorType,
 }
 
 pub fn pretty_print_expression_static_type(t: ExpressionStaticType) -> String {
@@ -35,6 +38,7 @@ pub fn pretty_print_expression_static_type(t: ExpressionStaticType) -> String {
     ExpressionStaticType::VoidType => "void".to_string(),
     ExpressionStaticType::IntType => "int".to_string(),
     ExpressionStaticType::BoolType => "bool".to_string(),
+    ExpressionStaticType::ErrorType => "error".to_string(),
   }
 }
 
@@ -44,6 +48,11 @@ pub struct FunctionType {
   pub return_type: ExpressionStaticType,
 }
 
+/// `choose { w1 => e1, w2 => e2 }` parses to a call of this name whose arguments alternate between
+/// the weights and the branches. It is lowered right after checking, so later passes never see the
+/// call. No identifier starts with `_`, so it cannot clash with a function named `choose`.
+pub const CHOOSE_FUNCTION_NAME: &str = "_chooseWeighted";
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 pub enum SourceLanguageExpression {
   LiteralExpression {
@@ -87,7 +96,12 @@ pub enum SourceLanguageExpression {
 pub struct SourceLanguageMutableGlobalVariableDefinition {
   pub line_number: usize,
   pub identifier: String,
-  pub assigned_value: i32,
+  /// Evaluated once at birth, in declaration order. It may read sensors and the globals declared
+  /// before it.
+  pub assigned_expression: Box<SourceLanguageExpression>,
+  /// A `tmp` variable, which does not keep its value across turns. It has no initial value, and
+  /// temporaries that are never live at the same time share a memory slot.
+  pub is_temporary: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
@@ -95,13 +109,68 @@ pub struct SourceLanguageFunctionDefinition {
   pub line_number: usize,
   pub identifier: String,
   pub function_arguments: Vec<(String, ExpressionStaticType)>,
-  pub return_type: ExpressionStaticType,
+  /// `None` for functions without a return type annotation, until the checker infers the type
+  /// from the body. Checked programs always have one.
+  pub return_type: Option<ExpressionStaticType>,
   pub body: Box<SourceLanguageExpression>,
 }
 
+/// `on condition => action, goto Target`. Without a target, the machine stays in its state.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct SourceLanguageMachineTransition {
+  pub line_number: usize,
+  pub condition: Box<SourceLanguageExpression>,
+  pub action: Box<SourceLanguageExpression>,
+  pub target_state: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct SourceLanguageMachineState {
+  pub line_number: usize,
+  pub identifier: String,
+  /// Tried in order. The first one whose condition holds fires.
+  pub transitions: Vec<SourceLanguageMachineTransition>,
+}
+
+/// A finite-state machine. Calling it takes one transition from its current state. The first
+/// state is the initial one.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct SourceLanguageMachineDefinition {
+  pub line_number: usize,
+  pub identifier: String,
+  pub states: Vec<SourceLanguageMachineState>,
+}
+
+/// `defense: 3;` in a species declaration.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct SourceLanguageSpeciesAttribute {
+  pub line_number: usize,
+  pub identifier: String,
+  pub value: i32,
+}
+
+/// The attributes of a species, in the order of the critter file header. All of them but
+/// `memsize`, which defaults to what the program needs, and `posture`, which defaults to 0, are
+/// required.
+pub const SPECIES_ATTRIBUTES: [&str; 6] =
+  ["memsize", "defense", "offense", "size", "energy", "posture"];
+
+/// `species Name { defense: 3; ... }`. It becomes the header of the critter file, which lists the
+/// attributes the critter is born with.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct SourceLanguageSpeciesDeclaration {
+  pub line_number: usize,
+  pub identifier: String,
+  pub attributes: Vec<SourceLanguageSpeciesAttribute>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
 pub struct SourceLanguageProgram {
+  /// Without one, only the rules are emitted.
+  pub species_declaration: Option<SourceLanguageSpeciesDeclaration>,
   pub global_variable_definitions: Vec<SourceLanguageMutableGlobalVariableDefinition>,
+  /// Only present before checking, which desugars machines into globals and functions.
+  pub machine_definitions: Vec<SourceLanguageMachineDefinition>,
   pub function_definitions: Vec<SourceLanguageFunctionDefinition>,
 }
 
@@ -115,4 +184,6 @@ pub struct IfElseBlock {
 pub struct FullyInlinedProgram {
   pub global_variable_definitions: Vec<SourceLanguageMutableGlobalVariableDefinition>,
   pub if_else_blocks: Vec<IfElseBlock>,
+  /// Human-readable notes about what the optimization passes did to the program.
+  pub notes: Vec<String>,
 }
@@ -1,9 +1,18 @@
 use crate::ast::{
   pretty_print_expression_static_type, BinaryOperator, ExpressionStaticType, FunctionType,
   LiteralValue, SourceLanguageExpression, SourceLanguageFunctionDefinition,
+  SourceLanguageMachineDefinition, SourceLanguageMachineState, SourceLanguageMachineTransition,
   SourceLanguageMutableGlobalVariableDefinition, SourceLanguageProgram,
+  SourceLanguageSpeciesDeclaration, CHOOSE_FUNCTION_NAME, SPECIES_ATTRIBUTES,
 };
+use crate::chooser::lower_choose_expressions;
+use crate::evaluator::compile_time_evaluation;
+use crate::machine::desugar_machines;
 use crate::pl::SourceLanguageProgramParser;
+use crate::propagator::collect_read_variables;
+use crate::renamer::expression_calls_function;
+use crate::sequencer::{collect_condition_read_variables, is_recomputable};
+use crate::solver::may_hold_together;
 use im::{HashMap, HashSet};
 
 fn check_type(
@@ -12,7 +21,10 @@ fn check_type(
   expected_type: ExpressionStaticType,
   actual_type: ExpressionStaticType,
 ) -> ExpressionStaticType {
-  if expected_type != actual_type {
+  if expected_type != actual_type
+    && expected_type != ExpressionStaticType::ErrorType
+    && actual_type != ExpressionStaticType::ErrorType
+  {
     (*type_errors).push(format!(
       "Line {:}: Expected type `{:}`, actual type `{:}`.",
       line_number,
@@ -23,6 +35,578 @@ fn check_type(
   actual_type
 }
 
+/// Optimal string alignment distance: the Levenshtein distance where swapping two adjacent
+/// characters also counts as a single edit.
+fn edit_distance(s1: &str, s2: &str) -> usize {
+  let characters1: Vec<char> = s1.chars().collect();
+  let characters2: Vec<char> = s2.chars().collect();
+  let mut distances = vec![vec![0; characters2.len() + 1]; characters1.len() + 1];
+  for (i, row) in distances.iter_mut().enumerate() {
+    row[0] = i;
+  }
+  for (j, distance) in distances[0].iter_mut().enumerate() {
+    *distance = j;
+  }
+  for i in 1..=characters1.len() {
+    for j in 1..=characters2.len() {
+      let substitution_cost = if characters1[i - 1] == characters2[j - 1] {
+        0
+      } else {
+        1
+      };
+      let mut distance = (distances[i - 1][j - 1] + substitution_cost)
+        .min(distances[i - 1][j] + 1)
+        .min(distances[i][j - 1] + 1);
+      if i > 1
+        && j > 1
+        && characters1[i - 1] == characters2[j - 2]
+        && characters1[i - 2] == characters2[j - 1]
+      {
+        distance = distance.min(distances[i - 2][j - 2] + 1);
+      }
+      distances[i][j] = distance;
+    }
+  }
+  distances[characters1.len()][characters2.len()]
+}
+
+/// Returns a ` Did you mean ...?` hint naming the closest candidate, or an empty string when no
+/// candidate is close enough to be a plausible typo.
+fn suggest_similar_name<'a>(name: &str, candidates: impl Iterator<Item = &'a String>) -> String {
+  let max_distance = std::cmp::max(1, name.chars().count() / 3);
+  let mut best_candidate: Option<(usize, &String)> = None;
+  for candidate in candidates {
+    let distance = edit_distance(name, candidate);
+    if distance > max_distance {
+      continue;
+    }
+    // Break ties alphabetically so that the hint does not depend on hash map iteration order.
+    best_candidate = match best_candidate {
+      Some((best_distance, best_name))
+        if best_distance < distance || (best_distance == distance && best_name <= candidate) =>
+      {
+        Some((best_distance, best_name))
+      }
+      _ => Some((distance, candidate)),
+    };
+  }
+  match best_candidate {
+    Some((_, candidate)) => format!(" Did you mean `{:}`?", candidate),
+    None => String::new(),
+  }
+}
+
+fn check_divisor(
+  line_number: usize,
+  type_errors: &mut Vec<String>,
+  operator: BinaryOperator,
+  divisor: &SourceLanguageExpression,
+) {
+  if let SourceLanguageExpression::LiteralExpression {
+    line_number: _,
+    literal: LiteralValue::IntLiteral(0),
+  } = *compile_time_evaluation(divisor)
+  {
+    (*type_errors).push(format!(
+      "Line {:}: {:} by zero.",
+      line_number,
+      if operator == BinaryOperator::DIV {
+        "Division"
+      } else {
+        "Modulo"
+      }
+    ));
+  }
+}
+
+/// Checks the weights and branches of a `choose`. The call stays in the checked program for
+/// `lower_choose_expressions`, with its branches checked.
+fn type_check_choose(
+  functions_environment: &HashMap<String, FunctionType>,
+  readable_values_environment: &HashMap<String, ExpressionStaticType>,
+  global_values_environment: &HashSet<String>,
+  expected_type: ExpressionStaticType,
+  type_errors: &mut Vec<String>,
+  line_number: usize,
+  weights_and_branches: &[Box<SourceLanguageExpression>],
+) -> Box<SourceLanguageExpression> {
+  check_type(
+    line_number,
+    type_errors,
+    expected_type,
+    ExpressionStaticType::VoidType,
+  );
+  let mut total_weight: i64 = 0;
+  let mut checked_weights_and_branches = Vec::new();
+  for weight_and_branch in weights_and_branches.chunks(2) {
+    if let SourceLanguageExpression::LiteralExpression {
+      line_number: weight_line_number,
+      literal: LiteralValue::IntLiteral(weight),
+    } = *weight_and_branch[0]
+    {
+      if weight <= 0 {
+        type_errors.push(format!(
+          "Line {:}: Weight of a `choose` branch must be positive.",
+          weight_line_number
+        ));
+      }
+      total_weight += weight as i64;
+    }
+    checked_weights_and_branches.push(weight_and_branch[0].clone());
+    checked_weights_and_branches.push(type_check_expression(
+      functions_environment,
+      readable_values_environment,
+      global_values_environment,
+      ExpressionStaticType::VoidType,
+      type_errors,
+      &weight_and_branch[1],
+    ));
+  }
+  if checked_weights_and_branches.is_empty() {
+    type_errors.push(format!(
+      "Line {:}: `choose` needs at least one branch.",
+      line_number
+    ));
+  }
+  if total_weight > i32::MAX as i64 {
+    type_errors.push(format!(
+      "Line {:}: Weights of `choose` add up to more than {:}.",
+      line_number,
+      i32::MAX
+    ));
+  }
+  Box::new(SourceLanguageExpression::FunctionCallExpression {
+    line_number,
+    static_type: ExpressionStaticType::VoidType,
+    function_name: CHOOSE_FUNCTION_NAME.to_string(),
+    function_arguments: checked_weights_and_branches,
+  })
+}
+
+/// Returns whether evaluating the expression may perform a critter action.
+fn may_act(expression: &SourceLanguageExpression, acting_functions: &HashSet<String>) -> bool {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => false,
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name,
+      function_arguments,
+    } => {
+      acting_functions.contains(function_name)
+        || function_arguments
+          .iter()
+          .any(|argument| may_act(argument, acting_functions))
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => may_act(e1, acting_functions) || may_act(e2, acting_functions),
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => {
+      may_act(condition, acting_functions)
+        || may_act(e1, acting_functions)
+        || may_act(e2, acting_functions)
+    }
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier: _,
+      assigned_expression,
+    } => may_act(assigned_expression, acting_functions),
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => expressions
+      .iter()
+      .any(|sub_expression| may_act(sub_expression, acting_functions)),
+  }
+}
+
+/// Reports the recursive calls that more actions follow. Each action after the first one in a
+/// turn resumes on a later turn from a compiler-owned slot, and resuming after such a call would
+/// need one slot for every level of recursion.
+fn check_resumable_recursion(
+  function_name: &str,
+  acting_functions: &HashSet<String>,
+  type_errors: &mut Vec<String>,
+  expression: &SourceLanguageExpression,
+) {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => {}
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name: _,
+      function_arguments,
+    } => {
+      for argument in function_arguments {
+        check_resumable_recursion(function_name, acting_functions, type_errors, argument);
+      }
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => {
+      check_resumable_recursion(function_name, acting_functions, type_errors, e1);
+      check_resumable_recursion(function_name, acting_functions, type_errors, e2);
+    }
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => {
+      check_resumable_recursion(function_name, acting_functions, type_errors, condition);
+      check_resumable_recursion(function_name, acting_functions, type_errors, e1);
+      check_resumable_recursion(function_name, acting_functions, type_errors, e2);
+    }
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier: _,
+      assigned_expression,
+    } => check_resumable_recursion(
+      function_name,
+      acting_functions,
+      type_errors,
+      assigned_expression,
+    ),
+    SourceLanguageExpression::ChainExpression {
+      line_number,
+      expressions,
+    } => {
+      for (index, sub_expression) in expressions.iter().enumerate() {
+        if expression_calls_function(sub_expression, function_name)
+          && expressions[(index + 1)..]
+            .iter()
+            .any(|later_expression| may_act(later_expression, acting_functions))
+        {
+          type_errors.push(format!(
+            "Line {:}: Actions after the recursive call to `{:}` would need unbounded state to resume on later turns.",
+            line_number, function_name
+          ));
+        }
+        check_resumable_recursion(function_name, acting_functions, type_errors, sub_expression);
+      }
+    }
+  }
+}
+
+/// What calling a function does to the temporaries written so far in the turn.
+#[derive(Debug, Clone, PartialEq)]
+struct TemporaryUsage {
+  /// The temporaries that the function may read before it writes them, while the values the
+  /// caller wrote are still there.
+  reads_before_writes: HashSet<String>,
+  /// The temporaries that every path through the function writes after its last action.
+  writes: HashSet<String>,
+  may_act: bool,
+}
+
+/// The temporaries known to be written in the current turn at some point of a function.
+#[derive(Debug, Clone)]
+struct TemporaryState {
+  written: HashSet<String>,
+  /// Whether an action may have ended the turn, so that the values written before the function
+  /// was called no longer count.
+  may_have_acted: bool,
+}
+
+/// Returns whether a temporary holds a value written in the current turn when it is read. A value
+/// that the caller may have written is recorded in `reads_before_writes` instead.
+fn is_temporary_readable(
+  identifier: &str,
+  state: &TemporaryState,
+  reads_before_writes: &mut HashSet<String>,
+) -> bool {
+  if state.written.contains(identifier) {
+    return true;
+  }
+  if !state.may_have_acted {
+    reads_before_writes.insert(identifier.to_string());
+    return true;
+  }
+  false
+}
+
+/// Walks an expression in evaluation order. It reports the reads of temporaries that are not
+/// written in the current turn, and collects in `reads_before_writes` the reads that the caller
+/// has to write first.
+fn check_temporary_reads(
+  temporaries: &HashSet<String>,
+  usages: &HashMap<String, TemporaryUsage>,
+  state: &mut TemporaryState,
+  reads_before_writes: &mut HashSet<String>,
+  type_errors: &mut Vec<String>,
+  expression: &SourceLanguageExpression,
+) {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. } => {}
+    SourceLanguageExpression::VariableExpression {
+      line_number,
+      identifier,
+    } => {
+      if temporaries.contains(identifier)
+        && !is_temporary_readable(identifier, state, reads_before_writes)
+      {
+        type_errors.push(format!(
+          "Line {:}: Temporary `{:}` is read before it is written in this turn.",
+          line_number, identifier
+        ));
+      }
+    }
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number,
+      static_type,
+      function_name,
+      function_arguments,
+    } => {
+      for argument in function_arguments {
+        check_temporary_reads(
+          temporaries,
+          usages,
+          state,
+          reads_before_writes,
+          type_errors,
+          argument,
+        );
+      }
+      match usages.get(function_name) {
+        Some(usage) => {
+          for identifier in usage.reads_before_writes.iter() {
+            if !is_temporary_readable(identifier, state, reads_before_writes) {
+              type_errors.push(format!(
+                "Line {:}: `{:}` reads temporary `{:}` before it is written in this turn.",
+                line_number, function_name, identifier
+              ));
+            }
+          }
+          if usage.may_act {
+            state.written = usage.writes.clone();
+            state.may_have_acted = true;
+          } else {
+            state.written = state.written.clone().union(usage.writes.clone());
+          }
+        }
+        None if *static_type == ExpressionStaticType::VoidType => {
+          state.written = HashSet::new();
+          state.may_have_acted = true;
+        }
+        None => {}
+      }
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => {
+      for operand in [e1, e2] {
+        check_temporary_reads(
+          temporaries,
+          usages,
+          state,
+          reads_before_writes,
+          type_errors,
+          operand,
+        );
+      }
+    }
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => {
+      check_temporary_reads(
+        temporaries,
+        usages,
+        state,
+        reads_before_writes,
+        type_errors,
+        condition,
+      );
+      let mut else_state = state.clone();
+      check_temporary_reads(
+        temporaries,
+        usages,
+        state,
+        reads_before_writes,
+        type_errors,
+        e1,
+      );
+      check_temporary_reads(
+        temporaries,
+        usages,
+        &mut else_state,
+        reads_before_writes,
+        type_errors,
+        e2,
+      );
+      state.written = state.written.clone().intersection(else_state.written);
+      state.may_have_acted = state.may_have_acted || else_state.may_have_acted;
+    }
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier,
+      assigned_expression,
+    } => {
+      check_temporary_reads(
+        temporaries,
+        usages,
+        state,
+        reads_before_writes,
+        type_errors,
+        assigned_expression,
+      );
+      if temporaries.contains(identifier) {
+        state.written.insert(identifier.clone());
+      }
+    }
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => {
+      for sub_expression in expressions {
+        check_temporary_reads(
+          temporaries,
+          usages,
+          state,
+          reads_before_writes,
+          type_errors,
+          sub_expression,
+        );
+      }
+    }
+  }
+}
+
+/// Reports every read of a temporary that may come before its write in the same turn. A function
+/// may read the temporaries its callers write, but `main` starts the turn, and an action ends it.
+/// The usage of a recursive function is refined from the most optimistic guess until it is
+/// stable.
+fn check_temporaries(program: &SourceLanguageProgram, type_errors: &mut Vec<String>) {
+  let temporaries: HashSet<String> = program
+    .global_variable_definitions
+    .iter()
+    .filter(|global_variable| global_variable.is_temporary)
+    .map(|global_variable| global_variable.identifier.clone())
+    .collect();
+  let mut usages: HashMap<String, TemporaryUsage> = HashMap::new();
+  for function in &program.function_definitions {
+    let is_main = function.identifier == "main";
+    let mut usage = TemporaryUsage {
+      reads_before_writes: HashSet::new(),
+      writes: temporaries.clone(),
+      may_act: false,
+    };
+    loop {
+      // Nothing written before `main` belongs to the current turn.
+      let mut state = TemporaryState {
+        written: HashSet::new(),
+        may_have_acted: is_main,
+      };
+      let mut reads_before_writes = HashSet::new();
+      let mut function_type_errors = Vec::new();
+      check_temporary_reads(
+        &temporaries,
+        &usages.update(function.identifier.clone(), usage.clone()),
+        &mut state,
+        &mut reads_before_writes,
+        &mut function_type_errors,
+        &function.body,
+      );
+      let refined_usage = TemporaryUsage {
+        reads_before_writes,
+        writes: state.written,
+        may_act: state.may_have_acted,
+      };
+      if refined_usage == usage {
+        type_errors.extend(function_type_errors);
+        break;
+      }
+      usage = refined_usage;
+    }
+    usages.insert(function.identifier.clone(), usage);
+  }
+}
+
+/// Synthesizes the type of an expression without reporting errors. It is only used to infer the
+/// return type of unannotated functions, whose bodies are then checked against the result.
+fn infer_expression_type(
+  functions_environment: &HashMap<String, FunctionType>,
+  readable_values_environment: &HashMap<String, ExpressionStaticType>,
+  expression: &SourceLanguageExpression,
+) -> ExpressionStaticType {
+  match expression {
+    SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal: LiteralValue::IntLiteral(_),
+    } => ExpressionStaticType::IntType,
+    SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal: LiteralValue::BoolLiteral(_),
+    } => ExpressionStaticType::BoolType,
+    SourceLanguageExpression::VariableExpression {
+      line_number: _,
+      identifier,
+    } => readable_values_environment
+      .get(identifier)
+      .copied()
+      .unwrap_or(ExpressionStaticType::ErrorType),
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name,
+      function_arguments: _,
+    } if function_name == CHOOSE_FUNCTION_NAME => ExpressionStaticType::VoidType,
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name,
+      function_arguments: _,
+    } => functions_environment
+      .get(function_name)
+      .map(|function_type| function_type.return_type)
+      .unwrap_or(ExpressionStaticType::ErrorType),
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator,
+      e1: _,
+      e2: _,
+    } => match operator {
+      BinaryOperator::MUL
+      | BinaryOperator::DIV
+      | BinaryOperator::MOD
+      | BinaryOperator::PLUS
+      | BinaryOperator::MINUS => ExpressionStaticType::IntType,
+      _ => ExpressionStaticType::BoolType,
+    },
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition: _,
+      e1,
+      e2,
+    } => match infer_expression_type(functions_environment, readable_values_environment, e1) {
+      ExpressionStaticType::ErrorType => {
+        infer_expression_type(functions_environment, readable_values_environment, e2)
+      }
+      t => t,
+    },
+    SourceLanguageExpression::AssignmentExpression { .. }
+    | SourceLanguageExpression::ChainExpression { .. } => ExpressionStaticType::VoidType,
+  }
+}
+
 fn type_check_expression(
   functions_environment: &HashMap<String, FunctionType>,
   readable_values_environment: &HashMap<String, ExpressionStaticType>,
@@ -72,8 +656,10 @@ fn type_check_expression(
         }
         None => {
           type_errors.push(format!(
-            "Line {:}: Undefined variable `{:}`.",
-            line_number, identifier
+            "Line {:}: Undefined variable `{:}`.{:}",
+            line_number,
+            identifier,
+            suggest_similar_name(identifier, readable_values_environment.keys())
           ));
         }
       };
@@ -82,6 +668,20 @@ fn type_check_expression(
         identifier: (*identifier).clone(),
       })
     }
+    &SourceLanguageExpression::FunctionCallExpression {
+      line_number,
+      static_type: _,
+      function_name,
+      function_arguments,
+    } if function_name == CHOOSE_FUNCTION_NAME => type_check_choose(
+      functions_environment,
+      readable_values_environment,
+      global_values_environment,
+      expected_type,
+      type_errors,
+      *line_number,
+      function_arguments,
+    ),
     &SourceLanguageExpression::FunctionCallExpression {
       line_number,
       static_type: _,
@@ -91,21 +691,35 @@ fn type_check_expression(
       match (*functions_environment).get(function_name) {
         None => {
           type_errors.push(format!(
-            "Line {:}: Undefined function `{:}`.",
-            line_number, function_name
+            "Line {:}: Undefined function `{:}`.{:}",
+            line_number,
+            function_name,
+            suggest_similar_name(function_name, functions_environment.keys())
           ));
           Box::new(SourceLanguageExpression::FunctionCallExpression {
             line_number: *line_number,
-            static_type: ExpressionStaticType::VoidType,
+            static_type: ExpressionStaticType::ErrorType,
             function_name: (*function_name).clone(),
-            function_arguments: (*function_arguments).clone(),
+            function_arguments: function_arguments
+              .iter()
+              .map(|argument_expression| {
+                type_check_expression(
+                  functions_environment,
+                  readable_values_environment,
+                  global_values_environment,
+                  ExpressionStaticType::ErrorType,
+                  type_errors,
+                  argument_expression,
+                )
+              })
+              .collect(),
           })
         }
         Some(function_type) => {
           let FunctionType {
             argument_types,
             return_type,
-          } = &*function_type;
+          } = function_type;
           // Check return type
           check_type(*line_number, type_errors, expected_type, *return_type);
           // Check argument types
@@ -118,17 +732,19 @@ fn type_check_expression(
             ));
           }
           let mut checked_function_arguments = Vec::new();
-          for (argument_expression, argument_type) in function_arguments
-            .into_iter()
-            .zip(argument_types.into_iter())
-          {
+          for (index, argument_expression) in function_arguments.iter().enumerate() {
+            // Extra arguments are still checked, but against the error type to avoid cascades.
+            let argument_type = argument_types
+              .get(index)
+              .copied()
+              .unwrap_or(ExpressionStaticType::ErrorType);
             checked_function_arguments.push(type_check_expression(
               functions_environment,
               readable_values_environment,
               global_values_environment,
-              *argument_type,
+              argument_type,
               type_errors,
-              &*argument_expression,
+              argument_expression,
             ));
           }
           Box::new(SourceLanguageExpression::FunctionCallExpression {
@@ -157,25 +773,30 @@ fn type_check_expression(
           expected_type,
           ExpressionStaticType::IntType,
         );
+        let checked_e1 = type_check_expression(
+          functions_environment,
+          readable_values_environment,
+          global_values_environment,
+          ExpressionStaticType::IntType,
+          type_errors,
+          e1,
+        );
+        let checked_e2 = type_check_expression(
+          functions_environment,
+          readable_values_environment,
+          global_values_environment,
+          ExpressionStaticType::IntType,
+          type_errors,
+          e2,
+        );
+        if *operator == BinaryOperator::DIV || *operator == BinaryOperator::MOD {
+          check_divisor(*line_number, type_errors, *operator, &checked_e2);
+        }
         Box::new(SourceLanguageExpression::BinaryExpression {
           line_number: *line_number,
           operator: *operator,
-          e1: type_check_expression(
-            functions_environment,
-            readable_values_environment,
-            global_values_environment,
-            ExpressionStaticType::IntType,
-            type_errors,
-            &*e1,
-          ),
-          e2: type_check_expression(
-            functions_environment,
-            readable_values_environment,
-            global_values_environment,
-            ExpressionStaticType::IntType,
-            type_errors,
-            &*e2,
-          ),
+          e1: checked_e1,
+          e2: checked_e2,
         })
       }
       BinaryOperator::LT | BinaryOperator::LE | BinaryOperator::GT | BinaryOperator::GE => {
@@ -194,7 +815,7 @@ fn type_check_expression(
             global_values_environment,
             ExpressionStaticType::IntType,
             type_errors,
-            &*e1,
+            e1,
           ),
           e2: type_check_expression(
             functions_environment,
@@ -202,7 +823,7 @@ fn type_check_expression(
             global_values_environment,
             ExpressionStaticType::IntType,
             type_errors,
-            &*e2,
+            e2,
           ),
         })
       }
@@ -222,7 +843,7 @@ fn type_check_expression(
             global_values_environment,
             ExpressionStaticType::BoolType,
             type_errors,
-            &*e1,
+            e1,
           ),
           e2: type_check_expression(
             functions_environment,
@@ -230,7 +851,7 @@ fn type_check_expression(
             global_values_environment,
             ExpressionStaticType::BoolType,
             type_errors,
-            &*e2,
+            e2,
           ),
         })
       }
@@ -250,7 +871,7 @@ fn type_check_expression(
             global_values_environment,
             ExpressionStaticType::IntType,
             type_errors,
-            &*e1,
+            e1,
           ),
           e2: type_check_expression(
             functions_environment,
@@ -258,7 +879,7 @@ fn type_check_expression(
             global_values_environment,
             ExpressionStaticType::IntType,
             type_errors,
-            &*e2,
+            e2,
           ),
         })
       }
@@ -276,7 +897,7 @@ fn type_check_expression(
         global_values_environment,
         ExpressionStaticType::BoolType,
         type_errors,
-        &*condition,
+        condition,
       ),
       e1: type_check_expression(
         functions_environment,
@@ -284,7 +905,7 @@ fn type_check_expression(
         global_values_environment,
         expected_type,
         type_errors,
-        &*e1,
+        e1,
       ),
       e2: type_check_expression(
         functions_environment,
@@ -292,7 +913,7 @@ fn type_check_expression(
         global_values_environment,
         expected_type,
         type_errors,
-        &*e2,
+        e2,
       ),
     }),
     &SourceLanguageExpression::AssignmentExpression {
@@ -309,11 +930,20 @@ fn type_check_expression(
       Box::new(SourceLanguageExpression::AssignmentExpression {
         line_number: *line_number,
         identifier: {
-          if !(*global_values_environment).contains(identifier) {
+          if (*readable_values_environment).contains_key(identifier)
+            && !(*global_values_environment).contains(identifier)
+          {
             type_errors.push(format!(
-              "Line {:}: Undefined global variable `{:}`.",
+              "Line {:}: Cannot assign to function parameter `{:}`. Only global variables are assignable.",
               line_number, identifier
             ));
+          } else if !(*global_values_environment).contains(identifier) {
+            type_errors.push(format!(
+              "Line {:}: Undefined global variable `{:}`.{:}",
+              line_number,
+              identifier,
+              suggest_similar_name(identifier, global_values_environment.iter())
+            ));
           }
           (*identifier).clone()
         },
@@ -323,7 +953,7 @@ fn type_check_expression(
           global_values_environment,
           ExpressionStaticType::IntType,
           type_errors,
-          &*assigned_expression,
+          assigned_expression,
         ),
       })
     }
@@ -348,7 +978,7 @@ fn type_check_expression(
               global_values_environment,
               ExpressionStaticType::VoidType,
               type_errors,
-              &*sub_expression,
+              sub_expression,
             ));
           }
           checked_expressions
@@ -358,17 +988,130 @@ fn type_check_expression(
   }
 }
 
+/// Reports the states of a checked machine that no transition leads to from the initial state,
+/// and the states where no transition may fire.
+fn check_machine_states(machine: &SourceLanguageMachineDefinition, type_errors: &mut Vec<String>) {
+  let mut is_reachable = vec![false; machine.states.len()];
+  let mut worklist = vec![0];
+  while let Some(state_index) = worklist.pop() {
+    if state_index >= machine.states.len() || is_reachable[state_index] {
+      continue;
+    }
+    is_reachable[state_index] = true;
+    for transition in &machine.states[state_index].transitions {
+      if let Some(target_index) = transition.target_state.as_ref().and_then(|target_state| {
+        machine
+          .states
+          .iter()
+          .position(|state| state.identifier == *target_state)
+      }) {
+        worklist.push(target_index);
+      }
+    }
+  }
+
+  for (state_index, state) in machine.states.iter().enumerate() {
+    // Transitions always lead to the first state of a name, so a duplicate is never reached. It
+    // is reported as a duplicate already.
+    let is_duplicate = machine.states[..state_index]
+      .iter()
+      .any(|earlier_state| earlier_state.identifier == state.identifier);
+    if !is_reachable[state_index] && !is_duplicate {
+      type_errors.push(format!(
+        "Line {:}: State `{:}` of machine `{:}` is unreachable from its initial state `{:}`.",
+        state.line_number, state.identifier, machine.identifier, machine.states[0].identifier
+      ));
+    }
+    let failed_conditions: Vec<(&SourceLanguageExpression, bool)> = state
+      .transitions
+      .iter()
+      .map(|transition| (transition.condition.as_ref(), false))
+      .collect();
+    if may_hold_together(&failed_conditions) {
+      type_errors.push(format!(
+        "Line {:}: State `{:}` of machine `{:}` may have no transition to take. End it with `on true => ...`.",
+        state.line_number, state.identifier, machine.identifier
+      ));
+    }
+  }
+}
+
+/// Reports unknown, duplicate, missing and out of range species attributes. `memsize` is checked
+/// against the memory the program needs once it is compiled.
+fn check_species_declaration(
+  species_declaration: &SourceLanguageSpeciesDeclaration,
+  type_errors: &mut Vec<String>,
+) {
+  let attribute_names: Vec<String> = SPECIES_ATTRIBUTES
+    .iter()
+    .map(|name| name.to_string())
+    .collect();
+  for (index, attribute) in species_declaration.attributes.iter().enumerate() {
+    let name = &attribute.identifier;
+    if !attribute_names.contains(name) {
+      type_errors.push(format!(
+        "Line {:}: Unknown species attribute `{:}`.{:}",
+        attribute.line_number,
+        name,
+        suggest_similar_name(name, attribute_names.iter())
+      ));
+    } else if species_declaration.attributes[..index]
+      .iter()
+      .any(|earlier_attribute| earlier_attribute.identifier == *name)
+    {
+      type_errors.push(format!(
+        "Line {:}: Duplicate species attribute: `{:}`",
+        attribute.line_number, name
+      ));
+    } else if name == "posture" && attribute.value > 99 {
+      type_errors.push(format!(
+        "Line {:}: Species attribute `posture` must be between 0 and 99.",
+        attribute.line_number
+      ));
+    } else if name != "posture" && attribute.value < 1 {
+      type_errors.push(format!(
+        "Line {:}: Species attribute `{:}` must be at least 1.",
+        attribute.line_number, name
+      ));
+    }
+  }
+  for name in SPECIES_ATTRIBUTES {
+    if name != "memsize"
+      && name != "posture"
+      && !species_declaration
+        .attributes
+        .iter()
+        .any(|attribute| attribute.identifier == name)
+    {
+      type_errors.push(format!(
+        "Line {:}: Species `{:}` is missing `{:}`.",
+        species_declaration.line_number, species_declaration.identifier, name
+      ));
+    }
+  }
+}
+
 fn type_check_program(
   functions_environment: HashMap<String, FunctionType>,
   program: &SourceLanguageProgram,
 ) -> (SourceLanguageProgram, Vec<String>) {
   let SourceLanguageProgram {
+    species_declaration,
     global_variable_definitions,
+    machine_definitions,
     function_definitions,
-  } = &*program;
+  } = program;
 
   let mut type_errors = Vec::new();
+  if let Some(species_declaration) = species_declaration {
+    check_species_declaration(species_declaration, &mut type_errors);
+  }
   let mut mutable_global_values_environment = HashSet::new();
+  let mut acting_functions: HashSet<String> = functions_environment
+    .iter()
+    .filter(|(_, function_type)| function_type.return_type == ExpressionStaticType::VoidType)
+    .map(|(name, _)| name.clone())
+    .collect();
   let mut mutable_patched_functions_environment = functions_environment;
 
   for global_variable in global_variable_definitions {
@@ -387,19 +1130,190 @@ fn type_check_program(
   let mut checked_global_variables = Vec::new();
   let mut checked_functions = Vec::new();
 
+  let mut global_readable_values_environment = HashMap::new();
+  for v in global_values_environment.iter() {
+    global_readable_values_environment =
+      global_readable_values_environment.update((*v).clone(), ExpressionStaticType::IntType);
+  }
+
+  // Initializers run at birth in declaration order, so each one may only read the globals
+  // declared before it, and no temporary holds a value yet. They also all run in one rule, whose
+  // conditions can only see an earlier initializer by computing it again, which `random` cannot.
+  let mut initialized_global_values = HashSet::new();
+  let mut unrecomputable_global_values = std::collections::HashSet::new();
   for global_variable in global_variable_definitions {
     let SourceLanguageMutableGlobalVariableDefinition {
       line_number,
       identifier,
-      assigned_value,
-    } = &*global_variable;
+      assigned_expression,
+      is_temporary,
+    } = global_variable;
+
+    let mut read_variables: Vec<String> = {
+      let mut read_variables = std::collections::HashSet::new();
+      collect_read_variables(assigned_expression, &mut read_variables);
+      read_variables.into_iter().collect()
+    };
+    read_variables.sort();
+    for read_variable in read_variables {
+      if global_variable_definitions.iter().any(|global_variable| {
+        global_variable.is_temporary && global_variable.identifier == read_variable
+      }) {
+        type_errors.push(format!(
+          "Line {:}: Temporary `{:}` is read before it is written in this turn.",
+          line_number, read_variable
+        ));
+      } else if global_values_environment.contains(&read_variable)
+        && !initialized_global_values.contains(&read_variable)
+      {
+        type_errors.push(format!(
+          "Line {:}: The initializer of `{:}` reads `{:}`, which is not declared before it.",
+          line_number, identifier, read_variable
+        ));
+      }
+    }
+    let checked_assigned_expression = type_check_expression(
+      &mutable_patched_functions_environment,
+      &global_readable_values_environment,
+      &global_values_environment,
+      ExpressionStaticType::IntType,
+      &mut type_errors,
+      assigned_expression,
+    );
+    let mut condition_read_variables: Vec<String> = {
+      let mut condition_read_variables = std::collections::HashSet::new();
+      collect_condition_read_variables(&checked_assigned_expression, &mut condition_read_variables);
+      condition_read_variables
+        .intersection(&unrecomputable_global_values)
+        .cloned()
+        .collect()
+    };
+    condition_read_variables.sort();
+    for read_variable in condition_read_variables {
+      type_errors.push(format!(
+        "Line {:}: The initializer of `{:}` branches on `{:}`, whose random initial value is not \
+         known yet when the branch is taken.",
+        line_number, identifier, read_variable
+      ));
+    }
+    if !is_recomputable(&checked_assigned_expression, &unrecomputable_global_values) {
+      unrecomputable_global_values.insert(identifier.clone());
+    }
+    initialized_global_values.insert(identifier.clone());
 
     checked_global_variables.push(SourceLanguageMutableGlobalVariableDefinition {
       line_number: *line_number,
       identifier: (*identifier).clone(),
-      assigned_value: *assigned_value,
+      assigned_expression: checked_assigned_expression,
+      is_temporary: *is_temporary,
     });
   }
+  let mut checked_machines = Vec::new();
+  for machine_definition in machine_definitions {
+    let name = machine_definition.identifier.clone();
+    if mutable_patched_functions_environment.contains_key(&name) {
+      type_errors.push(format!(
+        "Line {:}: Duplicate function: `{:}`",
+        machine_definition.line_number, name
+      ))
+    }
+    if machine_definition.states.is_empty() {
+      type_errors.push(format!(
+        "Line {:}: Machine `{:}` has no states.",
+        machine_definition.line_number, name
+      ));
+    }
+    // The machine is in scope of its own transitions, so that a call to itself gets an error of
+    // its own instead of an undefined function.
+    let machine_environment = mutable_patched_functions_environment.update(
+      name.clone(),
+      FunctionType {
+        argument_types: Vec::new(),
+        return_type: ExpressionStaticType::VoidType,
+      },
+    );
+    let state_names: Vec<String> = machine_definition
+      .states
+      .iter()
+      .map(|state| state.identifier.clone())
+      .collect();
+
+    let mut checked_states = Vec::new();
+    for (state_index, state) in machine_definition.states.iter().enumerate() {
+      if state_names[..state_index].contains(&state.identifier) {
+        type_errors.push(format!(
+          "Line {:}: Duplicate state: `{:}`",
+          state.line_number, state.identifier
+        ));
+      }
+      let mut checked_transitions = Vec::new();
+      for transition in &state.transitions {
+        if let Some(target_state) = &transition.target_state {
+          if !state_names.contains(target_state) {
+            type_errors.push(format!(
+              "Line {:}: Undefined state `{:}` in machine `{:}`.{:}",
+              transition.line_number,
+              target_state,
+              name,
+              suggest_similar_name(target_state, state_names.iter())
+            ));
+          }
+        }
+        if expression_calls_function(&transition.condition, &name)
+          || expression_calls_function(&transition.action, &name)
+        {
+          type_errors.push(format!(
+            "Line {:}: Machine `{:}` cannot run itself from its own transitions.",
+            transition.line_number, name
+          ));
+        }
+        checked_transitions.push(SourceLanguageMachineTransition {
+          line_number: transition.line_number,
+          condition: type_check_expression(
+            &machine_environment,
+            &global_readable_values_environment,
+            &global_values_environment,
+            ExpressionStaticType::BoolType,
+            &mut type_errors,
+            &transition.condition,
+          ),
+          action: type_check_expression(
+            &machine_environment,
+            &global_readable_values_environment,
+            &global_values_environment,
+            ExpressionStaticType::VoidType,
+            &mut type_errors,
+            &transition.action,
+          ),
+          target_state: transition.target_state.clone(),
+        });
+      }
+      checked_states.push(SourceLanguageMachineState {
+        line_number: state.line_number,
+        identifier: state.identifier.clone(),
+        transitions: checked_transitions,
+      });
+    }
+    let checked_machine = SourceLanguageMachineDefinition {
+      line_number: machine_definition.line_number,
+      identifier: name.clone(),
+      states: checked_states,
+    };
+    if !checked_machine.states.is_empty() {
+      check_machine_states(&checked_machine, &mut type_errors);
+    }
+
+    if checked_machine.states.iter().any(|state| {
+      state
+        .transitions
+        .iter()
+        .any(|transition| may_act(&transition.action, &acting_functions))
+    }) {
+      acting_functions = acting_functions.update(name.clone());
+    }
+    mutable_patched_functions_environment = machine_environment;
+    checked_machines.push(checked_machine);
+  }
 
   for function_definition in function_definitions {
     let name = function_definition.identifier.clone();
@@ -410,23 +1324,12 @@ fn type_check_program(
       ))
     }
 
-    let function_type = FunctionType {
-      argument_types: function_definition
-        .function_arguments
-        .iter()
-        .map(|(_, t)| *t)
-        .collect(),
-      return_type: function_definition.return_type,
-    };
-    mutable_patched_functions_environment =
-      mutable_patched_functions_environment.update(name, function_type);
-
     let mut readable_values_environment = HashMap::new();
     for v in global_values_environment.iter() {
       readable_values_environment =
         readable_values_environment.update((*v).clone(), ExpressionStaticType::IntType);
     }
-    for (parameter_name, parameter_type) in &*function_definition.function_arguments {
+    for (parameter_name, parameter_type) in &function_definition.function_arguments {
       let name = parameter_name.clone();
       if readable_values_environment.contains_key(&name) {
         type_errors.push(format!(
@@ -436,24 +1339,63 @@ fn type_check_program(
       }
       readable_values_environment = readable_values_environment.update(name, *parameter_type);
     }
+
+    let return_type = match function_definition.return_type {
+      Some(return_type) => return_type,
+      None if expression_calls_function(&function_definition.body, &name) => {
+        type_errors.push(format!(
+          "Line {:}: Recursive function `{:}` must annotate its return type.",
+          function_definition.line_number, name
+        ));
+        ExpressionStaticType::ErrorType
+      }
+      None => infer_expression_type(
+        &mutable_patched_functions_environment,
+        &readable_values_environment,
+        &function_definition.body,
+      ),
+    };
+
+    let function_type = FunctionType {
+      argument_types: function_definition
+        .function_arguments
+        .iter()
+        .map(|(_, t)| *t)
+        .collect(),
+      return_type,
+    };
+    mutable_patched_functions_environment =
+      mutable_patched_functions_environment.update(name, function_type);
+
+    let body = type_check_expression(
+      &mutable_patched_functions_environment,
+      &readable_values_environment,
+      &global_values_environment,
+      return_type,
+      &mut type_errors,
+      &function_definition.body,
+    );
+    if may_act(&body, &acting_functions) {
+      acting_functions = acting_functions.update(function_definition.identifier.clone());
+    }
+    check_resumable_recursion(
+      &function_definition.identifier,
+      &acting_functions,
+      &mut type_errors,
+      &body,
+    );
+
     checked_functions.push(SourceLanguageFunctionDefinition {
       line_number: function_definition.line_number,
       identifier: function_definition.identifier.clone(),
       function_arguments: (function_definition.function_arguments).clone(),
-      return_type: function_definition.return_type,
-      body: type_check_expression(
-        &mutable_patched_functions_environment,
-        &readable_values_environment,
-        &global_values_environment,
-        function_definition.return_type,
-        &mut type_errors,
-        &*function_definition.body,
-      ),
+      return_type: Some(return_type),
+      body,
     })
   }
 
   let last_function = &checked_functions[checked_functions.len() - 1];
-  if last_function.identifier != "main" || last_function.function_arguments.len() > 0 {
+  if last_function.identifier != "main" || !last_function.function_arguments.is_empty() {
     type_errors.push(format!(
       "Line {:}: Missing main function with void return type at the end. We only have {:}.",
       last_function.line_number, last_function.identifier,
@@ -461,7 +1403,9 @@ fn type_check_program(
   }
 
   let checked_program = SourceLanguageProgram {
+    species_declaration: species_declaration.clone(),
     global_variable_definitions: checked_global_variables,
+    machine_definitions: checked_machines,
     function_definitions: checked_functions,
   };
   (checked_program, type_errors)
@@ -474,13 +1418,319 @@ pub fn get_type_checked_program(
   let generated_parser = SourceLanguageProgramParser::new();
   match generated_parser.parse(source_string.as_str()) {
     Ok(program) => {
-      let (checked_program, errors) = type_check_program(functions_environment, &*program);
-      if errors.len() > 0 {
+      let (checked_program, mut errors) = type_check_program(functions_environment, &program);
+      // Temporaries are checked on the lowered program, since machines and `choose` write their
+      // own.
+      let lowered_program = lower_choose_expressions(&desugar_machines(&checked_program));
+      check_temporaries(&lowered_program, &mut errors);
+      if !errors.is_empty() {
         Err(errors)
       } else {
-        Ok(checked_program)
+        Ok(lowered_program)
       }
     }
     Err(e) => Err(vec![format!("{:}", e)]),
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utilities::{check_errors, checked_expression, checked_program};
+
+  #[test]
+  fn constant_zero_divisors_are_reported() {
+    assert_eq!(
+      check_errors("var x = 1;\nfun main(): void = serve(x / 0)"),
+      vec!["Line 36: Division by zero."]
+    );
+    // The divisor is folded first, so a zero that takes some arithmetic to see counts as well.
+    assert_eq!(
+      check_errors("var x = 1;\nfun main(): void = serve(x % (2 - 2))"),
+      vec!["Line 36: Modulo by zero."]
+    );
+  }
+
+  #[test]
+  fn divisors_that_are_not_constant_are_allowed() {
+    assert!(check_errors("var x = 1;\nfun main(): void = serve(100 / x)").is_empty());
+    assert!(check_errors("fun main(): void = serve(100 % nearby(0))").is_empty());
+  }
+
+  #[test]
+  fn undefined_functions_do_not_cascade_into_type_errors() {
+    assert_eq!(
+      check_errors("fun main(): void = serve(undefined(1) + 1)"),
+      vec!["Line 25: Undefined function `undefined`."]
+    );
+    // The arguments of an undefined function are still checked on their own.
+    assert_eq!(
+      check_errors("fun main(): void = serve(undefined(1 + true))"),
+      vec![
+        "Line 25: Undefined function `undefined`.",
+        "Line 39: Expected type `int`, actual type `bool`."
+      ]
+    );
+  }
+
+  #[test]
+  fn extra_arguments_are_checked_without_an_expected_type() {
+    assert_eq!(
+      check_errors("fun main(): void = serve(nearby(1, true))"),
+      vec!["Line 25: Expected argument length `1`, actual 2."]
+    );
+    assert_eq!(
+      check_errors("fun main(): void = serve(nearby(1, 2 + true))"),
+      vec![
+        "Line 25: Expected argument length `1`, actual 2.",
+        "Line 39: Expected type `int`, actual type `bool`."
+      ]
+    );
+  }
+
+  fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+  }
+
+  #[test]
+  fn edit_distance_counts_adjacent_transpositions_once() {
+    assert_eq!(edit_distance("forward", "forward"), 0);
+    assert_eq!(edit_distance("forwrd", "forward"), 1);
+    assert_eq!(edit_distance("cuont", "count"), 1);
+    assert_eq!(edit_distance("", "abc"), 3);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+  }
+
+  #[test]
+  fn suggest_similar_name_picks_the_closest_candidate() {
+    let candidates = names(&["energy", "nearby", "ahead"]);
+    assert_eq!(
+      suggest_similar_name("naerby", candidates.iter()),
+      " Did you mean `nearby`?"
+    );
+    assert_eq!(suggest_similar_name("smell", candidates.iter()), "");
+    // Ties go to the alphabetically first candidate.
+    assert_eq!(
+      suggest_similar_name("ab", names(&["ac", "aa"]).iter()),
+      " Did you mean `aa`?"
+    );
+  }
+
+  #[test]
+  fn undefined_names_come_with_suggestions() {
+    assert_eq!(
+      check_errors("var count = 0;\nfun main(): void = serve(cuont)"),
+      vec!["Line 40: Undefined variable `cuont`. Did you mean `count`?"]
+    );
+    assert_eq!(
+      check_errors("fun main(): void = forwrd()"),
+      vec!["Line 19: Undefined function `forwrd`. Did you mean `forward`?"]
+    );
+    assert_eq!(
+      check_errors("fun main(): void = serve(zzz)"),
+      vec!["Line 25: Undefined variable `zzz`."]
+    );
+  }
+
+  #[test]
+  fn omitted_return_types_are_inferred_from_the_body() {
+    let program = checked_program(
+      "fun f(n: int) = n + 1
+       fun g() = nearby(0) > 1
+       fun w() = forward()
+       fun main(): void = if g() then serve(f(2)) else w()",
+    );
+    let return_types: Vec<_> = program
+      .function_definitions
+      .iter()
+      .map(|function| function.return_type)
+      .collect();
+    assert_eq!(
+      return_types,
+      vec![
+        Some(ExpressionStaticType::IntType),
+        Some(ExpressionStaticType::BoolType),
+        Some(ExpressionStaticType::VoidType),
+        Some(ExpressionStaticType::VoidType)
+      ]
+    );
+    assert_eq!(
+      check_errors("fun g() = nearby(0) > 1\nfun main(): void = serve(g())"),
+      vec!["Line 49: Expected type `int`, actual type `bool`."]
+    );
+  }
+
+  #[test]
+  fn recursive_functions_need_a_return_type() {
+    assert_eq!(
+      check_errors("fun h(n: int) = if n > 0 then h(n - 1) else 0\nfun main(): void = serve(h(2))"),
+      vec!["Line 0: Recursive function `h` must annotate its return type."]
+    );
+  }
+
+  fn acting_functions(names: &[&str]) -> HashSet<String> {
+    names.iter().map(|name| name.to_string()).collect()
+  }
+
+  #[test]
+  fn may_act_looks_through_every_subexpression() {
+    let actions = acting_functions(&["forward", "turn"]);
+    assert!(may_act(&checked_expression("void", "forward()"), &actions));
+    assert!(may_act(
+      &checked_expression("void", "if x > 0 then wait() else forward()"),
+      &actions
+    ));
+    assert!(may_act(
+      &checked_expression("void", "({ x = 1; forward() })"),
+      &actions
+    ));
+    assert!(!may_act(
+      &checked_expression("void", "if x > 0 then ({ x = 2 }) else ({ x = 1 })"),
+      &actions
+    ));
+    assert!(!may_act(
+      &checked_expression("int", "x + nearby(1)"),
+      &actions
+    ));
+  }
+
+  #[test]
+  fn recursive_functions_may_act_before_the_recursive_call() {
+    assert!(check_errors(
+      "fun walk(n: int): void = if n > 0 then ({ forward(); walk(n - 1) }) else wait()
+       fun main(): void = walk(3)"
+    )
+    .is_empty());
+    assert!(check_errors(
+      "var steps = 0;
+       fun walk(n: int): void = if n > 0 then ({ walk(n - 1); steps = steps + 1 }) else forward()
+       fun main(): void = walk(3)"
+    )
+    .is_empty());
+  }
+
+  #[test]
+  fn recursive_functions_may_not_act_after_the_recursive_call() {
+    let errors = check_errors(
+      "fun walk(n: int): void = if n > 0 then ({ forward(); walk(n - 1); left() }) else wait()
+       fun main(): void = walk(3)",
+    );
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("Actions after the recursive call to `walk`"));
+    // An action in a function that runs after the recursive call counts as well.
+    let errors = check_errors(
+      "fun turn(): void = ({ left(); left() })
+       fun walk(n: int): void = if n > 0 then ({ walk(n - 1); turn() }) else wait()
+       fun main(): void = walk(3)",
+    );
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("Actions after the recursive call to `walk`"));
+  }
+
+  #[test]
+  fn unreachable_states_are_reported_once() {
+    let errors = check_errors(
+      "machine M {
+         state A { on nearby(0) > 0 => left(), goto B; on true => wait() }
+         state B { on true => right(), goto A }
+         state B { on true => forward() }
+         state C { on true => backward() }
+       }
+       fun main(): void = M()",
+    );
+    // The second `B` is a duplicate, which is not unreachable as well.
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].contains("Duplicate state: `B`"));
+    assert!(errors[1].contains("State `C` of machine `M` is unreachable"));
+  }
+
+  #[test]
+  fn machines_may_not_run_themselves() {
+    assert_eq!(
+      check_errors(
+        "machine M {
+           state A { on true => M() }
+         }
+         fun main(): void = M()"
+      ),
+      vec!["Line 33: Machine `M` cannot run itself from its own transitions."]
+    );
+  }
+
+  #[test]
+  fn choose_weights_must_be_positive() {
+    assert_eq!(
+      check_errors("fun main(): void = choose { 0 => left(), 2 => right() }"),
+      vec!["Line 28: Weight of a `choose` branch must be positive."]
+    );
+  }
+
+  #[test]
+  fn temporaries_must_be_written_before_they_are_read_in_the_turn() {
+    assert_eq!(
+      check_errors("tmp t;\nfun main(): void = serve(t)"),
+      vec!["Line 32: Temporary `t` is read before it is written in this turn."]
+    );
+    // Writing on one path only leaves the other path reading whatever an earlier turn left.
+    assert_eq!(
+      check_errors(
+        "tmp t;\nfun main(): void = ({ if nearby(0) > 0 then ({ t = 1 }) else ({}); serve(t) })"
+      ),
+      vec!["Line 80: Temporary `t` is read before it is written in this turn."]
+    );
+    // An action ends the turn, so the write before it does not count after it.
+    assert_eq!(
+      check_errors("tmp t;\nfun main(): void = ({ t = nearby(0); forward(); serve(t) })"),
+      vec!["Line 61: Temporary `t` is read before it is written in this turn."]
+    );
+    assert!(check_errors("tmp t;\nfun main(): void = ({ t = nearby(0); serve(t) })").is_empty());
+  }
+
+  #[test]
+  fn functions_may_read_the_temporaries_their_callers_write() {
+    assert!(check_errors(
+      "tmp t;\nfun show(): void = serve(t)\nfun main(): void = ({ t = nearby(0); show() })"
+    )
+    .is_empty());
+    assert_eq!(
+      check_errors(
+        "tmp t;\nfun show(): void = serve(t)\nfun main(): void = ({ show(); t = nearby(0) })"
+      ),
+      vec!["Line 57: `show` reads temporary `t` before it is written in this turn."]
+    );
+  }
+
+  #[test]
+  fn initializers_may_not_branch_on_random_initial_values() {
+    let errors = check_errors(
+      "var a = random(6);
+       var b = a * 2;
+       var c = if b > 2 then 1 else 0;
+       fun main(): void = wait()",
+    );
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("The initializer of `c` branches on `b`"));
+    // Sensors read the same value for the whole turn, so the condition computes them again.
+    assert!(check_errors(
+      "var a = nearby(0);
+       var b = if a > 2 then a else random(6);
+       var c = b + 1;
+       fun main(): void = wait()"
+    )
+    .is_empty());
+  }
+
+  #[test]
+  fn contextual_keywords_still_name_variables_and_functions() {
+    assert!(check_errors(
+      "species Bug { defense: 1; offense: 1; size: 1; energy: 500; }
+       var species = 1;
+       var machine = 2;
+       tmp state;
+       fun on(goto: int): int = goto + species + machine
+       fun choose(tmp: int): int = tmp * 2
+       fun main(): void = ({ state = on(choose(nearby(0))); if state > 3 then wait() else left() })"
+    )
+    .is_empty());
+  }
+}
@@ -0,0 +1,281 @@
+use crate::ast::{
+  BinaryOperator, ExpressionStaticType, LiteralValue, SourceLanguageExpression,
+  SourceLanguageFunctionDefinition, SourceLanguageMutableGlobalVariableDefinition,
+  SourceLanguageProgram, CHOOSE_FUNCTION_NAME,
+};
+use crate::runtime::is_runtime_action;
+
+/// The global that holds the draw of a `choose` whose branches test it more than once, so that
+/// each test sees the same draw.
+const CHOOSE_VARIABLE: &str = "_choose";
+
+/// Returns whether the expression is a single runtime action with literal arguments, which never
+/// splits into several rules.
+fn is_single_action(expression: &SourceLanguageExpression) -> bool {
+  match expression {
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name,
+      function_arguments,
+    } => {
+      is_runtime_action(function_name)
+        && function_arguments.iter().all(|argument| {
+          matches!(
+            **argument,
+            SourceLanguageExpression::LiteralExpression { .. }
+          )
+        })
+    }
+    _ => false,
+  }
+}
+
+/// Lowers a checked `choose` to ifs over a single `random(total)` read. Branch `k` runs when the
+/// draw is below the sum of the first `k` weights.
+fn lower_choose(
+  line_number: usize,
+  weights_and_branches: &[Box<SourceLanguageExpression>],
+  stores_any_draw: &mut bool,
+) -> Box<SourceLanguageExpression> {
+  let mut bounds = Vec::new();
+  let mut total_weight: i32 = 0;
+  let mut branches = Vec::new();
+  for weight_and_branch in weights_and_branches.chunks(2) {
+    if let SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal: LiteralValue::IntLiteral(weight),
+    } = *weight_and_branch[0]
+    {
+      // The checker reports weights that add up to more than fits.
+      total_weight = total_weight.saturating_add(weight);
+    }
+    bounds.push(total_weight);
+    branches.push(lower_choose_expression(
+      &weight_and_branch[1],
+      stores_any_draw,
+    ));
+  }
+  // Two branches that are single actions test the draw once. Otherwise later tests, or the rules
+  // that a branching branch is split into, must see the same draw, so it is stored first.
+  let stores_draw = branches.len() > 2
+    || (branches.len() == 2 && !branches.iter().all(|branch| is_single_action(branch)));
+  let mut lowered = match branches.pop() {
+    Some(last_branch) => last_branch,
+    None => {
+      return Box::new(SourceLanguageExpression::ChainExpression {
+        line_number,
+        expressions: Vec::new(),
+      })
+    }
+  };
+
+  let random_draw = Box::new(SourceLanguageExpression::FunctionCallExpression {
+    line_number,
+    static_type: ExpressionStaticType::IntType,
+    function_name: "random".to_string(),
+    function_arguments: vec![Box::new(SourceLanguageExpression::LiteralExpression {
+      line_number,
+      literal: LiteralValue::IntLiteral(total_weight),
+    })],
+  });
+  let draw = if stores_draw {
+    Box::new(SourceLanguageExpression::VariableExpression {
+      line_number,
+      identifier: CHOOSE_VARIABLE.to_string(),
+    })
+  } else {
+    random_draw.clone()
+  };
+  for (branch, bound) in branches.into_iter().zip(bounds).rev() {
+    lowered = Box::new(SourceLanguageExpression::IfElseExpression {
+      line_number,
+      condition: Box::new(SourceLanguageExpression::BinaryExpression {
+        line_number,
+        operator: BinaryOperator::LT,
+        e1: draw.clone(),
+        e2: Box::new(SourceLanguageExpression::LiteralExpression {
+          line_number,
+          literal: LiteralValue::IntLiteral(bound),
+        }),
+      }),
+      e1: branch,
+      e2: lowered,
+    });
+  }
+  if stores_draw {
+    *stores_any_draw = true;
+    lowered = Box::new(SourceLanguageExpression::ChainExpression {
+      line_number,
+      expressions: vec![
+        Box::new(SourceLanguageExpression::AssignmentExpression {
+          line_number,
+          identifier: CHOOSE_VARIABLE.to_string(),
+          assigned_expression: random_draw,
+        }),
+        lowered,
+      ],
+    });
+  }
+  lowered
+}
+
+fn lower_choose_expression(
+  expression: &SourceLanguageExpression,
+  stores_any_draw: &mut bool,
+) -> Box<SourceLanguageExpression> {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => Box::new(expression.clone()),
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number,
+      static_type: _,
+      function_name,
+      function_arguments,
+    } if function_name == CHOOSE_FUNCTION_NAME => {
+      lower_choose(*line_number, function_arguments, stores_any_draw)
+    }
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number,
+      static_type,
+      function_name,
+      function_arguments,
+    } => Box::new(SourceLanguageExpression::FunctionCallExpression {
+      line_number: *line_number,
+      static_type: *static_type,
+      function_name: function_name.clone(),
+      function_arguments: function_arguments
+        .iter()
+        .map(|argument| lower_choose_expression(argument, stores_any_draw))
+        .collect(),
+    }),
+    SourceLanguageExpression::BinaryExpression {
+      line_number,
+      operator,
+      e1,
+      e2,
+    } => Box::new(SourceLanguageExpression::BinaryExpression {
+      line_number: *line_number,
+      operator: *operator,
+      e1: lower_choose_expression(e1, stores_any_draw),
+      e2: lower_choose_expression(e2, stores_any_draw),
+    }),
+    SourceLanguageExpression::IfElseExpression {
+      line_number,
+      condition,
+      e1,
+      e2,
+    } => Box::new(SourceLanguageExpression::IfElseExpression {
+      line_number: *line_number,
+      condition: lower_choose_expression(condition, stores_any_draw),
+      e1: lower_choose_expression(e1, stores_any_draw),
+      e2: lower_choose_expression(e2, stores_any_draw),
+    }),
+    SourceLanguageExpression::AssignmentExpression {
+      line_number,
+      identifier,
+      assigned_expression,
+    } => Box::new(SourceLanguageExpression::AssignmentExpression {
+      line_number: *line_number,
+      identifier: identifier.clone(),
+      assigned_expression: lower_choose_expression(assigned_expression, stores_any_draw),
+    }),
+    SourceLanguageExpression::ChainExpression {
+      line_number,
+      expressions,
+    } => Box::new(SourceLanguageExpression::ChainExpression {
+      line_number: *line_number,
+      expressions: expressions
+        .iter()
+        .map(|sub_expression| lower_choose_expression(sub_expression, stores_any_draw))
+        .collect(),
+    }),
+  }
+}
+
+/// Lowers every `choose` of a checked program to ifs over a `random` read, and adds the global that
+/// holds the draws that are tested more than once.
+pub fn lower_choose_expressions(program: &SourceLanguageProgram) -> SourceLanguageProgram {
+  let mut stores_any_draw = false;
+  let function_definitions = program
+    .function_definitions
+    .iter()
+    .map(|function| SourceLanguageFunctionDefinition {
+      line_number: function.line_number,
+      identifier: function.identifier.clone(),
+      function_arguments: function.function_arguments.clone(),
+      return_type: function.return_type,
+      body: lower_choose_expression(&function.body, &mut stores_any_draw),
+    })
+    .collect();
+  let mut global_variable_definitions = program.global_variable_definitions.clone();
+  if stores_any_draw {
+    global_variable_definitions.push(SourceLanguageMutableGlobalVariableDefinition {
+      line_number: 0,
+      identifier: CHOOSE_VARIABLE.to_string(),
+      assigned_expression: Box::new(SourceLanguageExpression::LiteralExpression {
+        line_number: 0,
+        literal: LiteralValue::IntLiteral(0),
+      }),
+      is_temporary: true,
+    });
+  }
+  SourceLanguageProgram {
+    global_variable_definitions,
+    machine_definitions: program.machine_definitions.clone(),
+    function_definitions,
+    species_declaration: program.species_declaration.clone(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::test_utilities::{compile, rules};
+
+  #[test]
+  fn choose_between_two_actions_draws_in_the_condition() {
+    let code = compile("fun main(): void = choose { 1 => left(), 3 => right() }").unwrap();
+    assert_eq!(
+      rules(&code),
+      vec!["(random[4] < 1) --> left;", "1 = 1 --> right;"]
+    );
+  }
+
+  #[test]
+  fn choose_between_more_branches_stores_one_draw() {
+    let code =
+      compile("fun main(): void = choose { 1 => left(), 2 => right(), 3 => forward() }").unwrap();
+    assert_eq!(code.matches("random").count(), 1);
+    assert!(code.contains(":= random[6]"));
+  }
+
+  #[test]
+  fn choose_stores_the_draw_when_a_branch_is_split_into_several_rules() {
+    let code = compile(
+      "fun main(): void = choose {
+         1 => (if nearby(0) > 0 then forward() else left()),
+         1 => (if nearby(1) > 0 then eat() else wait())
+       }",
+    )
+    .unwrap();
+    assert_eq!(code.matches("random").count(), 1);
+    assert!(code.contains(":= random[2]"));
+  }
+
+  #[test]
+  fn functions_named_choose_are_not_choose_expressions() {
+    let code = compile(
+      "fun choose(n: int): int = n * 2
+       fun main(): void = if choose(nearby(0)) > 3 then wait() else choose { 1 => left(), 1 => right() }",
+    )
+    .unwrap();
+    assert_eq!(
+      rules(&code),
+      vec![
+        "((nearby[0] * 2) > 3) --> wait;",
+        "(random[2] < 1) --> left;",
+        "1 = 1 --> right;"
+      ]
+    );
+  }
+}
@@ -1,14 +1,17 @@
 use crate::ast::{
   BinaryOperator, ExpressionStaticType, FullyInlinedProgram, IfElseBlock, LiteralValue,
-  SourceLanguageExpression, SourceLanguageProgram,
+  SourceLanguageExpression, SourceLanguageProgram, SPECIES_ATTRIBUTES,
 };
 use crate::inliner::program_inline;
+use crate::sequencer::LoweringMode;
+use crate::transformer::transform_to_if_else_blocks;
 use std::collections::HashMap;
 
 fn pretty_print(
   expression: &SourceLanguageExpression,
   string_builder: &mut String,
   variable_replacement_map: &HashMap<String, i32>,
+  diagnostics: &mut Vec<String>,
 ) -> () {
   match &expression {
     SourceLanguageExpression::LiteralExpression {
@@ -27,7 +30,7 @@ fn pretty_print(
       *variable_replacement_map.get(identifier).unwrap()
     )),
     SourceLanguageExpression::FunctionCallExpression {
-      line_number: _,
+      line_number,
       static_type,
       function_name,
       function_arguments,
@@ -72,10 +75,14 @@ fn pretty_print(
           &function_arguments[0],
           string_builder,
           variable_replacement_map,
+          diagnostics,
         );
         string_builder.push(']');
       } else {
-        panic!(format!("Unknown function {:}", function_name));
+        diagnostics.push(format!(
+          "Line {:}: Call to `{:}` could not be lowered into rules.",
+          line_number, function_name
+        ));
       }
     }
     SourceLanguageExpression::BinaryExpression {
@@ -86,7 +93,7 @@ fn pretty_print(
     } => {
       let is_condition = *operator == BinaryOperator::AND || *operator == BinaryOperator::OR;
       string_builder.push(if is_condition { '{' } else { '(' });
-      pretty_print(e1, string_builder, variable_replacement_map);
+      pretty_print(e1, string_builder, variable_replacement_map, diagnostics);
       string_builder.push(' ');
       match operator {
         BinaryOperator::MUL => string_builder.push('*'),
@@ -106,15 +113,18 @@ fn pretty_print(
         BinaryOperator::OR => string_builder.push_str("or"),
       }
       string_builder.push(' ');
-      pretty_print(e2, string_builder, variable_replacement_map);
+      pretty_print(e2, string_builder, variable_replacement_map, diagnostics);
       string_builder.push(if is_condition { '}' } else { ')' });
     }
     SourceLanguageExpression::IfElseExpression {
-      line_number: _,
+      line_number,
       condition: _,
       e1: _,
       e2: _,
-    } => panic!("should not be here!"),
+    } => diagnostics.push(format!(
+      "Line {:}: Conditional expression could not be lowered into rules.",
+      line_number
+    )),
     SourceLanguageExpression::AssignmentExpression {
       line_number: _,
       identifier,
@@ -128,6 +138,7 @@ fn pretty_print(
         assigned_expression,
         string_builder,
         variable_replacement_map,
+        diagnostics,
       );
     }
     SourceLanguageExpression::ChainExpression {
@@ -135,43 +146,363 @@ fn pretty_print(
       expressions,
     } => {
       for sub_expression in expressions {
-        pretty_print(sub_expression, string_builder, variable_replacement_map);
+        pretty_print(
+          sub_expression,
+          string_builder,
+          variable_replacement_map,
+          diagnostics,
+        );
       }
     }
   }
 }
 
-pub fn compile_to_critter_lang(program: &SourceLanguageProgram, inline_depth: usize) -> String {
+/// The name under which lowering refers to the flag slot, which is 0 until the globals are
+/// initialized.
+const INITIALIZED_FLAG_VARIABLE: &str = "_initialized";
+
+/// The last of the slots that critter reserves for its own attributes, from `MEMSIZE` at `mem[0]`
+/// to `POSTURE` at `mem[7]`.
+const LAST_RESERVED_SLOT: i32 = 7;
+
+/// How the compiled program initializes its globals on its first turn.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InitializationStrategy {
+  /// A first rule runs the initializers while the flag slot is 0, and sets it to 1. Programs
+  /// without globals to initialize get no rule.
+  FlagRule,
+  /// No rule at all. Every global has to start at 0, which is what fresh memory holds.
+  None,
+  /// No rule when every global starts at 0, and a flag rule otherwise.
+  Auto,
+}
+
+/// Where the compiled program keeps its state in critter memory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryLayout {
+  pub initialization_strategy: InitializationStrategy,
+  pub flag_slot: i32,
+  /// The slot of the first global. The others follow it.
+  pub base_slot: i32,
+}
+
+impl Default for MemoryLayout {
+  fn default() -> MemoryLayout {
+    MemoryLayout {
+      initialization_strategy: InitializationStrategy::FlagRule,
+      flag_slot: 8,
+      base_slot: 9,
+    }
+  }
+}
+
+pub struct CompiledCritterProgram {
+  pub code: String,
+  /// Human-readable notes about what the optimization passes did to the program.
+  pub notes: Vec<String>,
+}
+
+/// Compiles a type-checked program to critter rules. Returns the errors instead when the program
+/// cannot be compiled, or when the memory layout does not fit.
+pub fn compile_to_critter_lang(
+  program: &SourceLanguageProgram,
+  inline_depth: usize,
+  lowering_mode: LoweringMode,
+  memory_layout: MemoryLayout,
+) -> Result<CompiledCritterProgram, Vec<String>> {
   let FullyInlinedProgram {
     global_variable_definitions,
     if_else_blocks,
-  } = &*program_inline(program, inline_depth);
-
-  let mut string_builder = String::new();
-  string_builder.push_str("mem[8] = 0 --> mem[8] := 1");
+    notes,
+  } = &*program_inline(program, inline_depth, lowering_mode)?;
 
+  let MemoryLayout {
+    initialization_strategy,
+    flag_slot,
+    base_slot,
+  } = memory_layout;
   let mut variable_replacement_map = HashMap::new();
-  let mut variable_counter = 9;
+  variable_replacement_map.insert(INITIALIZED_FLAG_VARIABLE.to_string(), flag_slot);
+  let mut variable_counter = base_slot;
+  let mut initializations = Vec::new();
+  let mut nonzero_global_variables = Vec::new();
   for global_variable_definition in global_variable_definitions {
     variable_replacement_map.insert(
       global_variable_definition.identifier.clone(),
       variable_counter,
     );
-    string_builder.push_str(&format!(
-      " mem[{:}] := {:}",
-      variable_counter, global_variable_definition.assigned_value
-    ));
+    // Temporaries are always written before they are read in a turn.
+    if !global_variable_definition.is_temporary {
+      initializations.push(Box::new(SourceLanguageExpression::AssignmentExpression {
+        line_number: global_variable_definition.line_number,
+        identifier: global_variable_definition.identifier.clone(),
+        assigned_expression: global_variable_definition.assigned_expression.clone(),
+      }));
+      if !matches!(
+        *global_variable_definition.assigned_expression,
+        SourceLanguageExpression::LiteralExpression {
+          line_number: _,
+          literal: LiteralValue::IntLiteral(0),
+        }
+      ) {
+        nonzero_global_variables.push(global_variable_definition);
+      }
+    }
     variable_counter += 1;
   }
-  string_builder.push_str(";\n");
+
+  let has_flag_rule = match initialization_strategy {
+    InitializationStrategy::FlagRule => !initializations.is_empty(),
+    InitializationStrategy::None => false,
+    InitializationStrategy::Auto => !nonzero_global_variables.is_empty(),
+  };
+  let mut layout_errors = Vec::new();
+  if base_slot <= LAST_RESERVED_SLOT {
+    layout_errors.push(format!(
+      "The globals start at mem[{:}], which is reserved by critter. Use mem[{:}] or later.",
+      base_slot,
+      LAST_RESERVED_SLOT + 1
+    ));
+  }
+  if has_flag_rule && flag_slot <= LAST_RESERVED_SLOT {
+    layout_errors.push(format!(
+      "The initialization flag mem[{:}] is reserved by critter. Use mem[{:}] or later.",
+      flag_slot,
+      LAST_RESERVED_SLOT + 1
+    ));
+  }
+  if has_flag_rule && base_slot <= flag_slot && flag_slot < variable_counter {
+    layout_errors.push(format!(
+      "The initialization flag mem[{:}] collides with the globals in mem[{:}] to mem[{:}].",
+      flag_slot,
+      base_slot,
+      variable_counter - 1
+    ));
+  }
+  if !has_flag_rule {
+    for global_variable_definition in nonzero_global_variables {
+      layout_errors.push(format!(
+        "Line {:}: Global `{:}` does not start at 0, so it needs an initialization rule.",
+        global_variable_definition.line_number, global_variable_definition.identifier
+      ));
+    }
+  }
+  // Critter memory runs from mem[0] to mem[memsize - 1], and always covers the reserved slots.
+  let mut highest_slot = LAST_RESERVED_SLOT;
+  if variable_counter > base_slot {
+    highest_slot = std::cmp::max(highest_slot, variable_counter - 1);
+  }
+  if has_flag_rule {
+    highest_slot = std::cmp::max(highest_slot, flag_slot);
+  }
+  let mut string_builder = String::new();
+  if let Some(species_declaration) = &program.species_declaration {
+    let find_attribute = |name: &str| {
+      species_declaration
+        .attributes
+        .iter()
+        .find(|attribute| attribute.identifier == name)
+    };
+    string_builder.push_str(&format!("species: {:}\n", species_declaration.identifier));
+    for name in SPECIES_ATTRIBUTES {
+      let value = match (name, find_attribute(name)) {
+        ("memsize", Some(attribute)) => {
+          if attribute.value <= highest_slot {
+            layout_errors.push(format!(
+              "Line {:}: The species declares memsize {:}, but the program uses memory up to mem[{:}].",
+              attribute.line_number, attribute.value, highest_slot
+            ));
+          }
+          attribute.value
+        }
+        ("memsize", None) => highest_slot + 1,
+        (_, Some(attribute)) => attribute.value,
+        (_, None) => 0,
+      };
+      string_builder.push_str(&format!("{:}: {:}\n", name, value));
+    }
+    string_builder.push('\n');
+  }
+  if !layout_errors.is_empty() {
+    return Err(layout_errors);
+  }
+
+  let mut diagnostics = Vec::new();
+  // The initializers run in the first turn, before anything else. Initializers with ifs need one
+  // rule for every path, and each of these rules also tests the flag.
+  let initialization_blocks = if has_flag_rule {
+    transform_to_if_else_blocks(&SourceLanguageExpression::ChainExpression {
+      line_number: 0,
+      expressions: initializations,
+    })
+  } else {
+    Vec::new()
+  };
+  for IfElseBlock { condition, action } in initialization_blocks {
+    let flag_condition = SourceLanguageExpression::BinaryExpression {
+      line_number: 0,
+      operator: BinaryOperator::EQ,
+      e1: Box::new(SourceLanguageExpression::VariableExpression {
+        line_number: 0,
+        identifier: INITIALIZED_FLAG_VARIABLE.to_string(),
+      }),
+      e2: Box::new(SourceLanguageExpression::LiteralExpression {
+        line_number: 0,
+        literal: LiteralValue::IntLiteral(0),
+      }),
+    };
+    if let SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal: LiteralValue::BoolLiteral(true),
+    } = condition
+    {
+      string_builder.push_str(&format!("mem[{:}] = 0", flag_slot));
+    } else {
+      pretty_print(
+        &SourceLanguageExpression::BinaryExpression {
+          line_number: 0,
+          operator: BinaryOperator::AND,
+          e1: Box::new(flag_condition),
+          e2: Box::new(condition),
+        },
+        &mut string_builder,
+        &variable_replacement_map,
+        &mut diagnostics,
+      );
+    }
+    string_builder.push_str(&format!(" --> mem[{:}] := 1", flag_slot));
+    pretty_print(
+      &action,
+      &mut string_builder,
+      &variable_replacement_map,
+      &mut diagnostics,
+    );
+    string_builder.push_str(";\n");
+  }
 
   for if_else_block in if_else_blocks {
     let IfElseBlock { condition, action } = &*if_else_block;
-    pretty_print(condition, &mut string_builder, &variable_replacement_map);
+    pretty_print(
+      condition,
+      &mut string_builder,
+      &variable_replacement_map,
+      &mut diagnostics,
+    );
     string_builder.push_str(" -->");
-    pretty_print(action, &mut string_builder, &variable_replacement_map);
+    pretty_print(
+      action,
+      &mut string_builder,
+      &variable_replacement_map,
+      &mut diagnostics,
+    );
     string_builder.push_str(";\n");
   }
 
-  string_builder
+  if !diagnostics.is_empty() {
+    return Err(diagnostics);
+  }
+  Ok(CompiledCritterProgram {
+    code: string_builder,
+    notes: notes.clone(),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utilities::{checked_expression, compile, rules};
+
+  fn diagnostics_of(expression: &SourceLanguageExpression) -> Vec<String> {
+    let variable_replacement_map: HashMap<String, i32> =
+      vec![("x".to_string(), 9)].into_iter().collect();
+    let mut diagnostics = Vec::new();
+    pretty_print(
+      expression,
+      &mut String::new(),
+      &variable_replacement_map,
+      &mut diagnostics,
+    );
+    diagnostics
+  }
+
+  #[test]
+  fn leftover_conditionals_are_reported() {
+    let expression = checked_expression("void", "serve(1 + (if x == 0 then 1 else 2))");
+    assert_eq!(
+      vec!["Line 60: Conditional expression could not be lowered into rules."],
+      diagnostics_of(&expression)
+    );
+  }
+
+  #[test]
+  fn leftover_calls_to_user_functions_are_reported() {
+    let expression = SourceLanguageExpression::FunctionCallExpression {
+      line_number: 42,
+      static_type: ExpressionStaticType::IntType,
+      function_name: "helper".to_string(),
+      function_arguments: Vec::new(),
+    };
+    assert_eq!(
+      vec!["Line 42: Call to `helper` could not be lowered into rules."],
+      diagnostics_of(&expression)
+    );
+  }
+
+  #[test]
+  fn lowered_expressions_print_without_diagnostics() {
+    let expression = checked_expression("void", "serve(x + random(3))");
+    assert!(diagnostics_of(&expression).is_empty());
+  }
+
+  #[test]
+  fn species_header_lists_every_attribute_with_the_computed_memsize() {
+    let code = compile(
+      "species Bug { defense: 2; offense: 3; size: 1; energy: 500; }
+       var a = 1;
+       var b = 2;
+       fun main(): void = ({ a = a + b; wait() })",
+    )
+    .unwrap();
+    // `b` is never assigned, so only `a` takes a slot after the flag.
+    assert_eq!(
+      rules(&code)[..7].to_vec(),
+      vec![
+        "species: Bug",
+        "memsize: 10",
+        "defense: 2",
+        "offense: 3",
+        "size: 1",
+        "energy: 500",
+        "posture: 0",
+      ]
+    );
+    // Without globals there is no initialization rule, so the reserved slots are enough.
+    let code = compile(
+      "species Bug { defense: 2; offense: 3; size: 1; energy: 500; }
+       fun main(): void = wait()",
+    )
+    .unwrap();
+    assert!(code.contains("memsize: 8\n"));
+  }
+
+  #[test]
+  fn declared_memsize_must_cover_every_used_slot() {
+    assert_eq!(
+      compile(
+        "species Bug { memsize: 9; defense: 2; offense: 3; size: 1; energy: 500; }
+         var a = 1;
+         fun main(): void = ({ a = a + 1; wait() })"
+      ),
+      Err(vec![
+        "Line 14: The species declares memsize 9, but the program uses memory up to mem[9]."
+          .to_string()
+      ])
+    );
+    assert!(compile(
+      "species Bug { memsize: 10; defense: 2; offense: 3; size: 1; energy: 500; }
+       var a = 1;
+       fun main(): void = ({ a = a + 1; wait() })"
+    )
+    .is_ok());
+  }
 }
@@ -0,0 +1,509 @@
+use crate::ast::{
+  BinaryOperator, ExpressionStaticType, FullyInlinedProgram, IfElseBlock, LiteralValue,
+  SourceLanguageExpression, SourceLanguageMutableGlobalVariableDefinition,
+};
+use crate::evaluator::{has_side_effect, is_deterministic};
+use crate::propagator::{collect_assigned_variables, collect_read_variables};
+use crate::renamer::{count_expression_nodes, erase_line_numbers};
+use std::collections::{HashMap, HashSet};
+
+/// The most temporary memory slots the pass may allocate.
+const MAX_TEMPORARY_COUNT: usize = 16;
+
+/// The rough size, in expression nodes, of the rule that recomputes the temporaries.
+const RECOMPUTE_RULE_OVERHEAD: usize = 8;
+
+/// The size, in expression nodes, of the update that records the pass in an update-only rule.
+const RECORD_PASS_OVERHEAD: usize = 4;
+
+const RECOMPUTED_PASS_VARIABLE: &str = "_cseRecomputedPass";
+
+fn is_int_expression(expression: &SourceLanguageExpression) -> bool {
+  match expression {
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type,
+      function_name: _,
+      function_arguments: _,
+    } => *static_type == ExpressionStaticType::IntType,
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator,
+      e1: _,
+      e2: _,
+    } => matches!(
+      operator,
+      BinaryOperator::MUL
+        | BinaryOperator::DIV
+        | BinaryOperator::MOD
+        | BinaryOperator::PLUS
+        | BinaryOperator::MINUS
+    ),
+    _ => false,
+  }
+}
+
+/// Returns whether a rule action performs an action, which ends the turn.
+fn performs_action(action: &SourceLanguageExpression) -> bool {
+  match action {
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type,
+      function_name: _,
+      function_arguments: _,
+    } => *static_type == ExpressionStaticType::VoidType,
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => expressions
+      .iter()
+      .any(|sub_expression| performs_action(sub_expression)),
+    _ => false,
+  }
+}
+
+/// Counts every int subexpression that may be computed once per pass and read many times.
+fn count_candidates(
+  expression: &SourceLanguageExpression,
+  candidates: &mut HashMap<SourceLanguageExpression, usize>,
+) {
+  if is_int_expression(expression) && !has_side_effect(expression) && is_deterministic(expression) {
+    *candidates
+      .entry(*erase_line_numbers(expression))
+      .or_insert(0) += 1;
+  }
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => {}
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name: _,
+      function_arguments,
+    } => {
+      for argument_expression in function_arguments {
+        count_candidates(argument_expression, candidates);
+      }
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => {
+      count_candidates(e1, candidates);
+      count_candidates(e2, candidates);
+    }
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => {
+      count_candidates(condition, candidates);
+      count_candidates(e1, candidates);
+      count_candidates(e2, candidates);
+    }
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier: _,
+      assigned_expression,
+    } => count_candidates(assigned_expression, candidates),
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => {
+      for sub_expression in expressions {
+        count_candidates(sub_expression, candidates);
+      }
+    }
+  }
+}
+
+fn replace_subexpression(
+  expression: &SourceLanguageExpression,
+  subexpression: &SourceLanguageExpression,
+  replacement_identifier: &str,
+) -> Box<SourceLanguageExpression> {
+  if *erase_line_numbers(expression) == *subexpression {
+    return Box::new(SourceLanguageExpression::VariableExpression {
+      line_number: 0,
+      identifier: replacement_identifier.to_string(),
+    });
+  }
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => Box::new(expression.clone()),
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number,
+      static_type,
+      function_name,
+      function_arguments,
+    } => Box::new(SourceLanguageExpression::FunctionCallExpression {
+      line_number: *line_number,
+      static_type: *static_type,
+      function_name: function_name.clone(),
+      function_arguments: function_arguments
+        .iter()
+        .map(|e| replace_subexpression(e, subexpression, replacement_identifier))
+        .collect(),
+    }),
+    SourceLanguageExpression::BinaryExpression {
+      line_number,
+      operator,
+      e1,
+      e2,
+    } => Box::new(SourceLanguageExpression::BinaryExpression {
+      line_number: *line_number,
+      operator: *operator,
+      e1: replace_subexpression(e1, subexpression, replacement_identifier),
+      e2: replace_subexpression(e2, subexpression, replacement_identifier),
+    }),
+    SourceLanguageExpression::IfElseExpression {
+      line_number,
+      condition,
+      e1,
+      e2,
+    } => Box::new(SourceLanguageExpression::IfElseExpression {
+      line_number: *line_number,
+      condition: replace_subexpression(condition, subexpression, replacement_identifier),
+      e1: replace_subexpression(e1, subexpression, replacement_identifier),
+      e2: replace_subexpression(e2, subexpression, replacement_identifier),
+    }),
+    SourceLanguageExpression::AssignmentExpression {
+      line_number,
+      identifier,
+      assigned_expression,
+    } => Box::new(SourceLanguageExpression::AssignmentExpression {
+      line_number: *line_number,
+      identifier: identifier.clone(),
+      assigned_expression: replace_subexpression(
+        assigned_expression,
+        subexpression,
+        replacement_identifier,
+      ),
+    }),
+    SourceLanguageExpression::ChainExpression {
+      line_number,
+      expressions,
+    } => Box::new(SourceLanguageExpression::ChainExpression {
+      line_number: *line_number,
+      expressions: expressions
+        .iter()
+        .map(|e| replace_subexpression(e, subexpression, replacement_identifier))
+        .collect(),
+    }),
+  }
+}
+
+/// Returns the candidate that saves the most expression nodes when it is stored in a temporary,
+/// if storing any candidate saves anything at all. `update_only_writes` holds the variables that
+/// each update-only rule writes.
+fn find_most_profitable_candidate(
+  candidates: &HashMap<SourceLanguageExpression, usize>,
+  update_only_writes: &[HashSet<String>],
+  additional_overhead: usize,
+) -> Option<SourceLanguageExpression> {
+  let mut best_candidate: Option<(usize, &SourceLanguageExpression)> = None;
+  for (candidate, count) in candidates {
+    let size = count_expression_nodes(candidate);
+    // Every occurrence but the one in the recompute rule shrinks to a single memory read.
+    let saved = (count - 1) * (size - 1);
+    // The update-only rules that change what the candidate reads compute it again.
+    let mut read_variables = HashSet::new();
+    collect_read_variables(candidate, &mut read_variables);
+    let recompute_count = update_only_writes
+      .iter()
+      .filter(|written_variables| !written_variables.is_disjoint(&read_variables))
+      .count();
+    let cost = (size + 1) * (1 + recompute_count) + additional_overhead;
+    if saved <= cost {
+      continue;
+    }
+    let saving = saved - cost;
+    // Break ties by the debug representation so that the output is deterministic.
+    best_candidate = match best_candidate {
+      Some((best_saving, best))
+        if best_saving > saving
+          || (best_saving == saving && format!("{:?}", best) <= format!("{:?}", candidate)) =>
+      {
+        Some((best_saving, best))
+      }
+      _ => Some((saving, candidate)),
+    };
+  }
+  best_candidate.map(|(_, candidate)| candidate.clone())
+}
+
+/// Stores pure int subexpressions that repeat across rule conditions in temporary memory slots.
+///
+/// The temporaries are computed by an update-only rule placed before every other rule. It records
+/// the number of the pass that follows it and fires whenever `PASS` differs from that number,
+/// which costs one extra pass at the start of every turn. Every other update-only rule computes
+/// the temporaries that read what it writes again and records the pass as well, so that the rule
+/// does not fire again after it. Only conditions are rewritten, since they are all evaluated
+/// before any update of the pass happens.
+///
+/// All of these globals are temporaries without an initial value. The temporaries are written
+/// before any condition reads them, and the recorded pass is never 1, so the rule always fires on
+/// the first pass of a turn. Rules that the compiler adds later, like the initialization rule,
+/// do not record the pass, so the rule fires after them.
+pub fn eliminate_common_subexpressions(program: &FullyInlinedProgram) -> FullyInlinedProgram {
+  let mut if_else_blocks = program.if_else_blocks.clone();
+  let mut temporary_definitions: Vec<(String, SourceLanguageExpression)> = Vec::new();
+  let update_only_writes: Vec<HashSet<String>> = if_else_blocks
+    .iter()
+    .filter(|if_else_block| !performs_action(&if_else_block.action))
+    .map(|if_else_block| {
+      let mut written_variables = HashSet::new();
+      collect_assigned_variables(&if_else_block.action, &mut written_variables);
+      written_variables
+    })
+    .collect();
+
+  while temporary_definitions.len() < MAX_TEMPORARY_COUNT {
+    let mut candidates = HashMap::new();
+    for if_else_block in &if_else_blocks {
+      count_candidates(&if_else_block.condition, &mut candidates);
+    }
+    let additional_overhead = if temporary_definitions.is_empty() {
+      RECOMPUTE_RULE_OVERHEAD + RECORD_PASS_OVERHEAD * update_only_writes.len()
+    } else {
+      0
+    };
+    let candidate =
+      match find_most_profitable_candidate(&candidates, &update_only_writes, additional_overhead) {
+        Some(candidate) => candidate,
+        None => break,
+      };
+    let temporary_identifier = format!("_cse{:}", temporary_definitions.len());
+    if_else_blocks = if_else_blocks
+      .iter()
+      .map(|if_else_block| IfElseBlock {
+        condition: *replace_subexpression(
+          &if_else_block.condition,
+          &candidate,
+          &temporary_identifier,
+        ),
+        action: if_else_block.action.clone(),
+      })
+      .collect();
+    temporary_definitions.push((temporary_identifier, candidate));
+  }
+
+  if temporary_definitions.is_empty() {
+    return program.clone();
+  }
+
+  let pass = || {
+    Box::new(SourceLanguageExpression::FunctionCallExpression {
+      line_number: 0,
+      static_type: ExpressionStaticType::IntType,
+      function_name: "pass".to_string(),
+      function_arguments: Vec::new(),
+    })
+  };
+  let recomputed_pass = || {
+    Box::new(SourceLanguageExpression::VariableExpression {
+      line_number: 0,
+      identifier: RECOMPUTED_PASS_VARIABLE.to_string(),
+    })
+  };
+  let record_pass = || {
+    Box::new(SourceLanguageExpression::AssignmentExpression {
+      line_number: 0,
+      identifier: RECOMPUTED_PASS_VARIABLE.to_string(),
+      assigned_expression: Box::new(SourceLanguageExpression::BinaryExpression {
+        line_number: 0,
+        operator: BinaryOperator::PLUS,
+        e1: pass(),
+        e2: Box::new(SourceLanguageExpression::LiteralExpression {
+          line_number: 0,
+          literal: LiteralValue::IntLiteral(1),
+        }),
+      }),
+    })
+  };
+  let mut recompute_actions = vec![record_pass()];
+  let mut global_variable_definitions = program.global_variable_definitions.clone();
+  global_variable_definitions.push(SourceLanguageMutableGlobalVariableDefinition {
+    line_number: 0,
+    identifier: RECOMPUTED_PASS_VARIABLE.to_string(),
+    assigned_expression: Box::new(SourceLanguageExpression::LiteralExpression {
+      line_number: 0,
+      literal: LiteralValue::IntLiteral(0),
+    }),
+    is_temporary: true,
+  });
+  for (temporary_identifier, temporary_expression) in &temporary_definitions {
+    recompute_actions.push(Box::new(SourceLanguageExpression::AssignmentExpression {
+      line_number: 0,
+      identifier: temporary_identifier.clone(),
+      assigned_expression: Box::new(temporary_expression.clone()),
+    }));
+    global_variable_definitions.push(SourceLanguageMutableGlobalVariableDefinition {
+      line_number: 0,
+      identifier: temporary_identifier.clone(),
+      assigned_expression: Box::new(SourceLanguageExpression::LiteralExpression {
+        line_number: 0,
+        literal: LiteralValue::IntLiteral(0),
+      }),
+      is_temporary: true,
+    });
+  }
+
+  for if_else_block in &mut if_else_blocks {
+    if performs_action(&if_else_block.action) {
+      continue;
+    }
+    let mut changed_variables = HashSet::new();
+    collect_assigned_variables(&if_else_block.action, &mut changed_variables);
+    let mut expressions = match &if_else_block.action {
+      SourceLanguageExpression::ChainExpression {
+        line_number: _,
+        expressions,
+      } => expressions.clone(),
+      action => vec![Box::new(action.clone())],
+    };
+    // Later temporaries may read earlier ones, so a recomputed temporary counts as changed too.
+    for (temporary_identifier, temporary_expression) in &temporary_definitions {
+      let mut read_variables = HashSet::new();
+      collect_read_variables(temporary_expression, &mut read_variables);
+      if !read_variables.is_disjoint(&changed_variables) {
+        expressions.push(Box::new(SourceLanguageExpression::AssignmentExpression {
+          line_number: 0,
+          identifier: temporary_identifier.clone(),
+          assigned_expression: Box::new(temporary_expression.clone()),
+        }));
+        changed_variables.insert(temporary_identifier.clone());
+      }
+    }
+    expressions.push(record_pass());
+    if_else_block.action = SourceLanguageExpression::ChainExpression {
+      line_number: 0,
+      expressions,
+    };
+  }
+
+  let mut new_if_else_blocks = vec![IfElseBlock {
+    condition: SourceLanguageExpression::BinaryExpression {
+      line_number: 0,
+      operator: BinaryOperator::NE,
+      e1: recomputed_pass(),
+      e2: pass(),
+    },
+    action: SourceLanguageExpression::ChainExpression {
+      line_number: 0,
+      expressions: recompute_actions,
+    },
+  }];
+  new_if_else_blocks.append(&mut if_else_blocks);
+
+  let mut notes = program.notes.clone();
+  notes.push(format!(
+    "Stored {:} repeated subexpressions in temporary memory slots, computed by a rule that takes one extra pass per turn.",
+    temporary_definitions.len()
+  ));
+  FullyInlinedProgram {
+    global_variable_definitions,
+    if_else_blocks: new_if_else_blocks,
+    notes,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utilities::inlined_program;
+
+  const REPEATED: &str = "(x * 3 + x / 2 + x * x) % 7";
+
+  fn assigned_variables(action: &SourceLanguageExpression) -> HashSet<String> {
+    let mut assigned_variables = HashSet::new();
+    collect_assigned_variables(action, &mut assigned_variables);
+    assigned_variables
+  }
+
+  fn repeated_condition_program() -> FullyInlinedProgram {
+    inlined_program(&[
+      (&format!("{} == 6", REPEATED), "(y = y + 1)"),
+      (&format!("{} == 5", REPEATED), "(x = x + 1)"),
+      (&format!("{} == 4", REPEATED), "forward()"),
+      (&format!("{} == 3", REPEATED), "backward()"),
+      (&format!("{} == 2", REPEATED), "left()"),
+      ("true", "right()"),
+    ])
+  }
+
+  #[test]
+  fn repeated_condition_subexpressions_are_stored_once_per_pass() {
+    let program = eliminate_common_subexpressions(&repeated_condition_program());
+    assert_eq!(7, program.if_else_blocks.len());
+    let recompute_rule = &program.if_else_blocks[0];
+    assert_eq!(
+      vec![RECOMPUTED_PASS_VARIABLE, "_cse0"]
+        .into_iter()
+        .map(|identifier| identifier.to_string())
+        .collect::<HashSet<_>>(),
+      assigned_variables(&recompute_rule.action)
+    );
+    for if_else_block in &program.if_else_blocks[1..] {
+      let mut read_variables = HashSet::new();
+      collect_read_variables(&if_else_block.condition, &mut read_variables);
+      assert!(!read_variables.contains("x"));
+    }
+  }
+
+  #[test]
+  fn update_only_rules_recompute_the_temporaries_that_read_their_writes() {
+    let program = eliminate_common_subexpressions(&repeated_condition_program());
+    // Writing `x` changes the temporary, so the next pass of the turn must not read a stale
+    // value. The rule computes it again right after the write.
+    let x_rule = assigned_variables(&program.if_else_blocks[2].action);
+    assert!(x_rule.contains("x"));
+    assert!(x_rule.contains("_cse0"));
+    assert!(x_rule.contains(RECOMPUTED_PASS_VARIABLE));
+    // Writing `y` leaves the temporary as it is.
+    let y_rule = assigned_variables(&program.if_else_blocks[1].action);
+    assert!(y_rule.contains("y"));
+    assert!(!y_rule.contains("_cse0"));
+    assert!(y_rule.contains(RECOMPUTED_PASS_VARIABLE));
+    // Rules with an action end the turn, so they have nothing to record.
+    assert!(assigned_variables(&program.if_else_blocks[3].action).is_empty());
+  }
+
+  #[test]
+  fn recomputed_temporaries_follow_the_write_they_depend_on() {
+    let program = eliminate_common_subexpressions(&repeated_condition_program());
+    match &program.if_else_blocks[2].action {
+      SourceLanguageExpression::ChainExpression {
+        line_number: _,
+        expressions,
+      } => {
+        let identifiers: Vec<&str> = expressions
+          .iter()
+          .map(|expression| match &**expression {
+            SourceLanguageExpression::AssignmentExpression {
+              line_number: _,
+              identifier,
+              assigned_expression: _,
+            } => identifier.as_str(),
+            _ => panic!("{:?}", expression),
+          })
+          .collect();
+        assert_eq!(vec!["x", "_cse0", RECOMPUTED_PASS_VARIABLE], identifiers);
+      }
+      action => panic!("{:?}", action),
+    }
+  }
+
+  #[test]
+  fn subexpressions_that_do_not_pay_for_the_recompute_rule_are_kept() {
+    let original_program =
+      inlined_program(&[("x * 3 > 5", "forward()"), ("x * 3 > 2", "backward()")]);
+    assert_eq!(
+      original_program,
+      eliminate_common_subexpressions(&original_program)
+    );
+  }
+}
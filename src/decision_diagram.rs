@@ -0,0 +1,698 @@
+use crate::ast::{BinaryOperator, LiteralValue, SourceLanguageExpression};
+use crate::evaluator::{has_side_effect, is_deterministic};
+use crate::renamer::erase_line_numbers;
+use crate::solver::may_hold_together;
+use std::collections::HashMap;
+
+// An ordered, shared and reduced decision diagram whose leaves are if-free expressions. Every
+// decision tests an atom: the if-free condition of an if. Atoms are ordered by first appearance,
+// every node is stored once, and a decision with two identical branches is replaced by the branch.
+
+pub type NodeId = usize;
+
+/// The most decisions `prune_infeasible_paths` checks before it leaves the rest of a diagram as
+/// it is.
+const PRUNING_BUDGET: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum DiagramNode {
+  Leaf(usize),
+  Decision {
+    atom: usize,
+    then_node: NodeId,
+    else_node: NodeId,
+  },
+}
+
+pub struct DecisionDiagram {
+  nodes: Vec<DiagramNode>,
+  node_ids: HashMap<DiagramNode, NodeId>,
+  leaves: Vec<SourceLanguageExpression>,
+  leaf_ids: HashMap<SourceLanguageExpression, usize>,
+  atoms: Vec<SourceLanguageExpression>,
+  atom_ids: HashMap<SourceLanguageExpression, usize>,
+}
+
+/// Returns the negation of a condition built from comparisons with `and` and `or`, by flipping
+/// every comparison and swapping `and` with `or`. Critter has no `not`.
+pub fn negate_condition(
+  condition: &SourceLanguageExpression,
+) -> Option<Box<SourceLanguageExpression>> {
+  match condition {
+    SourceLanguageExpression::LiteralExpression {
+      line_number,
+      literal: LiteralValue::BoolLiteral(b),
+    } => Some(Box::new(SourceLanguageExpression::LiteralExpression {
+      line_number: *line_number,
+      literal: LiteralValue::BoolLiteral(!b),
+    })),
+    SourceLanguageExpression::BinaryExpression {
+      line_number,
+      operator,
+      e1,
+      e2,
+    } => {
+      let (negated_operator, is_connective) = match operator {
+        BinaryOperator::AND => (BinaryOperator::OR, true),
+        BinaryOperator::OR => (BinaryOperator::AND, true),
+        BinaryOperator::LT => (BinaryOperator::GE, false),
+        BinaryOperator::LE => (BinaryOperator::GT, false),
+        BinaryOperator::GT => (BinaryOperator::LE, false),
+        BinaryOperator::GE => (BinaryOperator::LT, false),
+        BinaryOperator::EQ => (BinaryOperator::NE, false),
+        BinaryOperator::NE => (BinaryOperator::EQ, false),
+        _ => return None,
+      };
+      let (negated_e1, negated_e2) = if is_connective {
+        (negate_condition(e1)?, negate_condition(e2)?)
+      } else {
+        (e1.clone(), e2.clone())
+      };
+      Some(Box::new(SourceLanguageExpression::BinaryExpression {
+        line_number: *line_number,
+        operator: negated_operator,
+        e1: negated_e1,
+        e2: negated_e2,
+      }))
+    }
+    _ => None,
+  }
+}
+
+fn bool_literal(b: bool) -> SourceLanguageExpression {
+  SourceLanguageExpression::LiteralExpression {
+    line_number: 0,
+    literal: LiteralValue::BoolLiteral(b),
+  }
+}
+
+impl DecisionDiagram {
+  pub fn new() -> DecisionDiagram {
+    DecisionDiagram {
+      nodes: Vec::new(),
+      node_ids: HashMap::new(),
+      leaves: Vec::new(),
+      leaf_ids: HashMap::new(),
+      atoms: Vec::new(),
+      atom_ids: HashMap::new(),
+    }
+  }
+
+  fn intern(&mut self, node: DiagramNode) -> NodeId {
+    if let Some(id) = self.node_ids.get(&node) {
+      return *id;
+    }
+    self.nodes.push(node);
+    self.node_ids.insert(node, self.nodes.len() - 1);
+    self.nodes.len() - 1
+  }
+
+  fn decision(&mut self, atom: usize, then_node: NodeId, else_node: NodeId) -> NodeId {
+    if then_node == else_node {
+      then_node
+    } else {
+      self.intern(DiagramNode::Decision {
+        atom,
+        then_node,
+        else_node,
+      })
+    }
+  }
+
+  /// The diagram that always evaluates to `expression`, which must not contain ifs.
+  pub fn leaf(&mut self, expression: &SourceLanguageExpression) -> NodeId {
+    let key = *erase_line_numbers(expression);
+    let leaf = match self.leaf_ids.get(&key) {
+      Some(leaf) => *leaf,
+      None => {
+        self.leaves.push(expression.clone());
+        self.leaf_ids.insert(key, self.leaves.len() - 1);
+        self.leaves.len() - 1
+      }
+    };
+    self.intern(DiagramNode::Leaf(leaf))
+  }
+
+  /// The diagram of a single atom. Every occurrence of a nondeterministic atom is a separate
+  /// evaluation, so it gets an atom of its own.
+  fn atom(&mut self, expression: &SourceLanguageExpression) -> NodeId {
+    let key = *erase_line_numbers(expression);
+    let is_shareable = !has_side_effect(expression) && is_deterministic(expression);
+    let atom = match self.atom_ids.get(&key) {
+      Some(atom) if is_shareable => *atom,
+      _ => {
+        self.atoms.push(expression.clone());
+        if is_shareable {
+          self.atom_ids.insert(key, self.atoms.len() - 1);
+        }
+        self.atoms.len() - 1
+      }
+    };
+    let then_node = self.leaf(&bool_literal(true));
+    let else_node = self.leaf(&bool_literal(false));
+    self.decision(atom, then_node, else_node)
+  }
+
+  fn top_atom(&self, node: NodeId) -> usize {
+    match self.nodes[node] {
+      DiagramNode::Leaf(_) => usize::MAX,
+      DiagramNode::Decision {
+        atom,
+        then_node: _,
+        else_node: _,
+      } => atom,
+    }
+  }
+
+  /// Returns the branches of `node` for `atom` being true and false.
+  fn cofactors(&self, node: NodeId, atom: usize) -> (NodeId, NodeId) {
+    match self.nodes[node] {
+      DiagramNode::Decision {
+        atom: node_atom,
+        then_node,
+        else_node,
+      } if node_atom == atom => (then_node, else_node),
+      _ => (node, node),
+    }
+  }
+
+  fn get_bool_leaf(&self, node: NodeId) -> Option<bool> {
+    match self.nodes[node] {
+      DiagramNode::Leaf(leaf) => match &self.leaves[leaf] {
+        SourceLanguageExpression::LiteralExpression {
+          line_number: _,
+          literal: LiteralValue::BoolLiteral(b),
+        } => Some(*b),
+        _ => None,
+      },
+      DiagramNode::Decision { .. } => None,
+    }
+  }
+
+  /// The diagram of a boolean expression without ifs, which tests it as a single atom. Comparisons
+  /// are canonicalized to `<`, `<=` or `=`, so that `a >= b` tests the same atom as `a < b` with
+  /// the branches swapped.
+  pub fn condition(&mut self, expression: &SourceLanguageExpression) -> NodeId {
+    match expression {
+      SourceLanguageExpression::LiteralExpression {
+        line_number: _,
+        literal: LiteralValue::BoolLiteral(_),
+      } => self.leaf(expression),
+      SourceLanguageExpression::BinaryExpression {
+        line_number,
+        operator: operator @ (BinaryOperator::GE | BinaryOperator::GT | BinaryOperator::NE),
+        e1,
+        e2,
+      } => {
+        let canonical_operator = match operator {
+          BinaryOperator::GE => BinaryOperator::LT,
+          BinaryOperator::GT => BinaryOperator::LE,
+          _ => BinaryOperator::EQ,
+        };
+        let atom_node = self.atom(&SourceLanguageExpression::BinaryExpression {
+          line_number: *line_number,
+          operator: canonical_operator,
+          e1: e1.clone(),
+          e2: e2.clone(),
+        });
+        let true_node = self.leaf(&bool_literal(true));
+        let false_node = self.leaf(&bool_literal(false));
+        self.if_then_else(atom_node, false_node, true_node)
+      }
+      _ => self.atom(expression),
+    }
+  }
+
+  /// Turns a diagram whose leaves are boolean expressions into a diagram over their atoms, whose
+  /// leaves are `true` and `false`.
+  pub fn values_to_condition(&mut self, node: NodeId) -> NodeId {
+    let mut memo = HashMap::new();
+    self.values_to_condition_memoized(node, &mut memo)
+  }
+
+  fn values_to_condition_memoized(
+    &mut self,
+    node: NodeId,
+    memo: &mut HashMap<NodeId, NodeId>,
+  ) -> NodeId {
+    if let Some(result) = memo.get(&node) {
+      return *result;
+    }
+    let result = match self.nodes[node] {
+      DiagramNode::Leaf(leaf) => {
+        let expression = self.leaves[leaf].clone();
+        self.condition(&expression)
+      }
+      DiagramNode::Decision {
+        atom,
+        then_node,
+        else_node,
+      } => {
+        let then_condition = self.values_to_condition_memoized(then_node, memo);
+        let else_condition = self.values_to_condition_memoized(else_node, memo);
+        let true_node = self.leaf(&bool_literal(true));
+        let false_node = self.leaf(&bool_literal(false));
+        let atom_node = self.decision(atom, true_node, false_node);
+        self.if_then_else(atom_node, then_condition, else_condition)
+      }
+    };
+    memo.insert(node, result);
+    result
+  }
+
+  /// The diagram that follows `then_node` where the condition diagram `condition` holds and
+  /// `else_node` elsewhere.
+  pub fn if_then_else(
+    &mut self,
+    condition: NodeId,
+    then_node: NodeId,
+    else_node: NodeId,
+  ) -> NodeId {
+    let mut memo = HashMap::new();
+    self.if_then_else_memoized(condition, then_node, else_node, &mut memo)
+  }
+
+  fn if_then_else_memoized(
+    &mut self,
+    condition: NodeId,
+    then_node: NodeId,
+    else_node: NodeId,
+    memo: &mut HashMap<(NodeId, NodeId, NodeId), NodeId>,
+  ) -> NodeId {
+    match self.get_bool_leaf(condition) {
+      Some(true) => return then_node,
+      Some(false) => return else_node,
+      None => {}
+    }
+    if then_node == else_node {
+      return then_node;
+    }
+    if let Some(result) = memo.get(&(condition, then_node, else_node)) {
+      return *result;
+    }
+    let atom = self
+      .top_atom(condition)
+      .min(self.top_atom(then_node))
+      .min(self.top_atom(else_node));
+    let (c1, c0) = self.cofactors(condition, atom);
+    let (t1, t0) = self.cofactors(then_node, atom);
+    let (e1, e0) = self.cofactors(else_node, atom);
+    let result_then = self.if_then_else_memoized(c1, t1, e1, memo);
+    let result_else = self.if_then_else_memoized(c0, t0, e0, memo);
+    let result = self.decision(atom, result_then, result_else);
+    memo.insert((condition, then_node, else_node), result);
+    result
+  }
+
+  /// Applies `combine` to the leaves of two diagrams along every path through both.
+  pub fn combine(
+    &mut self,
+    n1: NodeId,
+    n2: NodeId,
+    combine: &dyn Fn(
+      &SourceLanguageExpression,
+      &SourceLanguageExpression,
+    ) -> SourceLanguageExpression,
+  ) -> NodeId {
+    let mut memo = HashMap::new();
+    self.combine_memoized(n1, n2, combine, &mut memo)
+  }
+
+  fn combine_memoized(
+    &mut self,
+    n1: NodeId,
+    n2: NodeId,
+    combine: &dyn Fn(
+      &SourceLanguageExpression,
+      &SourceLanguageExpression,
+    ) -> SourceLanguageExpression,
+    memo: &mut HashMap<(NodeId, NodeId), NodeId>,
+  ) -> NodeId {
+    if let Some(result) = memo.get(&(n1, n2)) {
+      return *result;
+    }
+    let result = match (self.nodes[n1], self.nodes[n2]) {
+      (DiagramNode::Leaf(l1), DiagramNode::Leaf(l2)) => {
+        let combined = combine(&self.leaves[l1], &self.leaves[l2]);
+        self.leaf(&combined)
+      }
+      _ => {
+        let atom = self.top_atom(n1).min(self.top_atom(n2));
+        let (n1_then, n1_else) = self.cofactors(n1, atom);
+        let (n2_then, n2_else) = self.cofactors(n2, atom);
+        let result_then = self.combine_memoized(n1_then, n2_then, combine, memo);
+        let result_else = self.combine_memoized(n1_else, n2_else, combine, memo);
+        self.decision(atom, result_then, result_else)
+      }
+    };
+    memo.insert((n1, n2), result);
+    result
+  }
+
+  /// Applies `transform` to every leaf of a diagram.
+  pub fn map(
+    &mut self,
+    node: NodeId,
+    transform: &dyn Fn(&SourceLanguageExpression) -> SourceLanguageExpression,
+  ) -> NodeId {
+    let unit = self.leaf(&bool_literal(true));
+    self.combine(node, unit, &|leaf, _| transform(leaf))
+  }
+
+  /// Replaces every leaf of a diagram with the diagram that `expand` builds for it, so that what
+  /// follows a leaf may depend on the leaf itself.
+  pub fn flat_map(
+    &mut self,
+    node: NodeId,
+    expand: &mut dyn FnMut(&mut DecisionDiagram, &SourceLanguageExpression) -> NodeId,
+  ) -> NodeId {
+    let mut memo = HashMap::new();
+    self.flat_map_memoized(node, expand, &mut memo)
+  }
+
+  fn flat_map_memoized(
+    &mut self,
+    node: NodeId,
+    expand: &mut dyn FnMut(&mut DecisionDiagram, &SourceLanguageExpression) -> NodeId,
+    memo: &mut HashMap<NodeId, NodeId>,
+  ) -> NodeId {
+    if let Some(result) = memo.get(&node) {
+      return *result;
+    }
+    let result = match self.nodes[node] {
+      DiagramNode::Leaf(leaf) => {
+        let expression = self.leaves[leaf].clone();
+        expand(self, &expression)
+      }
+      DiagramNode::Decision {
+        atom,
+        then_node,
+        else_node,
+      } => {
+        let then_result = self.flat_map_memoized(then_node, expand, memo);
+        let else_result = self.flat_map_memoized(else_node, expand, memo);
+        let true_node = self.leaf(&bool_literal(true));
+        let false_node = self.leaf(&bool_literal(false));
+        let atom_node = self.decision(atom, true_node, false_node);
+        self.if_then_else(atom_node, then_result, else_result)
+      }
+    };
+    memo.insert(node, result);
+    result
+  }
+
+  /// Removes every decision whose outcome is already decided by the atoms tested above it, such
+  /// as a test of `x = 1` on a path where `x = 0` holds.
+  pub fn prune_infeasible_paths(&mut self, node: NodeId) -> NodeId {
+    let mut budget = PRUNING_BUDGET;
+    self.prune_memoized(node, &mut Vec::new(), &mut budget)
+  }
+
+  fn prune_memoized(
+    &mut self,
+    node: NodeId,
+    path: &mut Vec<(SourceLanguageExpression, bool)>,
+    budget: &mut usize,
+  ) -> NodeId {
+    let (atom, then_node, else_node) = match self.nodes[node] {
+      DiagramNode::Leaf(_) => return node,
+      DiagramNode::Decision {
+        atom,
+        then_node,
+        else_node,
+      } => (atom, then_node, else_node),
+    };
+    if *budget == 0 {
+      return node;
+    }
+    *budget -= 1;
+    let outcome_may_hold = |value: bool| {
+      let mut conditions: Vec<(&SourceLanguageExpression, bool)> =
+        path.iter().map(|(atom, value)| (atom, *value)).collect();
+      conditions.push((&self.atoms[atom], value));
+      may_hold_together(&conditions)
+    };
+    let (then_may_hold, else_may_hold) = (outcome_may_hold(true), outcome_may_hold(false));
+    let atom_expression = self.atoms[atom].clone();
+    let mut prune_branch = |diagram: &mut DecisionDiagram, branch: NodeId, value: bool| {
+      path.push((atom_expression.clone(), value));
+      let result = diagram.prune_memoized(branch, path, budget);
+      path.pop();
+      result
+    };
+    match (then_may_hold, else_may_hold) {
+      (false, _) => prune_branch(self, else_node, false),
+      (_, false) => prune_branch(self, then_node, true),
+      _ => {
+        let then_result = prune_branch(self, then_node, true);
+        let else_result = prune_branch(self, else_node, false);
+        self.decision(atom, then_result, else_result)
+      }
+    }
+  }
+
+  fn count_paths(&self, node: NodeId, memo: &mut HashMap<NodeId, usize>) -> usize {
+    if let Some(count) = memo.get(&node) {
+      return *count;
+    }
+    let count = match self.nodes[node] {
+      DiagramNode::Leaf(_) => 1,
+      DiagramNode::Decision {
+        atom: _,
+        then_node,
+        else_node,
+      } => self
+        .count_paths(then_node, memo)
+        .saturating_add(self.count_paths(else_node, memo)),
+    };
+    memo.insert(node, count);
+    count
+  }
+
+  /// Lists every path to a leaf as the conditions tested along it, paired with the leaf. At every
+  /// decision, the paths through the branch with fewer paths are listed first and carry the atom,
+  /// negated for the else-branch. The paths through the other branch omit it: the earlier paths
+  /// cover every state in which it goes the other way.
+  pub fn paths(
+    &self,
+    node: NodeId,
+  ) -> Vec<(Vec<SourceLanguageExpression>, SourceLanguageExpression)> {
+    let mut paths = Vec::new();
+    let mut path_counts = HashMap::new();
+    self.collect_paths(node, &mut Vec::new(), &mut path_counts, &mut paths);
+    paths
+  }
+
+  fn collect_paths(
+    &self,
+    node: NodeId,
+    path: &mut Vec<SourceLanguageExpression>,
+    path_counts: &mut HashMap<NodeId, usize>,
+    paths: &mut Vec<(Vec<SourceLanguageExpression>, SourceLanguageExpression)>,
+  ) {
+    let (atom, then_node, else_node) = match self.nodes[node] {
+      DiagramNode::Leaf(leaf) => {
+        paths.push((path.clone(), self.leaves[leaf].clone()));
+        return;
+      }
+      DiagramNode::Decision {
+        atom,
+        then_node,
+        else_node,
+      } => (atom, then_node, else_node),
+    };
+    let else_first =
+      self.count_paths(else_node, path_counts) < self.count_paths(then_node, path_counts);
+    match negate_condition(&self.atoms[atom]) {
+      Some(negated_atom) if else_first => {
+        path.push(*negated_atom);
+        self.collect_paths(else_node, path, path_counts, paths);
+        path.pop();
+        self.collect_paths(then_node, path, path_counts, paths);
+      }
+      _ => {
+        path.push(self.atoms[atom].clone());
+        self.collect_paths(then_node, path, path_counts, paths);
+        path.pop();
+        self.collect_paths(else_node, path, path_counts, paths);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utilities::checked_condition;
+
+  fn int(i: i32) -> SourceLanguageExpression {
+    SourceLanguageExpression::LiteralExpression {
+      line_number: 0,
+      literal: LiteralValue::IntLiteral(i),
+    }
+  }
+
+  fn get_int(expression: &SourceLanguageExpression) -> i32 {
+    match expression {
+      SourceLanguageExpression::LiteralExpression {
+        line_number: _,
+        literal: LiteralValue::IntLiteral(i),
+      } => *i,
+      _ => panic!("Expected an int leaf, found {:?}", expression),
+    }
+  }
+
+  /// A diagram whose atoms are ordered like the given conditions.
+  fn diagram_over(sources: &[&str]) -> DecisionDiagram {
+    let mut diagram = DecisionDiagram::new();
+    for source in sources {
+      diagram.condition(&checked_condition(source));
+    }
+    diagram
+  }
+
+  /// The diagram of `if condition then then_value else else_value`.
+  fn choice(
+    diagram: &mut DecisionDiagram,
+    condition: &str,
+    then_node: NodeId,
+    else_node: NodeId,
+  ) -> NodeId {
+    let condition_node = diagram.condition(&checked_condition(condition));
+    diagram.if_then_else(condition_node, then_node, else_node)
+  }
+
+  /// The paths of a diagram as the tested conditions and the int leaf.
+  fn int_paths(
+    diagram: &DecisionDiagram,
+    node: NodeId,
+  ) -> Vec<(Vec<SourceLanguageExpression>, i32)> {
+    diagram
+      .paths(node)
+      .into_iter()
+      .map(|(conditions, leaf)| {
+        (
+          conditions
+            .iter()
+            .map(|condition| *erase_line_numbers(condition))
+            .collect(),
+          get_int(&leaf),
+        )
+      })
+      .collect()
+  }
+
+  fn conditions(sources: &[&str]) -> Vec<SourceLanguageExpression> {
+    sources
+      .iter()
+      .map(|source| *erase_line_numbers(&checked_condition(source)))
+      .collect()
+  }
+
+  fn add(l1: &SourceLanguageExpression, l2: &SourceLanguageExpression) -> SourceLanguageExpression {
+    int(get_int(l1) + get_int(l2))
+  }
+
+  #[test]
+  fn decisions_with_identical_branches_are_reduced() {
+    let mut diagram = DecisionDiagram::new();
+    let leaf = diagram.leaf(&int(1));
+    assert_eq!(choice(&mut diagram, "x < 1", leaf, leaf), leaf);
+    let one = diagram.leaf(&int(1));
+    let two = diagram.leaf(&int(2));
+    let n1 = choice(&mut diagram, "x < 1", one, two);
+    // The same comparison, spelled the other way around, is the same atom and the same node.
+    let n2 = choice(&mut diagram, "x >= 1", two, one);
+    assert_eq!(n1, n2);
+  }
+
+  #[test]
+  fn combine_follows_every_path_through_both_diagrams() {
+    let mut diagram = DecisionDiagram::new();
+    let (one, two, ten, twenty) = (
+      diagram.leaf(&int(1)),
+      diagram.leaf(&int(2)),
+      diagram.leaf(&int(10)),
+      diagram.leaf(&int(20)),
+    );
+    let d1 = choice(&mut diagram, "x < 1", one, two);
+    let d2 = choice(&mut diagram, "y < 1", ten, twenty);
+    let combined = diagram.combine(d1, d2, &add);
+    let mut sums: Vec<i32> = int_paths(&diagram, combined)
+      .into_iter()
+      .map(|(_, sum)| sum)
+      .collect();
+    sums.sort_unstable();
+    assert_eq!(sums, vec![11, 12, 21, 22]);
+    // Both sides test the same atom, so they always take the same branch.
+    let doubled = diagram.combine(d1, d1, &add);
+    assert_eq!(
+      int_paths(&diagram, doubled),
+      vec![(conditions(&["x < 1"]), 2), (Vec::new(), 4)]
+    );
+  }
+
+  /// `if x < 1 then (if x < 5 then 1 else 2) else 3`, where the leaf 2 can never be reached.
+  fn nested_diagram() -> (DecisionDiagram, NodeId) {
+    let mut diagram = diagram_over(&["x < 1", "x < 5"]);
+    let (one, two, three) = (
+      diagram.leaf(&int(1)),
+      diagram.leaf(&int(2)),
+      diagram.leaf(&int(3)),
+    );
+    let inner = choice(&mut diagram, "x < 5", one, two);
+    let root = choice(&mut diagram, "x < 1", inner, three);
+    (diagram, root)
+  }
+
+  #[test]
+  fn prune_infeasible_paths_removes_decided_tests() {
+    let (mut diagram, root) = nested_diagram();
+    assert_eq!(int_paths(&diagram, root).len(), 3);
+    let pruned = diagram.prune_infeasible_paths(root);
+    assert_eq!(
+      int_paths(&diagram, pruned),
+      vec![(conditions(&["x < 1"]), 1), (Vec::new(), 3)]
+    );
+  }
+
+  #[test]
+  fn pruning_stops_when_the_budget_runs_out() {
+    let (mut diagram, root) = nested_diagram();
+    assert_eq!(diagram.prune_memoized(root, &mut Vec::new(), &mut 0), root);
+    // The budget only covers the outer decision, so the inner one stays as it is.
+    let partially_pruned = diagram.prune_memoized(root, &mut Vec::new(), &mut 1);
+    assert_eq!(int_paths(&diagram, partially_pruned).len(), 3);
+  }
+
+  #[test]
+  fn paths_list_the_branch_with_fewer_paths_first() {
+    let mut diagram = diagram_over(&["x < 1", "y < 1"]);
+    let (one, two, three) = (
+      diagram.leaf(&int(1)),
+      diagram.leaf(&int(2)),
+      diagram.leaf(&int(3)),
+    );
+    let else_nested = choice(&mut diagram, "y < 1", two, three);
+    let root = choice(&mut diagram, "x < 1", one, else_nested);
+    assert_eq!(
+      int_paths(&diagram, root),
+      vec![
+        (conditions(&["x < 1"]), 1),
+        (conditions(&["y < 1"]), 2),
+        (Vec::new(), 3),
+      ]
+    );
+    // With more paths in the then-branch, the else-branch comes first with the negated atom.
+    let then_nested = choice(&mut diagram, "y < 1", one, two);
+    let root = choice(&mut diagram, "x < 1", then_nested, three);
+    assert_eq!(
+      int_paths(&diagram, root),
+      vec![
+        (conditions(&["x >= 1"]), 3),
+        (conditions(&["y < 1"]), 1),
+        (Vec::new(), 2),
+      ]
+    );
+  }
+}
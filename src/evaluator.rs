@@ -1,4 +1,194 @@
-use crate::ast::{BinaryOperator, LiteralValue, SourceLanguageExpression};
+use crate::arithmetic::apply_binary_operator;
+use crate::ast::{BinaryOperator, ExpressionStaticType, LiteralValue, SourceLanguageExpression};
+use crate::renamer::erase_line_numbers;
+use crate::runtime::is_deterministic_runtime_function;
+
+/// Returns whether evaluating the expression may change the critter's memory or the world.
+pub fn has_side_effect(expression: &SourceLanguageExpression) -> bool {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => false,
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type,
+      function_name: _,
+      function_arguments,
+    } => {
+      *static_type == ExpressionStaticType::VoidType
+        || function_arguments.iter().any(|e| has_side_effect(e))
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => has_side_effect(e1) || has_side_effect(e2),
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => has_side_effect(condition) || has_side_effect(e1) || has_side_effect(e2),
+    SourceLanguageExpression::AssignmentExpression { .. }
+    | SourceLanguageExpression::ChainExpression { .. } => true,
+  }
+}
+
+/// Returns whether evaluating the expression twice within the same turn always gives the same
+/// value. Calls to user functions are conservatively treated as non-deterministic.
+pub fn is_deterministic(expression: &SourceLanguageExpression) -> bool {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => true,
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name,
+      function_arguments,
+    } => {
+      is_deterministic_runtime_function(function_name)
+        && function_arguments.iter().all(|e| is_deterministic(e))
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => is_deterministic(e1) && is_deterministic(e2),
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => is_deterministic(condition) && is_deterministic(e1) && is_deterministic(e2),
+    SourceLanguageExpression::AssignmentExpression { .. }
+    | SourceLanguageExpression::ChainExpression { .. } => false,
+  }
+}
+
+fn are_same_expression(e1: &SourceLanguageExpression, e2: &SourceLanguageExpression) -> bool {
+  erase_line_numbers(e1) == erase_line_numbers(e2)
+}
+
+/// Returns whether `e1 op e2` may be replaced by a constant that does not mention the operands,
+/// because both operands always evaluate to the same value.
+fn is_repeated_pure_operand(e1: &SourceLanguageExpression, e2: &SourceLanguageExpression) -> bool {
+  !has_side_effect(e1) && is_deterministic(e1) && are_same_expression(e1, e2)
+}
+
+fn get_int_literal(expression: &SourceLanguageExpression) -> Option<i32> {
+  match expression {
+    SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal: LiteralValue::IntLiteral(i),
+    } => Some(*i),
+    _ => None,
+  }
+}
+
+fn get_bool_literal(expression: &SourceLanguageExpression) -> Option<bool> {
+  match expression {
+    SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal: LiteralValue::BoolLiteral(b),
+    } => Some(*b),
+    _ => None,
+  }
+}
+
+/// Simplifies a binary expression whose operands are already evaluated. Both-literal operands are
+/// folded. Otherwise, identity, annihilator and idempotence rules are tried in order. A rule that
+/// would drop an operand only fires when the dropped operand has no side effect.
+fn simplify_binary_expression(
+  line_number: usize,
+  operator: BinaryOperator,
+  e1: Box<SourceLanguageExpression>,
+  e2: Box<SourceLanguageExpression>,
+) -> Box<SourceLanguageExpression> {
+  let int_literal = |i: i32| {
+    Box::new(SourceLanguageExpression::LiteralExpression {
+      line_number,
+      literal: LiteralValue::IntLiteral(i),
+    })
+  };
+  let bool_literal = |b: bool| {
+    Box::new(SourceLanguageExpression::LiteralExpression {
+      line_number,
+      literal: LiteralValue::BoolLiteral(b),
+    })
+  };
+  if let (
+    SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal: l1,
+    },
+    SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal: l2,
+    },
+  ) = (&*e1, &*e2)
+  {
+    if let Some(literal) = apply_binary_operator(operator, *l1, *l2) {
+      return Box::new(SourceLanguageExpression::LiteralExpression {
+        line_number,
+        literal,
+      });
+    }
+  }
+  let i1 = get_int_literal(&e1);
+  let i2 = get_int_literal(&e2);
+  let b1 = get_bool_literal(&e1);
+  let b2 = get_bool_literal(&e2);
+  match operator {
+    // 0 + x ==> x, x + 0 ==> x
+    BinaryOperator::PLUS if i1 == Some(0) => e2,
+    BinaryOperator::PLUS if i2 == Some(0) => e1,
+    // x - 0 ==> x, x - x ==> 0
+    BinaryOperator::MINUS if i2 == Some(0) => e1,
+    BinaryOperator::MINUS if is_repeated_pure_operand(&e1, &e2) => int_literal(0),
+    // 1 * x ==> x, x * 1 ==> x, 0 * x ==> 0, x * 0 ==> 0
+    BinaryOperator::MUL if i1 == Some(1) => e2,
+    BinaryOperator::MUL if i2 == Some(1) => e1,
+    BinaryOperator::MUL if i1 == Some(0) && !has_side_effect(&e2) => int_literal(0),
+    BinaryOperator::MUL if i2 == Some(0) && !has_side_effect(&e1) => int_literal(0),
+    // x / 1 ==> x, 0 / x ==> 0 (division by zero is 0 in the critter world)
+    BinaryOperator::DIV if i2 == Some(1) => e1,
+    BinaryOperator::DIV if i1 == Some(0) && !has_side_effect(&e2) => int_literal(0),
+    // x % 1 ==> 0, 0 % x ==> 0, x % x ==> 0 (modulo by zero is 0 in the critter world)
+    BinaryOperator::MOD if i2 == Some(1) && !has_side_effect(&e1) => int_literal(0),
+    BinaryOperator::MOD if i1 == Some(0) && !has_side_effect(&e2) => int_literal(0),
+    BinaryOperator::MOD if is_repeated_pure_operand(&e1, &e2) => int_literal(0),
+    // x == x, x <= x, x >= x ==> true; x != x, x < x, x > x ==> false
+    BinaryOperator::EQ | BinaryOperator::LE | BinaryOperator::GE
+      if is_repeated_pure_operand(&e1, &e2) =>
+    {
+      bool_literal(true)
+    }
+    BinaryOperator::NE | BinaryOperator::LT | BinaryOperator::GT
+      if is_repeated_pure_operand(&e1, &e2) =>
+    {
+      bool_literal(false)
+    }
+    // true && c ==> c, c && true ==> c, false && c ==> false, c && false ==> false, c && c ==> c
+    BinaryOperator::AND if b1 == Some(true) => e2,
+    BinaryOperator::AND if b2 == Some(true) => e1,
+    BinaryOperator::AND if b1 == Some(false) && !has_side_effect(&e2) => bool_literal(false),
+    BinaryOperator::AND if b2 == Some(false) && !has_side_effect(&e1) => bool_literal(false),
+    BinaryOperator::AND if is_repeated_pure_operand(&e1, &e2) => e1,
+    // false || c ==> c, c || false ==> c, true || c ==> true, c || true ==> true, c || c ==> c
+    BinaryOperator::OR if b1 == Some(false) => e2,
+    BinaryOperator::OR if b2 == Some(false) => e1,
+    BinaryOperator::OR if b1 == Some(true) && !has_side_effect(&e2) => bool_literal(true),
+    BinaryOperator::OR if b2 == Some(true) && !has_side_effect(&e1) => bool_literal(true),
+    BinaryOperator::OR if is_repeated_pure_operand(&e1, &e2) => e1,
+    _ => Box::new(SourceLanguageExpression::BinaryExpression {
+      line_number,
+      operator,
+      e1,
+      e2,
+    }),
+  }
+}
 
 pub fn compile_time_evaluation(
   expression: &SourceLanguageExpression,
@@ -37,149 +227,12 @@ pub fn compile_time_evaluation(
       operator,
       e1,
       e2,
-    } => {
-      let evaluated_e1 = compile_time_evaluation(e1);
-      let evaluated_e2 = compile_time_evaluation(e2);
-      let copied_evaluated_e1 = evaluated_e1.clone();
-      let copied_evaluated_e2 = evaluated_e2.clone();
-      let generic = Box::new(SourceLanguageExpression::BinaryExpression {
-        line_number: *line_number,
-        operator: *operator,
-        e1: evaluated_e1,
-        e2: evaluated_e2,
-      });
-      match (*copied_evaluated_e1, *copied_evaluated_e2) {
-        (
-          SourceLanguageExpression::LiteralExpression {
-            line_number: _,
-            literal: l1,
-          },
-          SourceLanguageExpression::LiteralExpression {
-            line_number: _,
-            literal: l2,
-          },
-        ) => match operator {
-          BinaryOperator::MUL => match (l1, l2) {
-            (LiteralValue::IntLiteral(i1), LiteralValue::IntLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::IntLiteral(i1 * i2),
-              })
-            }
-            _ => generic,
-          },
-          BinaryOperator::DIV => match (l1, l2) {
-            (LiteralValue::IntLiteral(i1), LiteralValue::IntLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::IntLiteral(i1 / i2),
-              })
-            }
-            _ => generic,
-          },
-          BinaryOperator::MOD => match (l1, l2) {
-            (LiteralValue::IntLiteral(i1), LiteralValue::IntLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::IntLiteral(i1 % i2),
-              })
-            }
-            _ => generic,
-          },
-          BinaryOperator::PLUS => match (l1, l2) {
-            (LiteralValue::IntLiteral(i1), LiteralValue::IntLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::IntLiteral(i1 + i2),
-              })
-            }
-            _ => generic,
-          },
-          BinaryOperator::MINUS => match (l1, l2) {
-            (LiteralValue::IntLiteral(i1), LiteralValue::IntLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::IntLiteral(i1 - i2),
-              })
-            }
-            _ => generic,
-          },
-          BinaryOperator::LT => match (l1, l2) {
-            (LiteralValue::IntLiteral(i1), LiteralValue::IntLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::BoolLiteral(i1 < i2),
-              })
-            }
-            _ => generic,
-          },
-          BinaryOperator::LE => match (l1, l2) {
-            (LiteralValue::IntLiteral(i1), LiteralValue::IntLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::BoolLiteral(i1 <= i2),
-              })
-            }
-            _ => generic,
-          },
-          BinaryOperator::GT => match (l1, l2) {
-            (LiteralValue::IntLiteral(i1), LiteralValue::IntLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::BoolLiteral(i1 > i2),
-              })
-            }
-            _ => generic,
-          },
-          BinaryOperator::GE => match (l1, l2) {
-            (LiteralValue::IntLiteral(i1), LiteralValue::IntLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::BoolLiteral(i1 >= i2),
-              })
-            }
-            _ => generic,
-          },
-          BinaryOperator::EQ => match (l1, l2) {
-            (LiteralValue::IntLiteral(i1), LiteralValue::IntLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::BoolLiteral(i1 == i2),
-              })
-            }
-            _ => generic,
-          },
-          BinaryOperator::NE => match (l1, l2) {
-            (LiteralValue::IntLiteral(i1), LiteralValue::IntLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::BoolLiteral(i1 != i2),
-              })
-            }
-            _ => generic,
-          },
-          BinaryOperator::AND => match (l1, l2) {
-            (LiteralValue::BoolLiteral(i1), LiteralValue::BoolLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::BoolLiteral(i1 && i2),
-              })
-            }
-            _ => generic,
-          },
-          BinaryOperator::OR => match (l1, l2) {
-            (LiteralValue::BoolLiteral(i1), LiteralValue::BoolLiteral(i2)) => {
-              Box::new(SourceLanguageExpression::LiteralExpression {
-                line_number: *line_number,
-                literal: LiteralValue::BoolLiteral(i1 || i2),
-              })
-            }
-            _ => generic,
-          },
-        },
-        _ => generic,
-      }
-    }
+    } => simplify_binary_expression(
+      *line_number,
+      *operator,
+      compile_time_evaluation(e1),
+      compile_time_evaluation(e2),
+    ),
     SourceLanguageExpression::IfElseExpression {
       line_number,
       condition,
@@ -200,12 +253,26 @@ pub fn compile_time_evaluation(
 
           literal: LiteralValue::BoolLiteral(false),
         } => evaluated_e2,
-        _ => Box::new(SourceLanguageExpression::IfElseExpression {
-          line_number: *line_number,
-          condition: evaluated_condition,
-          e1: evaluated_e1,
-          e2: evaluated_e2,
-        }),
+        _ => {
+          if !has_side_effect(&evaluated_condition)
+            && are_same_expression(&evaluated_e1, &evaluated_e2)
+          {
+            // if c then e else e ==> e
+            evaluated_e1
+          } else if get_bool_literal(&evaluated_e1) == Some(true)
+            && get_bool_literal(&evaluated_e2) == Some(false)
+          {
+            // if c then true else false ==> c
+            evaluated_condition
+          } else {
+            Box::new(SourceLanguageExpression::IfElseExpression {
+              line_number: *line_number,
+              condition: evaluated_condition,
+              e1: evaluated_e1,
+              e2: evaluated_e2,
+            })
+          }
+        }
       }
     }
     SourceLanguageExpression::AssignmentExpression {
@@ -232,3 +299,123 @@ pub fn compile_time_evaluation(
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::renamer::erase_line_numbers;
+  use crate::test_utilities::checked_expression;
+
+  /// Asserts that `source` simplifies to `expected`, both checked as bodies of the given type.
+  fn assert_simplifies_to(return_type: &str, source: &str, expected: &str) {
+    assert_eq!(
+      erase_line_numbers(&compile_time_evaluation(&checked_expression(
+        return_type,
+        source
+      ))),
+      erase_line_numbers(&checked_expression(return_type, expected)),
+      "{:}",
+      source
+    );
+  }
+
+  #[test]
+  fn identities_and_annihilators_fold() {
+    assert_simplifies_to("int", "x + 0", "x");
+    assert_simplifies_to("int", "1 * (x - 0)", "x");
+    assert_simplifies_to("int", "nearby(1) * 0", "0");
+    assert_simplifies_to("int", "x % 1", "0");
+    assert_simplifies_to("bool", "true && x > 1", "x > 1");
+    assert_simplifies_to("bool", "x > 1 || true", "true");
+  }
+
+  #[test]
+  fn repeated_deterministic_operands_fold() {
+    assert_simplifies_to("int", "x - x", "0");
+    assert_simplifies_to("bool", "nearby(0) == nearby(0)", "true");
+    assert_simplifies_to("bool", "ahead(1) < ahead(1)", "false");
+    assert_simplifies_to("bool", "x > 1 && x > 1", "x > 1");
+  }
+
+  #[test]
+  fn repeated_random_operands_are_separate_draws() {
+    assert_simplifies_to("int", "random(3) - random(3)", "random(3) - random(3)");
+    assert_simplifies_to("bool", "random(3) == random(3)", "random(3) == random(3)");
+    assert_simplifies_to(
+      "bool",
+      "random(2) == 0 && random(2) == 0",
+      "random(2) == 0 && random(2) == 0",
+    );
+    // Dropping a draw that nothing else reads is still fine.
+    assert_simplifies_to("int", "random(3) * 0", "0");
+  }
+
+  /// `{ y = 2; y }` as an operand, which no checked source expression can spell.
+  fn assigning_operand() -> Box<SourceLanguageExpression> {
+    Box::new(SourceLanguageExpression::ChainExpression {
+      line_number: 0,
+      expressions: vec![
+        Box::new(SourceLanguageExpression::AssignmentExpression {
+          line_number: 0,
+          identifier: "y".to_string(),
+          assigned_expression: Box::new(checked_expression("int", "2")),
+        }),
+        Box::new(checked_expression("int", "y")),
+      ],
+    })
+  }
+
+  fn binary(
+    operator: BinaryOperator,
+    e1: Box<SourceLanguageExpression>,
+    e2: Box<SourceLanguageExpression>,
+  ) -> Box<SourceLanguageExpression> {
+    Box::new(SourceLanguageExpression::BinaryExpression {
+      line_number: 0,
+      operator,
+      e1,
+      e2,
+    })
+  }
+
+  #[test]
+  fn operands_with_side_effects_are_kept() {
+    let zero = || Box::new(checked_expression("int", "0"));
+    let one = || Box::new(checked_expression("int", "1"));
+    for expression in [
+      binary(BinaryOperator::MUL, zero(), assigning_operand()),
+      binary(BinaryOperator::MUL, assigning_operand(), zero()),
+      binary(BinaryOperator::DIV, zero(), assigning_operand()),
+      binary(BinaryOperator::MOD, assigning_operand(), one()),
+      binary(BinaryOperator::MOD, zero(), assigning_operand()),
+    ] {
+      assert_eq!(
+        erase_line_numbers(&compile_time_evaluation(&expression)),
+        erase_line_numbers(&expression)
+      );
+    }
+    // Identities keep the other operand, and with it its side effects.
+    assert_eq!(
+      erase_line_numbers(&compile_time_evaluation(&binary(
+        BinaryOperator::PLUS,
+        assigning_operand(),
+        zero()
+      ))),
+      erase_line_numbers(&assigning_operand())
+    );
+  }
+
+  #[test]
+  fn side_effects_and_determinism_of_calls() {
+    let random_sum = checked_expression("int", "random(2) + x");
+    assert!(has_side_effect(&checked_expression("void", "forward()")));
+    assert!(has_side_effect(&assigning_operand()));
+    assert!(!has_side_effect(&random_sum));
+    assert!(!is_deterministic(&random_sum));
+    assert!(is_deterministic(&checked_expression(
+      "int",
+      "nearby(2) + x"
+    )));
+    assert!(!is_deterministic(&checked_expression("void", "forward()")));
+  }
+}
@@ -1,15 +1,23 @@
 use crate::ast::{
   ExpressionStaticType, FullyInlinedProgram, LiteralValue, SourceLanguageExpression,
-  SourceLanguageFunctionDefinition, SourceLanguageProgram,
+  SourceLanguageFunctionDefinition, SourceLanguageMutableGlobalVariableDefinition,
+  SourceLanguageProgram,
 };
+use crate::cse::eliminate_common_subexpressions;
 use crate::evaluator::compile_time_evaluation;
-use crate::renamer::replace_variable_in_expression;
-use crate::transformer::transform_to_if_else_blocks;
+use crate::interpreter::{evaluate_constant_function_calls, DEFAULT_STEP_BUDGET};
+use crate::optimizer::optimize_rules;
+use crate::propagator::{propagate_constant_globals, share_temporary_slots};
+use crate::renamer::{expression_calls_function, replace_variable_in_expression};
+use crate::sequencer::{split_into_phases, LoweringMode, PHASE_VARIABLE};
+use crate::specializer::Specializer;
+use crate::transformer::{transform_to_if_else_blocks, LARGE_RULE_COUNT};
 use std::collections::HashMap;
 
 fn inline_function(
   expression: &SourceLanguageExpression,
   function_to_inline: &SourceLanguageFunctionDefinition,
+  specializer: &mut Specializer,
 ) -> Box<SourceLanguageExpression> {
   match &expression {
     SourceLanguageExpression::LiteralExpression {
@@ -39,21 +47,36 @@ fn inline_function(
           function_name: (*function_name).clone(),
           function_arguments: function_arguments
             .iter()
-            .map(|e| inline_function(e, function_to_inline))
+            .map(|e| inline_function(e, function_to_inline, specializer))
             .collect(),
         })
       } else {
+        let inlined_arguments: Vec<Box<SourceLanguageExpression>> = function_arguments
+          .iter()
+          .map(|e| compile_time_evaluation(&inline_function(e, function_to_inline, specializer)))
+          .collect();
+        let constant_arguments: Vec<Option<LiteralValue>> = inlined_arguments
+          .iter()
+          .map(|e| match &**e {
+            SourceLanguageExpression::LiteralExpression {
+              line_number: _,
+              literal,
+            } => Some(*literal),
+            _ => None,
+          })
+          .collect();
+        let specialized_body = specializer.specialize(function_to_inline, &constant_arguments);
         let mut replacement_map = HashMap::new();
         for (parameter, argument_expression) in function_to_inline
           .function_arguments
           .clone()
           .into_iter()
-          .zip(function_arguments.into_iter())
+          .zip(inlined_arguments.into_iter())
         {
           let (name, _) = parameter;
-          replacement_map.insert(name, (*argument_expression).clone());
+          replacement_map.insert(name, argument_expression);
         }
-        replace_variable_in_expression(&function_to_inline.body, &replacement_map)
+        replace_variable_in_expression(&specialized_body, &replacement_map)
       }
     }
     SourceLanguageExpression::BinaryExpression {
@@ -64,8 +87,8 @@ fn inline_function(
     } => Box::new(SourceLanguageExpression::BinaryExpression {
       line_number: *line_number,
       operator: *operator,
-      e1: inline_function(e1, function_to_inline),
-      e2: inline_function(e2, function_to_inline),
+      e1: inline_function(e1, function_to_inline, specializer),
+      e2: inline_function(e2, function_to_inline, specializer),
     }),
     SourceLanguageExpression::IfElseExpression {
       line_number,
@@ -74,9 +97,9 @@ fn inline_function(
       e2,
     } => Box::new(SourceLanguageExpression::IfElseExpression {
       line_number: *line_number,
-      condition: inline_function(condition, function_to_inline),
-      e1: inline_function(e1, function_to_inline),
-      e2: inline_function(e2, function_to_inline),
+      condition: inline_function(condition, function_to_inline, specializer),
+      e1: inline_function(e1, function_to_inline, specializer),
+      e2: inline_function(e2, function_to_inline, specializer),
     }),
     SourceLanguageExpression::AssignmentExpression {
       line_number,
@@ -85,7 +108,7 @@ fn inline_function(
     } => Box::new(SourceLanguageExpression::AssignmentExpression {
       line_number: *line_number,
       identifier: (*identifier).clone(),
-      assigned_expression: inline_function(assigned_expression, function_to_inline),
+      assigned_expression: inline_function(assigned_expression, function_to_inline, specializer),
     }),
     SourceLanguageExpression::ChainExpression {
       line_number,
@@ -93,7 +116,11 @@ fn inline_function(
     } => {
       let mut replaced_expressions = Vec::new();
       for sub_expression in expressions {
-        replaced_expressions.push(inline_function(sub_expression, function_to_inline));
+        replaced_expressions.push(inline_function(
+          sub_expression,
+          function_to_inline,
+          specializer,
+        ));
       }
       Box::new(SourceLanguageExpression::ChainExpression {
         line_number: *line_number,
@@ -200,22 +227,26 @@ fn function_self_inline(
   depth: usize,
 ) -> SourceLanguageFunctionDefinition {
   let mut body = function.body.clone();
+  let mut specializer = Specializer::new();
   for _ in 0..depth {
-    body = inline_function(&body, function);
+    body = inline_function(&body, function, &mut specializer);
   }
   let default_expression = match function.return_type {
-    ExpressionStaticType::BoolType => SourceLanguageExpression::LiteralExpression {
+    Some(ExpressionStaticType::BoolType) => SourceLanguageExpression::LiteralExpression {
       line_number: 0,
       literal: LiteralValue::BoolLiteral(false),
     },
-    ExpressionStaticType::IntType => SourceLanguageExpression::LiteralExpression {
+    Some(ExpressionStaticType::IntType) => SourceLanguageExpression::LiteralExpression {
       line_number: 0,
       literal: LiteralValue::IntLiteral(0),
     },
-    ExpressionStaticType::VoidType => SourceLanguageExpression::ChainExpression {
+    Some(ExpressionStaticType::VoidType) => SourceLanguageExpression::ChainExpression {
       line_number: 0,
       expressions: Vec::new(),
     },
+    Some(ExpressionStaticType::ErrorType) | None => {
+      panic!("Type checked program should have a valid return type!")
+    }
   };
   body = stub_function_call(&body, &function.identifier, &default_expression);
 
@@ -228,22 +259,117 @@ fn function_self_inline(
   }
 }
 
+/// Formats `count` followed by the singular or plural form of a noun, for notes.
+fn count_of(count: usize, singular: &str, plural: &str) -> String {
+  format!("{:} {:}", count, if count == 1 { singular } else { plural })
+}
+
+/// Inlines every call into `main` and lowers it to rules. Returns the errors instead when a call
+/// with constant arguments cannot be computed at compile time.
 pub fn program_inline(
   program: &SourceLanguageProgram,
   inline_depth: usize,
-) -> Box<FullyInlinedProgram> {
+  lowering_mode: LoweringMode,
+) -> Result<Box<FullyInlinedProgram>, Vec<String>> {
   let functions = &program.function_definitions;
-  let mut main_expression = functions[functions.len() - 1].body.clone();
+  let functions_environment: HashMap<String, &SourceLanguageFunctionDefinition> = functions
+    .iter()
+    .map(|function| (function.identifier.clone(), function))
+    .collect();
+  let mut errors = Vec::new();
+  let mut main_expression = compile_time_evaluation(&evaluate_constant_function_calls(
+    &functions[functions.len() - 1].body,
+    &functions_environment,
+    DEFAULT_STEP_BUDGET,
+    &mut errors,
+  ));
+  if !errors.is_empty() {
+    return Err(errors);
+  }
 
+  let mut specializer = Specializer::new();
   for i in (0..(functions.len() - 1)).rev() {
-    main_expression = compile_time_evaluation(&inline_function(
-      &main_expression,
-      &function_self_inline(&functions[i], inline_depth),
+    if !expression_calls_function(&main_expression, &functions[i].identifier) {
+      continue;
+    }
+    // Calls with constant arguments are computed outright. Only the remaining calls, which depend
+    // on runtime values, are inlined.
+    main_expression = compile_time_evaluation(&evaluate_constant_function_calls(
+      &inline_function(
+        &main_expression,
+        &function_self_inline(&functions[i], inline_depth),
+        &mut specializer,
+      ),
+      &functions_environment,
+      DEFAULT_STEP_BUDGET,
+      &mut errors,
+    ));
+    if !errors.is_empty() {
+      return Err(errors);
+    }
+  }
+
+  let (global_variable_definitions, main_expression) =
+    propagate_constant_globals(&program.global_variable_definitions, &main_expression);
+  let (mut global_variable_definitions, main_expression, shared_temporary_count) =
+    share_temporary_slots(&global_variable_definitions, &main_expression);
+
+  let mut notes = Vec::new();
+  if specializer.removed_node_count > 0 {
+    notes.push(format!(
+      "Specialized {:} into {:}, removing {:}.",
+      count_of(
+        specializer.specialized_call_count,
+        "call site",
+        "call sites"
+      ),
+      count_of(specializer.specialized_body_count(), "body", "bodies"),
+      count_of(
+        specializer.removed_node_count,
+        "expression node",
+        "expression nodes"
+      )
+    ));
+  }
+
+  if shared_temporary_count > 0 {
+    notes.push(format!(
+      "Stored {:} temporaries in the slots of temporaries that are never live at the same time.",
+      shared_temporary_count
+    ));
+  }
+
+  let (main_expression, phase_count) = split_into_phases(&main_expression, lowering_mode);
+  if phase_count > 0 {
+    global_variable_definitions.push(SourceLanguageMutableGlobalVariableDefinition {
+      line_number: 0,
+      identifier: PHASE_VARIABLE.to_string(),
+      assigned_expression: Box::new(SourceLanguageExpression::LiteralExpression {
+        line_number: 0,
+        literal: LiteralValue::IntLiteral(0),
+      }),
+      is_temporary: false,
+    });
+    notes.push(format!(
+      "Split sequential code into {:} phases that run on later passes or turns.",
+      phase_count
     ));
   }
 
-  Box::new(FullyInlinedProgram {
-    global_variable_definitions: program.global_variable_definitions.clone(),
-    if_else_blocks: transform_to_if_else_blocks(&main_expression),
-  })
+  let if_else_blocks = transform_to_if_else_blocks(&main_expression);
+  if if_else_blocks.len() > LARGE_RULE_COUNT {
+    notes.push(format!(
+      "Lowering produced {:} rules, more than {:}. Consider sharing conditions between branches.",
+      if_else_blocks.len(),
+      LARGE_RULE_COUNT
+    ));
+  }
+  let transformed_program = FullyInlinedProgram {
+    global_variable_definitions,
+    if_else_blocks,
+    notes,
+  };
+  Ok(Box::new(eliminate_common_subexpressions(&optimize_rules(
+    &transformed_program,
+  ))))
 }
@@ -0,0 +1,307 @@
+use crate::arithmetic::apply_binary_operator;
+use crate::ast::{
+  BinaryOperator, ExpressionStaticType, LiteralValue, SourceLanguageExpression,
+  SourceLanguageFunctionDefinition,
+};
+use crate::evaluator::compile_time_evaluation;
+use std::collections::HashMap;
+
+/// The number of expressions the interpreter may evaluate for a single call site before it gives
+/// up and leaves the call to the inliner.
+pub const DEFAULT_STEP_BUDGET: usize = 1_000_000;
+
+/// The deepest chain of nested calls the interpreter follows, which keeps runaway recursion from
+/// overflowing the compiler's own stack before the step budget runs out.
+const MAX_CALL_DEPTH: usize = 500;
+
+struct InterpreterBudget {
+  remaining_steps: usize,
+  call_depth: usize,
+  /// Set when the interpreter gave up because it ran out of steps or call depth, rather than
+  /// because the call depends on a runtime value.
+  is_exhausted: bool,
+}
+
+/// Evaluates a pure expression whose free variables are all bound in `environment`.
+///
+/// Returns `None` when the expression depends on a runtime value (a global variable, a sensor or
+/// an action) or when the step budget runs out.
+fn interpret_expression(
+  expression: &SourceLanguageExpression,
+  environment: &HashMap<String, LiteralValue>,
+  functions: &HashMap<String, &SourceLanguageFunctionDefinition>,
+  budget: &mut InterpreterBudget,
+) -> Option<LiteralValue> {
+  if budget.remaining_steps == 0 {
+    budget.is_exhausted = true;
+    return None;
+  }
+  budget.remaining_steps -= 1;
+  match expression {
+    SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal,
+    } => Some(*literal),
+    SourceLanguageExpression::VariableExpression {
+      line_number: _,
+      identifier,
+    } => environment.get(identifier).copied(),
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name,
+      function_arguments,
+    } => {
+      let function = functions.get(function_name)?;
+      let mut argument_values = Vec::new();
+      for argument_expression in function_arguments {
+        argument_values.push(interpret_expression(
+          argument_expression,
+          environment,
+          functions,
+          budget,
+        )?);
+      }
+      interpret_function_call(function, &argument_values, functions, budget)
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator,
+      e1,
+      e2,
+    } => {
+      let v1 = interpret_expression(e1, environment, functions, budget)?;
+      // Short-circuit, so that recursion guarded by `&&` or `||` terminates.
+      match (operator, v1) {
+        (BinaryOperator::AND, LiteralValue::BoolLiteral(false)) => Some(v1),
+        (BinaryOperator::OR, LiteralValue::BoolLiteral(true)) => Some(v1),
+        _ => {
+          let v2 = interpret_expression(e2, environment, functions, budget)?;
+          apply_binary_operator(*operator, v1, v2)
+        }
+      }
+    }
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => match interpret_expression(condition, environment, functions, budget)? {
+      LiteralValue::BoolLiteral(true) => interpret_expression(e1, environment, functions, budget),
+      LiteralValue::BoolLiteral(false) => interpret_expression(e2, environment, functions, budget),
+      LiteralValue::IntLiteral(_) => None,
+    },
+    SourceLanguageExpression::AssignmentExpression { .. }
+    | SourceLanguageExpression::ChainExpression { .. } => None,
+  }
+}
+
+fn interpret_function_call(
+  function: &SourceLanguageFunctionDefinition,
+  argument_values: &[LiteralValue],
+  functions: &HashMap<String, &SourceLanguageFunctionDefinition>,
+  budget: &mut InterpreterBudget,
+) -> Option<LiteralValue> {
+  if function.return_type != Some(ExpressionStaticType::IntType)
+    && function.return_type != Some(ExpressionStaticType::BoolType)
+  {
+    return None;
+  }
+  if budget.call_depth == MAX_CALL_DEPTH {
+    budget.is_exhausted = true;
+    return None;
+  }
+  let mut environment = HashMap::new();
+  for ((name, _), value) in function.function_arguments.iter().zip(argument_values) {
+    environment.insert(name.clone(), *value);
+  }
+  budget.call_depth += 1;
+  let value = interpret_expression(&function.body, &environment, functions, budget);
+  budget.call_depth -= 1;
+  value
+}
+
+/// Replaces every call to a pure int or bool user function whose arguments fold to constants with
+/// the value of the call, computed by running the function at compile time. Calls that depend on
+/// runtime values are left for the inliner. A call that exceeds `step_budget` or the call depth
+/// is reported in `errors`, since inlining it would only stub out its deeper calls with defaults.
+pub fn evaluate_constant_function_calls(
+  expression: &SourceLanguageExpression,
+  functions: &HashMap<String, &SourceLanguageFunctionDefinition>,
+  step_budget: usize,
+  errors: &mut Vec<String>,
+) -> Box<SourceLanguageExpression> {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => Box::new(expression.clone()),
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number,
+      static_type,
+      function_name,
+      function_arguments,
+    } => {
+      let evaluated_arguments: Vec<Box<SourceLanguageExpression>> = function_arguments
+        .iter()
+        .map(|e| {
+          compile_time_evaluation(&evaluate_constant_function_calls(
+            e,
+            functions,
+            step_budget,
+            errors,
+          ))
+        })
+        .collect();
+      let argument_values: Option<Vec<LiteralValue>> = evaluated_arguments
+        .iter()
+        .map(|e| match &**e {
+          SourceLanguageExpression::LiteralExpression {
+            line_number: _,
+            literal,
+          } => Some(*literal),
+          _ => None,
+        })
+        .collect();
+      let mut budget = InterpreterBudget {
+        remaining_steps: step_budget,
+        call_depth: 0,
+        is_exhausted: false,
+      };
+      let value = match (functions.get(function_name), argument_values) {
+        (Some(function), Some(argument_values)) => {
+          interpret_function_call(function, &argument_values, functions, &mut budget)
+        }
+        _ => None,
+      };
+      if budget.is_exhausted {
+        errors.push(format!(
+          "Line {:}: The call to `{:}` with constant arguments does not finish within {:} steps \
+           and {:} nested calls of compile-time evaluation.",
+          line_number, function_name, step_budget, MAX_CALL_DEPTH
+        ));
+      }
+      match value {
+        Some(literal) => Box::new(SourceLanguageExpression::LiteralExpression {
+          line_number: *line_number,
+          literal,
+        }),
+        None => Box::new(SourceLanguageExpression::FunctionCallExpression {
+          line_number: *line_number,
+          static_type: *static_type,
+          function_name: function_name.clone(),
+          function_arguments: evaluated_arguments,
+        }),
+      }
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number,
+      operator,
+      e1,
+      e2,
+    } => Box::new(SourceLanguageExpression::BinaryExpression {
+      line_number: *line_number,
+      operator: *operator,
+      e1: evaluate_constant_function_calls(e1, functions, step_budget, errors),
+      e2: evaluate_constant_function_calls(e2, functions, step_budget, errors),
+    }),
+    SourceLanguageExpression::IfElseExpression {
+      line_number,
+      condition,
+      e1,
+      e2,
+    } => Box::new(SourceLanguageExpression::IfElseExpression {
+      line_number: *line_number,
+      condition: evaluate_constant_function_calls(condition, functions, step_budget, errors),
+      e1: evaluate_constant_function_calls(e1, functions, step_budget, errors),
+      e2: evaluate_constant_function_calls(e2, functions, step_budget, errors),
+    }),
+    SourceLanguageExpression::AssignmentExpression {
+      line_number,
+      identifier,
+      assigned_expression,
+    } => Box::new(SourceLanguageExpression::AssignmentExpression {
+      line_number: *line_number,
+      identifier: identifier.clone(),
+      assigned_expression: evaluate_constant_function_calls(
+        assigned_expression,
+        functions,
+        step_budget,
+        errors,
+      ),
+    }),
+    SourceLanguageExpression::ChainExpression {
+      line_number,
+      expressions,
+    } => Box::new(SourceLanguageExpression::ChainExpression {
+      line_number: *line_number,
+      expressions: expressions
+        .iter()
+        .map(|e| evaluate_constant_function_calls(e, functions, step_budget, errors))
+        .collect(),
+    }),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::renamer::erase_line_numbers;
+  use crate::test_utilities::checked_program;
+
+  const COUNT_DOWN: &str = "var x = 0;\n\
+    fun f(n: int): int = if n == 0 then 0 else f(n - 1) + 1\n";
+
+  fn evaluate_main(source: &str) -> (Box<SourceLanguageExpression>, Vec<String>) {
+    let program = checked_program(source);
+    let functions: HashMap<String, &SourceLanguageFunctionDefinition> = program
+      .function_definitions
+      .iter()
+      .map(|f| (f.identifier.clone(), f))
+      .collect();
+    let main = &program.function_definitions[program.function_definitions.len() - 1];
+    let mut errors = Vec::new();
+    let value =
+      evaluate_constant_function_calls(&main.body, &functions, DEFAULT_STEP_BUDGET, &mut errors);
+    (erase_line_numbers(&value), errors)
+  }
+
+  #[test]
+  fn calls_with_constant_arguments_are_computed() {
+    let (value, errors) = evaluate_main(&format!("{}fun main(): void = serve(f(100))", COUNT_DOWN));
+    assert!(errors.is_empty());
+    assert_eq!(
+      erase_line_numbers(
+        &checked_program("fun main(): void = serve(100)").function_definitions[0].body
+      ),
+      value
+    );
+  }
+
+  #[test]
+  fn abandoned_calls_with_constant_arguments_are_reported() {
+    let (_, errors) = evaluate_main(&format!(
+      "{}fun main(): void = serve(f(100000))",
+      COUNT_DOWN
+    ));
+    assert_eq!(
+      vec![
+        "Line 92: The call to `f` with constant arguments does not finish within 1000000 steps \
+         and 500 nested calls of compile-time evaluation."
+      ],
+      errors
+    );
+  }
+
+  #[test]
+  fn calls_that_depend_on_runtime_values_are_kept() {
+    let (value, errors) = evaluate_main(&format!("{}fun main(): void = serve(f(x))", COUNT_DOWN));
+    assert!(errors.is_empty());
+    assert_eq!(
+      erase_line_numbers(
+        &checked_program(&format!("{}fun main(): void = serve(f(x))", COUNT_DOWN))
+          .function_definitions[1]
+          .body
+      ),
+      value
+    );
+  }
+}
@@ -0,0 +1,326 @@
+use crate::ast::{
+  BinaryOperator, ExpressionStaticType, LiteralValue, SourceLanguageExpression,
+  SourceLanguageFunctionDefinition, SourceLanguageMachineDefinition,
+  SourceLanguageMutableGlobalVariableDefinition, SourceLanguageProgram,
+};
+use crate::renamer::expression_calls_function;
+
+// A machine becomes a void function over an int global that holds the index of its current state,
+// with the initial state as 0. Machines that are never active at the same time share a global. A
+// machine that runs in a single state of a single parent only runs while its parent is in that
+// state, so its siblings in the other states of the parent may take its global. Entering a state
+// resets the machines that run in it, so that they never read what a sibling left behind.
+
+/// The parent machine and the state of it in which a machine runs, if that is the only place where
+/// the machine runs.
+fn find_parent_state(
+  machine_index: usize,
+  machines: &[SourceLanguageMachineDefinition],
+  functions: &[SourceLanguageFunctionDefinition],
+) -> Option<(usize, usize)> {
+  let machine_name = &machines[machine_index].identifier;
+  if functions
+    .iter()
+    .any(|function| expression_calls_function(&function.body, machine_name))
+  {
+    return None;
+  }
+  let mut parent_states = Vec::new();
+  for (parent_index, parent) in machines.iter().enumerate() {
+    for (state_index, state) in parent.states.iter().enumerate() {
+      if state.transitions.iter().any(|transition| {
+        expression_calls_function(&transition.condition, machine_name)
+          || expression_calls_function(&transition.action, machine_name)
+      }) {
+        parent_states.push((parent_index, state_index));
+      }
+    }
+  }
+  if parent_states.len() == 1 {
+    Some(parent_states[0])
+  } else {
+    None
+  }
+}
+
+/// The chain of parent machines and states of a machine, from its parent up.
+fn ancestor_states(
+  machine_index: usize,
+  parent_states: &[Option<(usize, usize)>],
+) -> Vec<(usize, usize)> {
+  let mut ancestors = Vec::new();
+  let mut current = machine_index;
+  while let Some((parent_index, state_index)) = parent_states[current] {
+    ancestors.push((parent_index, state_index));
+    current = parent_index;
+  }
+  ancestors
+}
+
+/// Returns whether two machines run in different states of a common ancestor.
+fn are_exclusive(m1: usize, m2: usize, parent_states: &[Option<(usize, usize)>]) -> bool {
+  let ancestors1 = ancestor_states(m1, parent_states);
+  let ancestors2 = ancestor_states(m2, parent_states);
+  ancestors1
+    .iter()
+    .any(|(p1, s1)| ancestors2.iter().any(|(p2, s2)| p1 == p2 && s1 != s2))
+}
+
+fn slot_variable(slot: usize) -> Box<SourceLanguageExpression> {
+  Box::new(SourceLanguageExpression::VariableExpression {
+    line_number: 0,
+    identifier: format!("_machine{:}", slot),
+  })
+}
+
+fn slot_assignment(
+  line_number: usize,
+  slot: usize,
+  state_index: usize,
+) -> SourceLanguageExpression {
+  SourceLanguageExpression::AssignmentExpression {
+    line_number,
+    identifier: format!("_machine{:}", slot),
+    assigned_expression: Box::new(SourceLanguageExpression::LiteralExpression {
+      line_number,
+      literal: LiteralValue::IntLiteral(state_index as i32),
+    }),
+  }
+}
+
+struct MachineLowering<'a> {
+  machines: &'a [SourceLanguageMachineDefinition],
+  parent_states: Vec<Option<(usize, usize)>>,
+  slots: Vec<usize>,
+}
+
+impl<'a> MachineLowering<'a> {
+  /// Returns the updates that move a machine to a state, and reset the machines that run in it.
+  fn enter_state(
+    &self,
+    line_number: usize,
+    machine_index: usize,
+    state_index: usize,
+  ) -> Vec<SourceLanguageExpression> {
+    let mut updates = vec![slot_assignment(
+      line_number,
+      self.slots[machine_index],
+      state_index,
+    )];
+    for (child_index, parent_state) in self.parent_states.iter().enumerate() {
+      if *parent_state == Some((machine_index, state_index)) {
+        updates.extend(self.enter_state(line_number, child_index, 0));
+      }
+    }
+    updates
+  }
+
+  fn lower_state(&self, machine_index: usize, state_index: usize) -> Box<SourceLanguageExpression> {
+    let machine = &self.machines[machine_index];
+    let state = &machine.states[state_index];
+    let mut lowered: Box<SourceLanguageExpression> =
+      Box::new(SourceLanguageExpression::ChainExpression {
+        line_number: state.line_number,
+        expressions: Vec::new(),
+      });
+    for transition in state.transitions.iter().rev() {
+      let mut expressions = vec![transition.action.clone()];
+      let target_index = transition.target_state.as_ref().and_then(|target_state| {
+        machine
+          .states
+          .iter()
+          .position(|state| state.identifier == *target_state)
+      });
+      if let Some(target_index) = target_index {
+        if target_index != state_index {
+          let updates = self.enter_state(transition.line_number, machine_index, target_index);
+          expressions.extend(updates.into_iter().map(Box::new));
+        }
+      }
+      lowered = Box::new(SourceLanguageExpression::IfElseExpression {
+        line_number: transition.line_number,
+        condition: transition.condition.clone(),
+        e1: Box::new(SourceLanguageExpression::ChainExpression {
+          line_number: transition.line_number,
+          expressions,
+        }),
+        e2: lowered,
+      });
+    }
+    lowered
+  }
+
+  fn lower_machine(&self, machine_index: usize) -> SourceLanguageFunctionDefinition {
+    let machine = &self.machines[machine_index];
+    let mut body = match machine.states.len() {
+      0 => Box::new(SourceLanguageExpression::ChainExpression {
+        line_number: machine.line_number,
+        expressions: Vec::new(),
+      }),
+      state_count => self.lower_state(machine_index, state_count - 1),
+    };
+    // The last state needs no test, since the global always holds a valid state whenever the
+    // machine runs.
+    for state_index in (0..machine.states.len().saturating_sub(1)).rev() {
+      body = Box::new(SourceLanguageExpression::IfElseExpression {
+        line_number: machine.states[state_index].line_number,
+        condition: Box::new(SourceLanguageExpression::BinaryExpression {
+          line_number: machine.states[state_index].line_number,
+          operator: BinaryOperator::EQ,
+          e1: slot_variable(self.slots[machine_index]),
+          e2: Box::new(SourceLanguageExpression::LiteralExpression {
+            line_number: machine.states[state_index].line_number,
+            literal: LiteralValue::IntLiteral(state_index as i32),
+          }),
+        }),
+        e1: self.lower_state(machine_index, state_index),
+        e2: body,
+      });
+    }
+    SourceLanguageFunctionDefinition {
+      line_number: machine.line_number,
+      identifier: machine.identifier.clone(),
+      function_arguments: Vec::new(),
+      return_type: Some(ExpressionStaticType::VoidType),
+      body,
+    }
+  }
+}
+
+/// Lowers the checked machines of a program to the globals that hold their states and to void
+/// functions that take one transition each. The functions come in the order of the machines,
+/// before the other functions.
+pub fn desugar_machines(program: &SourceLanguageProgram) -> SourceLanguageProgram {
+  let machines = &program.machine_definitions;
+  let functions = &program.function_definitions;
+  let parent_states: Vec<Option<(usize, usize)>> = (0..machines.len())
+    .map(|machine_index| find_parent_state(machine_index, machines, functions))
+    .collect();
+
+  // Greedily give each machine the first global that no machine active at the same time uses.
+  let mut slot_members: Vec<Vec<usize>> = Vec::new();
+  let mut slots = Vec::new();
+  for machine_index in 0..machines.len() {
+    let free_slot = slot_members.iter().position(|members| {
+      members
+        .iter()
+        .all(|member| are_exclusive(*member, machine_index, &parent_states))
+    });
+    let slot = match free_slot {
+      Some(slot) => slot,
+      None => {
+        slot_members.push(Vec::new());
+        slot_members.len() - 1
+      }
+    };
+    slot_members[slot].push(machine_index);
+    slots.push(slot);
+  }
+
+  let lowering = MachineLowering {
+    machines,
+    parent_states,
+    slots,
+  };
+  let global_variable_definitions = program
+    .global_variable_definitions
+    .iter()
+    .cloned()
+    .chain(
+      (0..slot_members.len()).map(|slot| SourceLanguageMutableGlobalVariableDefinition {
+        line_number: 0,
+        identifier: format!("_machine{:}", slot),
+        assigned_expression: Box::new(SourceLanguageExpression::LiteralExpression {
+          line_number: 0,
+          literal: LiteralValue::IntLiteral(0),
+        }),
+        is_temporary: false,
+      }),
+    )
+    .collect();
+  let function_definitions = (0..machines.len())
+    .map(|machine_index| lowering.lower_machine(machine_index))
+    .chain(functions.iter().cloned())
+    .collect();
+  SourceLanguageProgram {
+    global_variable_definitions,
+    machine_definitions: Vec::new(),
+    function_definitions,
+    species_declaration: program.species_declaration.clone(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::test_utilities::checked_program;
+
+  fn machine_slots(source: &str) -> Vec<String> {
+    checked_program(source)
+      .global_variable_definitions
+      .into_iter()
+      .map(|global_variable| global_variable.identifier)
+      .filter(|identifier| identifier.starts_with("_machine"))
+      .collect()
+  }
+
+  const PARENT: &str = "
+    machine Parent {
+      state Left {
+        on nearby(0) > 0 => wait(), goto Right;
+        on true => First()
+      }
+      state Right {
+        on nearby(0) > 0 => wait(), goto Left;
+        on true => Second()
+      }
+    }
+    fun main(): void = Parent()";
+
+  const CHILDREN: &str = "
+    machine First {
+      state A {
+        on true => left(), goto B
+      }
+      state B {
+        on true => right(), goto A
+      }
+    }
+    machine Second {
+      state C {
+        on true => forward(), goto D
+      }
+      state D {
+        on true => backward(), goto C
+      }
+    }";
+
+  #[test]
+  fn siblings_in_different_states_share_a_slot() {
+    let slots = machine_slots(&format!("{:}{:}", CHILDREN, PARENT));
+    assert_eq!(slots, vec!["_machine0", "_machine1"]);
+  }
+
+  #[test]
+  fn siblings_in_the_same_state_get_slots_of_their_own() {
+    let parent = PARENT.replace("on true => Second()", "on true => First()");
+    let parent = parent.replace(
+      "on nearby(0) > 0 => wait(), goto Right;",
+      "on nearby(0) > 0 => wait(), goto Right;\n        on nearby(1) > 0 => Second();",
+    );
+    let slots = machine_slots(&format!("{:}{:}", CHILDREN, parent));
+    assert_eq!(slots, vec!["_machine0", "_machine1", "_machine2"]);
+  }
+
+  #[test]
+  fn machines_called_from_functions_get_slots_of_their_own() {
+    let program = format!(
+      "{:}{:}",
+      CHILDREN,
+      PARENT.replace(
+        "fun main(): void = Parent()",
+        "fun main(): void = ({ Second(); Parent() })"
+      )
+    );
+    assert_eq!(machine_slots(&program).len(), 3);
+  }
+}
@@ -1,17 +1,86 @@
+mod arithmetic;
 mod ast;
 #[rustfmt::skip]
 mod pl;
 mod checker;
+mod chooser;
 mod compiler;
+mod cse;
+mod decision_diagram;
 mod evaluator;
 mod inliner;
+mod interpreter;
+mod machine;
+mod minimizer;
+mod optimizer;
+mod propagator;
 mod renamer;
 mod runtime;
+mod sequencer;
+mod solver;
+mod specializer;
+#[cfg(test)]
+mod test_utilities;
 mod transformer;
 
 use std::io::{self, Read};
 
+/// Reads `--init=flag|none|auto`, `--flag-slot=N` and `--base-slot=N` on top of the default layout,
+/// and rejects every argument that is not one of these or `--update-only-passes`.
+fn parse_memory_layout(arguments: &[String]) -> Result<compiler::MemoryLayout, String> {
+  let mut memory_layout = compiler::MemoryLayout::default();
+  for argument in arguments {
+    if let Some(strategy) = argument.strip_prefix("--init=") {
+      memory_layout.initialization_strategy = match strategy {
+        "flag" => compiler::InitializationStrategy::FlagRule,
+        "none" => compiler::InitializationStrategy::None,
+        "auto" => compiler::InitializationStrategy::Auto,
+        _ => {
+          return Err(format!(
+            "Unknown initialization strategy `{:}`. Expected `flag`, `none` or `auto`.",
+            strategy
+          ))
+        }
+      };
+    } else if let Some(slot) = argument.strip_prefix("--flag-slot=") {
+      memory_layout.flag_slot = slot
+        .parse()
+        .map_err(|_| format!("Invalid flag slot `{:}`.", slot))?;
+    } else if let Some(slot) = argument.strip_prefix("--base-slot=") {
+      memory_layout.base_slot = slot
+        .parse()
+        .map_err(|_| format!("Invalid base slot `{:}`.", slot))?;
+    } else if argument != "--update-only-passes" {
+      return Err(format!(
+        "Unknown argument `{:}`. Expected `--update-only-passes`, `--init=`, `--flag-slot=` or \
+         `--base-slot=`.",
+        argument
+      ));
+    }
+  }
+  Ok(memory_layout)
+}
+
 fn main() {
+  let arguments: Vec<String> = std::env::args().collect();
+  // `--update-only-passes` reads the values that a chain computes on later passes of the turn,
+  // instead of substituting them into its conditions.
+  let lowering_mode = if arguments
+    .iter()
+    .any(|argument| argument == "--update-only-passes")
+  {
+    sequencer::LoweringMode::UpdateOnlyPasses
+  } else {
+    sequencer::LoweringMode::Substitute
+  };
+  let memory_layout = match parse_memory_layout(&arguments[1..]) {
+    Ok(memory_layout) => memory_layout,
+    Err(error) => {
+      println!("Errors:");
+      println!("{:}", error);
+      return;
+    }
+  };
   let mut program_buffer = String::new();
   match io::stdin().read_to_string(&mut program_buffer) {
     Ok(_) => (),
@@ -19,7 +88,20 @@ fn main() {
   }
   match checker::get_type_checked_program(runtime::get_critter_world_runtime(), program_buffer) {
     Ok(program) => {
-      println!("{:}", compiler::compile_to_critter_lang(&program, 20));
+      match compiler::compile_to_critter_lang(&program, 20, lowering_mode, memory_layout) {
+        Ok(compiled_program) => {
+          for note in compiled_program.notes {
+            eprintln!("Note: {:}", note);
+          }
+          println!("{:}", compiled_program.code);
+        }
+        Err(errors) => {
+          println!("Errors:");
+          for e in errors {
+            println!("{:}", e);
+          }
+        }
+      }
     }
     Err(errors) => {
       println!("Errors:");
@@ -29,3 +111,42 @@ fn main() {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn arguments(arguments: &[&str]) -> Vec<String> {
+    arguments
+      .iter()
+      .map(|argument| argument.to_string())
+      .collect()
+  }
+
+  #[test]
+  fn parse_memory_layout_reads_known_flags() {
+    let memory_layout = parse_memory_layout(&arguments(&[
+      "--update-only-passes",
+      "--init=auto",
+      "--flag-slot=20",
+      "--base-slot=10",
+    ]))
+    .unwrap();
+    assert_eq!(
+      memory_layout,
+      compiler::MemoryLayout {
+        initialization_strategy: compiler::InitializationStrategy::Auto,
+        flag_slot: 20,
+        base_slot: 10,
+      }
+    );
+  }
+
+  #[test]
+  fn parse_memory_layout_rejects_unknown_flags() {
+    let error = parse_memory_layout(&arguments(&["--flag-slto=9"])).unwrap_err();
+    assert!(error.starts_with("Unknown argument `--flag-slto=9`."));
+    assert!(parse_memory_layout(&arguments(&["--init=sometimes"])).is_err());
+    assert!(parse_memory_layout(&arguments(&["--base-slot=x"])).is_err());
+  }
+}
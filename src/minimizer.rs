@@ -0,0 +1,580 @@
+use crate::ast::{BinaryOperator, LiteralValue, SourceLanguageExpression};
+use crate::evaluator::{has_side_effect, is_deterministic};
+use crate::renamer::{count_expression_nodes, erase_line_numbers};
+use crate::solver::may_hold_together;
+
+/// The most distinct comparisons a condition may contain for Quine–McCluskey minimization, which
+/// enumerates every assignment of them.
+const MAX_MINIMIZED_ATOM_COUNT: usize = 6;
+
+/// A condition in which every comparison is replaced by a literal: the index of a canonical atom
+/// together with whether the atom holds (`true`) or fails (`false`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BooleanTree {
+  Constant(bool),
+  Literal(usize, bool),
+  And(Vec<BooleanTree>),
+  Or(Vec<BooleanTree>),
+}
+
+struct AtomTable {
+  atoms: Vec<SourceLanguageExpression>,
+  /// Whether every atom is a comparison, which can be negated by flipping its operator.
+  all_negatable: bool,
+}
+
+impl AtomTable {
+  fn literal(&mut self, atom: SourceLanguageExpression, polarity: bool) -> BooleanTree {
+    let atom = *erase_line_numbers(&atom);
+    let index = match self.atoms.iter().position(|a| *a == atom) {
+      Some(index) => index,
+      None => {
+        self.atoms.push(atom);
+        self.atoms.len() - 1
+      }
+    };
+    BooleanTree::Literal(index, polarity)
+  }
+}
+
+/// Canonicalizes comparisons to `<`, `<=` or `=`, so that a comparison and its negation share an
+/// atom: `a >= b` is the negation of `a < b`, `a > b` of `a <= b` and `a != b` of `a = b`.
+fn to_boolean_tree(expression: &SourceLanguageExpression, table: &mut AtomTable) -> BooleanTree {
+  match expression {
+    SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal: LiteralValue::BoolLiteral(b),
+    } => BooleanTree::Constant(*b),
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: BinaryOperator::AND,
+      e1,
+      e2,
+    } => BooleanTree::And(vec![to_boolean_tree(e1, table), to_boolean_tree(e2, table)]),
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: BinaryOperator::OR,
+      e1,
+      e2,
+    } => BooleanTree::Or(vec![to_boolean_tree(e1, table), to_boolean_tree(e2, table)]),
+    SourceLanguageExpression::BinaryExpression {
+      line_number,
+      operator,
+      e1,
+      e2,
+    } if matches!(
+      operator,
+      BinaryOperator::LT
+        | BinaryOperator::LE
+        | BinaryOperator::GT
+        | BinaryOperator::GE
+        | BinaryOperator::EQ
+        | BinaryOperator::NE
+    ) =>
+    {
+      let (canonical_operator, polarity) = match operator {
+        BinaryOperator::GE => (BinaryOperator::LT, false),
+        BinaryOperator::GT => (BinaryOperator::LE, false),
+        BinaryOperator::NE => (BinaryOperator::EQ, false),
+        _ => (*operator, true),
+      };
+      table.literal(
+        SourceLanguageExpression::BinaryExpression {
+          line_number: *line_number,
+          operator: canonical_operator,
+          e1: e1.clone(),
+          e2: e2.clone(),
+        },
+        polarity,
+      )
+    }
+    _ => {
+      table.all_negatable = false;
+      table.literal(expression.clone(), true)
+    }
+  }
+}
+
+/// Flattens nested and/or trees and drops constant operands. When `merge_repeated` is set, it also
+/// drops repeated operands and folds an operand together with its own negation, which is only
+/// valid when every atom gives the same answer each time it is evaluated.
+fn flatten(tree: BooleanTree, merge_repeated: bool) -> BooleanTree {
+  let (is_and, operands) = match tree {
+    BooleanTree::And(operands) => (true, operands),
+    BooleanTree::Or(operands) => (false, operands),
+    _ => return tree,
+  };
+  let mut flattened_operands: Vec<BooleanTree> = Vec::new();
+  for operand in operands {
+    let nested_operands = match flatten(operand, merge_repeated) {
+      BooleanTree::And(nested_operands) if is_and => nested_operands,
+      BooleanTree::Or(nested_operands) if !is_and => nested_operands,
+      operand => vec![operand],
+    };
+    for nested_operand in nested_operands {
+      match nested_operand {
+        // The neutral element of the operator is dropped.
+        BooleanTree::Constant(b) if b == is_and => {}
+        // The absorbing element of the operator absorbs everything.
+        BooleanTree::Constant(_) => return BooleanTree::Constant(!is_and),
+        BooleanTree::Literal(index, polarity)
+          if merge_repeated
+            && flattened_operands.contains(&BooleanTree::Literal(index, !polarity)) =>
+        {
+          return BooleanTree::Constant(!is_and)
+        }
+        nested_operand => {
+          if !merge_repeated || !flattened_operands.contains(&nested_operand) {
+            flattened_operands.push(nested_operand);
+          }
+        }
+      }
+    }
+  }
+  match flattened_operands.len() {
+    0 => BooleanTree::Constant(is_and),
+    1 => flattened_operands.pop().unwrap(),
+    _ if is_and => BooleanTree::And(flattened_operands),
+    _ => BooleanTree::Or(flattened_operands),
+  }
+}
+
+fn evaluate_boolean_tree(tree: &BooleanTree, assignment: usize) -> bool {
+  match tree {
+    BooleanTree::Constant(b) => *b,
+    BooleanTree::Literal(index, polarity) => ((assignment >> index) & 1 == 1) == *polarity,
+    BooleanTree::And(operands) => operands
+      .iter()
+      .all(|operand| evaluate_boolean_tree(operand, assignment)),
+    BooleanTree::Or(operands) => operands
+      .iter()
+      .any(|operand| evaluate_boolean_tree(operand, assignment)),
+  }
+}
+
+/// A product term of Quine–McCluskey: the atoms selected by `mask` must take the values in `bits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Implicant {
+  bits: usize,
+  mask: usize,
+}
+
+impl Implicant {
+  fn covers(&self, assignment: usize) -> bool {
+    assignment & self.mask == self.bits
+  }
+}
+
+fn find_prime_implicants(
+  cares: &[usize],
+  dont_cares: &[usize],
+  atom_count: usize,
+) -> Vec<Implicant> {
+  let full_mask = (1 << atom_count) - 1;
+  let mut current: Vec<Implicant> = cares
+    .iter()
+    .chain(dont_cares.iter())
+    .map(|assignment| Implicant {
+      bits: *assignment,
+      mask: full_mask,
+    })
+    .collect();
+  let mut prime_implicants = Vec::new();
+  while !current.is_empty() {
+    let mut combined = vec![false; current.len()];
+    let mut next: Vec<Implicant> = Vec::new();
+    for i in 0..current.len() {
+      for j in (i + 1)..current.len() {
+        let difference = current[i].bits ^ current[j].bits;
+        if current[i].mask == current[j].mask && difference.count_ones() == 1 {
+          combined[i] = true;
+          combined[j] = true;
+          let merged = Implicant {
+            bits: current[i].bits & !difference,
+            mask: current[i].mask & !difference,
+          };
+          if !next.contains(&merged) {
+            next.push(merged);
+          }
+        }
+      }
+    }
+    for (implicant, was_combined) in current.iter().zip(combined) {
+      if !was_combined && !prime_implicants.contains(implicant) {
+        prime_implicants.push(*implicant);
+      }
+    }
+    current = next;
+  }
+  prime_implicants
+}
+
+/// Picks the essential prime implicants, then greedily the ones covering the most remaining
+/// assignments, preferring fewer literals.
+fn select_cover(prime_implicants: &[Implicant], cares: &[usize]) -> Vec<Implicant> {
+  let mut cover: Vec<Implicant> = Vec::new();
+  for assignment in cares {
+    let covering: Vec<&Implicant> = prime_implicants
+      .iter()
+      .filter(|implicant| implicant.covers(*assignment))
+      .collect();
+    if covering.len() == 1 && !cover.contains(covering[0]) {
+      cover.push(*covering[0]);
+    }
+  }
+  loop {
+    let uncovered: Vec<usize> = cares
+      .iter()
+      .copied()
+      .filter(|assignment| !cover.iter().any(|implicant| implicant.covers(*assignment)))
+      .collect();
+    if uncovered.is_empty() {
+      return cover;
+    }
+    let best = prime_implicants
+      .iter()
+      .max_by_key(|implicant| {
+        let covered_count = uncovered
+          .iter()
+          .filter(|assignment| implicant.covers(**assignment))
+          .count();
+        (
+          covered_count,
+          usize::MAX - implicant.mask.count_ones() as usize,
+        )
+      })
+      .unwrap();
+    cover.push(*best);
+  }
+}
+
+/// Builds a sum of products, pulling out the literal shared by the most products until no literal
+/// is shared: `(a and b) or (a and c)` becomes `a and (b or c)`.
+fn factor_products(products: Vec<Vec<BooleanTree>>) -> BooleanTree {
+  if products.iter().any(|product| product.is_empty()) {
+    return BooleanTree::Constant(true);
+  }
+  let mut best_literal: Option<(usize, &BooleanTree)> = None;
+  for product in &products {
+    for literal in product {
+      let count = products.iter().filter(|p| p.contains(literal)).count();
+      let is_more_shared = match best_literal {
+        Some((best_count, _)) => count > best_count,
+        None => true,
+      };
+      if count >= 2 && is_more_shared {
+        best_literal = Some((count, literal));
+      }
+    }
+  }
+  let shared_literal = match best_literal {
+    Some((_, literal)) => literal.clone(),
+    None => {
+      return flatten(
+        BooleanTree::Or(products.into_iter().map(BooleanTree::And).collect()),
+        true,
+      )
+    }
+  };
+  let (with_literal, without_literal): (Vec<Vec<BooleanTree>>, Vec<Vec<BooleanTree>>) = products
+    .into_iter()
+    .partition(|p| p.contains(&shared_literal));
+  let factored = BooleanTree::And(vec![
+    shared_literal.clone(),
+    factor_products(
+      with_literal
+        .into_iter()
+        .map(|p| p.into_iter().filter(|l| *l != shared_literal).collect())
+        .collect(),
+    ),
+  ]);
+  if without_literal.is_empty() {
+    flatten(factored, true)
+  } else {
+    flatten(
+      BooleanTree::Or(vec![factored, factor_products(without_literal)]),
+      true,
+    )
+  }
+}
+
+/// The most products a condition may expand to before factoring.
+const MAX_EXPANDED_PRODUCT_COUNT: usize = 64;
+
+/// Expands a flattened tree into a sum of products by distributing `and` over `or`. Returns `None`
+/// when the expansion would exceed `MAX_EXPANDED_PRODUCT_COUNT` products.
+fn expand_to_products(tree: &BooleanTree) -> Option<Vec<Vec<BooleanTree>>> {
+  match tree {
+    BooleanTree::Constant(true) => Some(vec![Vec::new()]),
+    BooleanTree::Constant(false) => Some(Vec::new()),
+    BooleanTree::Literal(_, _) => Some(vec![vec![tree.clone()]]),
+    BooleanTree::Or(operands) => {
+      let mut products = Vec::new();
+      for operand in operands {
+        products.append(&mut expand_to_products(operand)?);
+        if products.len() > MAX_EXPANDED_PRODUCT_COUNT {
+          return None;
+        }
+      }
+      Some(products)
+    }
+    BooleanTree::And(operands) => {
+      let mut products: Vec<Vec<BooleanTree>> = vec![Vec::new()];
+      for operand in operands {
+        let operand_products = expand_to_products(operand)?;
+        if products.len() * operand_products.len() > MAX_EXPANDED_PRODUCT_COUNT {
+          return None;
+        }
+        let mut next_products = Vec::new();
+        for product in &products {
+          for operand_product in &operand_products {
+            let mut next_product = product.clone();
+            for literal in operand_product {
+              if !next_product.contains(literal) {
+                next_product.push(literal.clone());
+              }
+            }
+            next_products.push(next_product);
+          }
+        }
+        products = next_products;
+      }
+      Some(products)
+    }
+  }
+}
+
+/// Runs Quine–McCluskey over the atoms of `tree`. Assignments that contradict themselves, such as
+/// `x < 3` together with `x > 5`, can never occur and are treated as don't-cares.
+fn minimize_boolean_tree(tree: &BooleanTree, table: &AtomTable) -> BooleanTree {
+  let atom_count = table.atoms.len();
+  let mut cares = Vec::new();
+  let mut dont_cares = Vec::new();
+  for assignment in 0..(1usize << atom_count) {
+    let literals: Vec<(&SourceLanguageExpression, bool)> = table
+      .atoms
+      .iter()
+      .enumerate()
+      .map(|(index, atom)| (atom, (assignment >> index) & 1 == 1))
+      .collect();
+    if !may_hold_together(&literals) {
+      dont_cares.push(assignment);
+    } else if evaluate_boolean_tree(tree, assignment) {
+      cares.push(assignment);
+    }
+  }
+  if cares.is_empty() {
+    return BooleanTree::Constant(false);
+  }
+  let prime_implicants = find_prime_implicants(&cares, &dont_cares, atom_count);
+  let products = select_cover(&prime_implicants, &cares)
+    .iter()
+    .map(|implicant| {
+      (0..atom_count)
+        .filter(|index| (implicant.mask >> index) & 1 == 1)
+        .map(|index| BooleanTree::Literal(index, (implicant.bits >> index) & 1 == 1))
+        .collect()
+    })
+    .collect();
+  factor_products(products)
+}
+
+fn negate_comparison_operator(operator: BinaryOperator) -> BinaryOperator {
+  match operator {
+    BinaryOperator::LT => BinaryOperator::GE,
+    BinaryOperator::LE => BinaryOperator::GT,
+    BinaryOperator::EQ => BinaryOperator::NE,
+    _ => operator,
+  }
+}
+
+fn to_expression(tree: &BooleanTree, table: &AtomTable) -> Box<SourceLanguageExpression> {
+  match tree {
+    BooleanTree::Constant(b) => Box::new(SourceLanguageExpression::LiteralExpression {
+      line_number: 0,
+      literal: LiteralValue::BoolLiteral(*b),
+    }),
+    BooleanTree::Literal(index, true) => Box::new(table.atoms[*index].clone()),
+    BooleanTree::Literal(index, false) => match &table.atoms[*index] {
+      SourceLanguageExpression::BinaryExpression {
+        line_number,
+        operator,
+        e1,
+        e2,
+      } => Box::new(SourceLanguageExpression::BinaryExpression {
+        line_number: *line_number,
+        operator: negate_comparison_operator(*operator),
+        e1: e1.clone(),
+        e2: e2.clone(),
+      }),
+      _ => panic!("Only comparisons can be negated."),
+    },
+    BooleanTree::And(operands) | BooleanTree::Or(operands) => {
+      let operator = match tree {
+        BooleanTree::And(_) => BinaryOperator::AND,
+        _ => BinaryOperator::OR,
+      };
+      // Nest to the right, like the conditions built by the transformer.
+      let mut operand_expressions = operands.iter().rev().map(|o| to_expression(o, table));
+      let mut expression = operand_expressions.next().unwrap();
+      for operand_expression in operand_expressions {
+        expression = Box::new(SourceLanguageExpression::BinaryExpression {
+          line_number: 0,
+          operator,
+          e1: operand_expression,
+          e2: expression,
+        });
+      }
+      expression
+    }
+  }
+}
+
+/// Returns the smallest equivalent form of a rule condition that the minimizer finds.
+pub fn minimize_condition(condition: &SourceLanguageExpression) -> Box<SourceLanguageExpression> {
+  let mut table = AtomTable {
+    atoms: Vec::new(),
+    all_negatable: true,
+  };
+  // Every occurrence of a nondeterministic comparison is evaluated separately, so two occurrences
+  // may not be merged into one.
+  let is_pure = !has_side_effect(condition) && is_deterministic(condition);
+  let tree = flatten(to_boolean_tree(condition, &mut table), is_pure);
+  let mut best = to_expression(&tree, &table);
+  if is_pure && table.all_negatable && table.atoms.len() <= MAX_MINIMIZED_ATOM_COUNT {
+    let minimized = to_expression(&minimize_boolean_tree(&tree, &table), &table);
+    if count_expression_nodes(&minimized) < count_expression_nodes(&best) {
+      best = minimized;
+    }
+  } else if let (true, Some(products)) = (is_pure, expand_to_products(&tree)) {
+    // Too many atoms to enumerate, but shared literals can still be factored out.
+    let factored = to_expression(&factor_products(products), &table);
+    if count_expression_nodes(&factored) < count_expression_nodes(&best) {
+      best = factored;
+    }
+  }
+  if count_expression_nodes(&best) < count_expression_nodes(condition) {
+    best
+  } else {
+    Box::new(condition.clone())
+  }
+}
+
+/// Splits a condition into the operands of its top-level `and` chain.
+pub fn split_conjuncts(condition: &SourceLanguageExpression) -> Vec<SourceLanguageExpression> {
+  match condition {
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: BinaryOperator::AND,
+      e1,
+      e2,
+    } => {
+      let mut conjuncts = split_conjuncts(e1);
+      conjuncts.append(&mut split_conjuncts(e2));
+      conjuncts
+    }
+    _ => vec![condition.clone()],
+  }
+}
+
+/// Joins conditions into a right-nested `and` chain, or `true` when there are none.
+pub fn join_conjuncts(conjuncts: &[SourceLanguageExpression]) -> SourceLanguageExpression {
+  let mut iterator = conjuncts.iter().rev();
+  let mut condition = match iterator.next() {
+    Some(last) => last.clone(),
+    None => {
+      return SourceLanguageExpression::LiteralExpression {
+        line_number: 0,
+        literal: LiteralValue::BoolLiteral(true),
+      }
+    }
+  };
+  for conjunct in iterator {
+    condition = SourceLanguageExpression::BinaryExpression {
+      line_number: 0,
+      operator: BinaryOperator::AND,
+      e1: Box::new(conjunct.clone()),
+      e2: Box::new(condition),
+    };
+  }
+  condition
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utilities::checked_condition;
+
+  fn minimized(source: &str) -> SourceLanguageExpression {
+    *erase_line_numbers(&minimize_condition(&checked_condition(source)))
+  }
+
+  fn expected(source: &str) -> SourceLanguageExpression {
+    *erase_line_numbers(&checked_condition(source))
+  }
+
+  #[test]
+  fn quine_mccluskey_merges_adjacent_products() {
+    assert_eq!(
+      minimized("(x < 3 && y < 3) || (x < 3 && y >= 3)"),
+      expected("x < 3")
+    );
+    assert_eq!(
+      minimized("(x < 1 && y < 1) || (x >= 1 && z < 1) || (y < 1 && z < 1)"),
+      expected("(x < 1 && y < 1) || (x >= 1 && z < 1)")
+    );
+  }
+
+  #[test]
+  fn contradictory_assignments_are_dont_cares() {
+    // `x < 3` without `x < 5` can never occur, so it may be covered or not.
+    assert_eq!(minimized("x < 3 || (x >= 3 && x < 5)"), expected("x < 5"));
+  }
+
+  #[test]
+  fn factor_products_pulls_out_shared_literals() {
+    let (a, b, c) = (
+      BooleanTree::Literal(0, true),
+      BooleanTree::Literal(1, true),
+      BooleanTree::Literal(2, false),
+    );
+    assert_eq!(
+      factor_products(vec![vec![a.clone(), b.clone()], vec![a.clone(), c.clone()]]),
+      BooleanTree::And(vec![a.clone(), BooleanTree::Or(vec![b.clone(), c.clone()])])
+    );
+    assert_eq!(
+      factor_products(vec![vec![a.clone(), b.clone()], vec![c.clone()]]),
+      BooleanTree::Or(vec![BooleanTree::And(vec![a.clone(), b]), c])
+    );
+    assert_eq!(
+      factor_products(vec![vec![a.clone()], Vec::new()]),
+      BooleanTree::Constant(true)
+    );
+  }
+
+  #[test]
+  fn conditions_with_too_many_atoms_are_only_factored() {
+    let consensus = "((x < 1 && y < 1) || (x >= 1 && z < 1) || (y < 1 && z < 1))";
+    let extra_atoms = ["x + y < 1", "x - y < 1", "x * y < 1", "x + z < 1"];
+    // Three atoms of the consensus and three extra ones are still enumerated, which drops the
+    // redundant product.
+    let small = format!("{:} && {:}", extra_atoms[..3].join(" && "), consensus);
+    assert_eq!(
+      minimized(&small),
+      expected(
+        "x + y < 1 && (x - y < 1 && (x * y < 1 && ((x < 1 && y < 1) || (x >= 1 && z < 1))))"
+      )
+    );
+    // One more atom goes past `MAX_MINIMIZED_ATOM_COUNT`, so the redundant product stays, and
+    // only the literal it shares is factored out.
+    let large = format!("{:} && {:}", extra_atoms.join(" && "), consensus);
+    assert_eq!(extra_atoms.len() + 3, MAX_MINIMIZED_ATOM_COUNT + 1);
+    assert_eq!(
+      minimized(&large),
+      expected(
+        "x + y < 1 && (x - y < 1 && (x * y < 1 && (x + z < 1 && \
+         ((y < 1 && (x < 1 || z < 1)) || (x >= 1 && z < 1)))))"
+      )
+    );
+  }
+}
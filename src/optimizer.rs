@@ -0,0 +1,403 @@
+use crate::ast::{BinaryOperator, FullyInlinedProgram, IfElseBlock, SourceLanguageExpression};
+use crate::minimizer::{join_conjuncts, minimize_condition, split_conjuncts};
+use crate::propagator::collect_read_variables;
+use crate::renamer::{count_expression_nodes, erase_line_numbers};
+use crate::solver::may_hold_together;
+use std::collections::HashSet;
+
+/// Removes every rule that can never fire. Critter tries rules from top to bottom and fires the
+/// first one whose condition holds, so a rule is reachable only if its condition may hold while
+/// the conditions of all the rules before it fail.
+pub fn eliminate_unreachable_rules(program: &FullyInlinedProgram) -> FullyInlinedProgram {
+  let mut reachable_if_else_blocks: Vec<IfElseBlock> = Vec::new();
+  for if_else_block in &program.if_else_blocks {
+    let mut conditions: Vec<(&SourceLanguageExpression, bool)> = reachable_if_else_blocks
+      .iter()
+      .map(|earlier_block| (&earlier_block.condition, false))
+      .collect();
+    conditions.push((&if_else_block.condition, true));
+    if may_hold_together(&conditions) {
+      reachable_if_else_blocks.push(if_else_block.clone());
+    }
+  }
+
+  let removed_rule_count = program.if_else_blocks.len() - reachable_if_else_blocks.len();
+  let mut notes = program.notes.clone();
+  if removed_rule_count > 0 {
+    notes.push(format!(
+      "Removed {:} rules that can never fire.",
+      removed_rule_count
+    ));
+  }
+  FullyInlinedProgram {
+    global_variable_definitions: program.global_variable_definitions.clone(),
+    if_else_blocks: reachable_if_else_blocks,
+    notes,
+  }
+}
+
+/// Minimizes every rule condition on its own, then drops each conjunct that the failure of the
+/// earlier rules, together with the remaining conjuncts, already implies. Such a conjunct holds
+/// whenever the rule is tried at all.
+pub fn minimize_rule_conditions(program: &FullyInlinedProgram) -> FullyInlinedProgram {
+  let mut minimized_if_else_blocks: Vec<IfElseBlock> = Vec::new();
+  for if_else_block in &program.if_else_blocks {
+    let minimized_condition = minimize_condition(&if_else_block.condition);
+    let mut conjuncts = split_conjuncts(&minimized_condition);
+    let conjunct_count = conjuncts.len();
+    let mut index = 0;
+    while index < conjuncts.len() {
+      let mut conditions: Vec<(&SourceLanguageExpression, bool)> = minimized_if_else_blocks
+        .iter()
+        .map(|earlier_block| (&earlier_block.condition, false))
+        .collect();
+      for (other_index, other_conjunct) in conjuncts.iter().enumerate() {
+        if other_index != index {
+          conditions.push((other_conjunct, true));
+        }
+      }
+      conditions.push((&conjuncts[index], false));
+      if may_hold_together(&conditions) {
+        index += 1;
+      } else {
+        conjuncts.remove(index);
+      }
+    }
+    minimized_if_else_blocks.push(IfElseBlock {
+      condition: if conjuncts.len() < conjunct_count {
+        join_conjuncts(&conjuncts)
+      } else {
+        *minimized_condition
+      },
+      action: if_else_block.action.clone(),
+    });
+  }
+
+  let count_condition_nodes = |if_else_blocks: &[IfElseBlock]| -> usize {
+    if_else_blocks
+      .iter()
+      .map(|if_else_block| count_expression_nodes(&if_else_block.condition))
+      .sum()
+  };
+  let original_node_count = count_condition_nodes(&program.if_else_blocks);
+  let minimized_node_count = count_condition_nodes(&minimized_if_else_blocks);
+  let mut notes = program.notes.clone();
+  if minimized_node_count < original_node_count {
+    notes.push(format!(
+      "Minimized rule conditions from {:} to {:} expression nodes.",
+      original_node_count, minimized_node_count
+    ));
+  }
+  FullyInlinedProgram {
+    global_variable_definitions: program.global_variable_definitions.clone(),
+    if_else_blocks: minimized_if_else_blocks,
+    notes,
+  }
+}
+
+/// Returns whether `condition` can never hold together with the condition of any rule in
+/// `range`, in the states where that rule is tried. Moving a rule with `condition` across those
+/// rules then never changes which rule fires. The rule at `moved_index`, if any, is the one being
+/// moved, so its failure is not assumed.
+fn is_disjoint_from_rules(
+  if_else_blocks: &[IfElseBlock],
+  range: std::ops::Range<usize>,
+  moved_index: Option<usize>,
+  condition: &SourceLanguageExpression,
+) -> bool {
+  range.into_iter().all(|k| {
+    let mut conditions: Vec<(&SourceLanguageExpression, bool)> = if_else_blocks[..k]
+      .iter()
+      .enumerate()
+      .filter(|(index, _)| Some(*index) != moved_index)
+      .map(|(_, earlier_block)| (&earlier_block.condition, false))
+      .collect();
+    conditions.push((&if_else_blocks[k].condition, true));
+    conditions.push((condition, true));
+    !may_hold_together(&conditions)
+  })
+}
+
+fn or_conditions(
+  c1: &SourceLanguageExpression,
+  c2: &SourceLanguageExpression,
+) -> SourceLanguageExpression {
+  *minimize_condition(&SourceLanguageExpression::BinaryExpression {
+    line_number: 0,
+    operator: BinaryOperator::OR,
+    e1: Box::new(c1.clone()),
+    e2: Box::new(c2.clone()),
+  })
+}
+
+/// Flattens nested chains, so that actions that print the same also compare equal.
+fn flatten_action(
+  action: &SourceLanguageExpression,
+  flattened: &mut Vec<SourceLanguageExpression>,
+) {
+  match action {
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => {
+      for sub_expression in expressions {
+        flatten_action(sub_expression, flattened);
+      }
+    }
+    _ => flattened.push(*erase_line_numbers(action)),
+  }
+}
+
+/// Merges rules with the same action into one rule whose condition is the `or` of theirs. A later
+/// rule moves up into an earlier one when it is disjoint from every rule in between; otherwise
+/// the earlier rule moves down into the later one when it is disjoint from every rule in between.
+pub fn merge_rules_with_identical_actions(program: &FullyInlinedProgram) -> FullyInlinedProgram {
+  let mut merged_if_else_blocks: Vec<IfElseBlock> = Vec::new();
+  for if_else_block in &program.if_else_blocks {
+    let mut action = Vec::new();
+    flatten_action(&if_else_block.action, &mut action);
+    let mut incoming_block = if_else_block.clone();
+    // A rule that moves down into the incoming one becomes part of it, and the merged rule may in
+    // turn absorb another earlier rule.
+    'search: loop {
+      for i in 0..merged_if_else_blocks.len() {
+        let mut earlier_action = Vec::new();
+        flatten_action(&merged_if_else_blocks[i].action, &mut earlier_action);
+        if earlier_action != action {
+          continue;
+        }
+        let end = merged_if_else_blocks.len();
+        let merged_condition = or_conditions(
+          &merged_if_else_blocks[i].condition,
+          &incoming_block.condition,
+        );
+        // Merging only pays off when the merged condition is smaller than the rule it removes.
+        if count_expression_nodes(&merged_condition)
+          >= count_expression_nodes(&merged_if_else_blocks[i].condition)
+            + count_expression_nodes(&incoming_block.condition)
+            + count_expression_nodes(&incoming_block.action)
+        {
+          continue;
+        }
+        if is_disjoint_from_rules(
+          &merged_if_else_blocks,
+          (i + 1)..end,
+          None,
+          &incoming_block.condition,
+        ) {
+          merged_if_else_blocks[i].condition = merged_condition;
+          break 'search;
+        }
+        if is_disjoint_from_rules(
+          &merged_if_else_blocks,
+          (i + 1)..end,
+          Some(i),
+          &merged_if_else_blocks[i].condition,
+        ) {
+          merged_if_else_blocks.remove(i);
+          incoming_block.condition = merged_condition;
+          continue 'search;
+        }
+      }
+      merged_if_else_blocks.push(incoming_block);
+      break;
+    }
+  }
+
+  let merged_rule_count = program.if_else_blocks.len() - merged_if_else_blocks.len();
+  let mut notes = program.notes.clone();
+  if merged_rule_count > 0 {
+    notes.push(format!(
+      "Merged {:} rules into earlier or later rules with the same action.",
+      merged_rule_count
+    ));
+  }
+  FullyInlinedProgram {
+    global_variable_definitions: program.global_variable_definitions.clone(),
+    if_else_blocks: merged_if_else_blocks,
+    notes,
+  }
+}
+
+/// Removes every update that a later update of the same rule overwrites before anything reads
+/// it, like the reset of a phase selection that the rule selects again.
+fn remove_overwritten_updates(action: &SourceLanguageExpression) -> SourceLanguageExpression {
+  let mut flattened = Vec::new();
+  flatten_action(action, &mut flattened);
+  let mut kept_expressions: Vec<Box<SourceLanguageExpression>> = Vec::new();
+  for (index, expression) in flattened.iter().enumerate() {
+    if let SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier,
+      assigned_expression: _,
+    } = expression
+    {
+      let mut is_overwritten = false;
+      for later_expression in &flattened[(index + 1)..] {
+        let mut read_variables = HashSet::new();
+        collect_read_variables(later_expression, &mut read_variables);
+        if read_variables.contains(identifier) {
+          break;
+        }
+        if let SourceLanguageExpression::AssignmentExpression {
+          line_number: _,
+          identifier: later_identifier,
+          assigned_expression: _,
+        } = later_expression
+        {
+          if later_identifier == identifier {
+            is_overwritten = true;
+            break;
+          }
+        }
+      }
+      if is_overwritten {
+        continue;
+      }
+    }
+    kept_expressions.push(Box::new(expression.clone()));
+  }
+  if kept_expressions.len() == flattened.len() {
+    return action.clone();
+  }
+  SourceLanguageExpression::ChainExpression {
+    line_number: 0,
+    expressions: kept_expressions,
+  }
+}
+
+/// Runs the rule-level optimizations on a lowered program.
+pub fn optimize_rules(program: &FullyInlinedProgram) -> FullyInlinedProgram {
+  let program = FullyInlinedProgram {
+    global_variable_definitions: program.global_variable_definitions.clone(),
+    if_else_blocks: program
+      .if_else_blocks
+      .iter()
+      .map(|if_else_block| IfElseBlock {
+        condition: if_else_block.condition.clone(),
+        action: remove_overwritten_updates(&if_else_block.action),
+      })
+      .collect(),
+    notes: program.notes.clone(),
+  };
+  let program = eliminate_unreachable_rules(&program);
+  let program = minimize_rule_conditions(&program);
+  let program = merge_rules_with_identical_actions(&program);
+  // Merged conditions may cover later rules completely.
+  eliminate_unreachable_rules(&program)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utilities::{checked_expression, inlined_program};
+
+  fn erased_rules(program: &FullyInlinedProgram) -> Vec<IfElseBlock> {
+    program
+      .if_else_blocks
+      .iter()
+      .map(|if_else_block| IfElseBlock {
+        condition: *erase_line_numbers(&if_else_block.condition),
+        action: *erase_line_numbers(&if_else_block.action),
+      })
+      .collect()
+  }
+
+  fn merged_rules(rules: &[(&str, &str)]) -> Vec<IfElseBlock> {
+    erased_rules(&merge_rules_with_identical_actions(&inlined_program(rules)))
+  }
+
+  fn expected_rules(rules: &[(&str, &str)]) -> Vec<IfElseBlock> {
+    erased_rules(&inlined_program(rules))
+  }
+
+  #[test]
+  fn later_rules_move_up_across_disjoint_rules() {
+    assert_eq!(
+      expected_rules(&[("x < 1 || x > 9", "serve(x + 1)"), ("x == 5", "backward()")]),
+      merged_rules(&[
+        ("x < 1", "serve(x + 1)"),
+        ("x == 5", "backward()"),
+        ("x > 9", "serve(x + 1)"),
+      ])
+    );
+  }
+
+  #[test]
+  fn earlier_rules_move_down_across_disjoint_rules() {
+    assert_eq!(
+      expected_rules(&[
+        ("y == 1 && x > 3", "backward()"),
+        ("x == 0 || x > 9", "serve(x + 1)"),
+      ]),
+      merged_rules(&[
+        ("x == 0", "serve(x + 1)"),
+        ("y == 1 && x > 3", "backward()"),
+        ("x > 9", "serve(x + 1)"),
+      ])
+    );
+  }
+
+  #[test]
+  fn rules_that_may_overlap_an_intervening_rule_are_kept_apart() {
+    let rules = [
+      ("x < 1", "serve(x + 1)"),
+      ("y == 1", "backward()"),
+      ("x > 9", "serve(x + 1)"),
+    ];
+    assert_eq!(expected_rules(&rules), merged_rules(&rules));
+  }
+
+  #[test]
+  fn rules_with_non_deterministic_reads_are_kept_apart() {
+    // Each rule draws again, so the solver cannot show that any two of them are disjoint.
+    let rules = [
+      ("x < 1 && random(2) == 0", "serve(x + 1)"),
+      ("x == 5", "backward()"),
+      ("x > 9 && random(2) == 0", "serve(x + 1)"),
+    ];
+    assert_eq!(expected_rules(&rules), merged_rules(&rules));
+    let rules = [
+      ("random(2) == 0", "serve(x + 1)"),
+      ("random(2) == 1", "backward()"),
+      ("random(2) == 0", "serve(x + 1)"),
+    ];
+    assert_eq!(expected_rules(&rules), merged_rules(&rules));
+  }
+
+  #[test]
+  fn merges_that_do_not_shrink_the_program_are_skipped() {
+    let rules = [
+      ("x < 1", "forward()"),
+      ("x == 5", "backward()"),
+      ("x > 9", "forward()"),
+    ];
+    assert_eq!(expected_rules(&rules), merged_rules(&rules));
+  }
+
+  fn without_overwritten_updates(action: &str) -> SourceLanguageExpression {
+    *erase_line_numbers(&remove_overwritten_updates(&checked_expression(
+      "void", action,
+    )))
+  }
+
+  fn expected_action(action: &str) -> SourceLanguageExpression {
+    *erase_line_numbers(&checked_expression("void", action))
+  }
+
+  #[test]
+  fn updates_overwritten_before_any_read_are_removed() {
+    assert_eq!(
+      expected_action("({ y = 2; x = 3; forward() })"),
+      without_overwritten_updates("({ x = 1; y = 2; x = 3; forward() })")
+    );
+  }
+
+  #[test]
+  fn updates_read_before_they_are_overwritten_are_kept() {
+    let action = "({ x = 1; y = x; x = 3; forward() })";
+    assert_eq!(expected_action(action), without_overwritten_updates(action));
+    let action = "({ x = 1; x = x + 1; forward() })";
+    assert_eq!(expected_action(action), without_overwritten_updates(action));
+  }
+}
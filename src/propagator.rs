@@ -0,0 +1,415 @@
+use crate::ast::{SourceLanguageExpression, SourceLanguageMutableGlobalVariableDefinition};
+use crate::evaluator::compile_time_evaluation;
+use crate::renamer::{rename_variables, replace_variable_in_expression};
+use std::collections::{HashMap, HashSet};
+
+pub fn collect_assigned_variables(
+  expression: &SourceLanguageExpression,
+  assigned_variables: &mut HashSet<String>,
+) {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => {}
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name: _,
+      function_arguments,
+    } => {
+      for argument_expression in function_arguments {
+        collect_assigned_variables(argument_expression, assigned_variables);
+      }
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => {
+      collect_assigned_variables(e1, assigned_variables);
+      collect_assigned_variables(e2, assigned_variables);
+    }
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => {
+      collect_assigned_variables(condition, assigned_variables);
+      collect_assigned_variables(e1, assigned_variables);
+      collect_assigned_variables(e2, assigned_variables);
+    }
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier,
+      assigned_expression,
+    } => {
+      assigned_variables.insert(identifier.clone());
+      collect_assigned_variables(assigned_expression, assigned_variables);
+    }
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => {
+      for sub_expression in expressions {
+        collect_assigned_variables(sub_expression, assigned_variables);
+      }
+    }
+  }
+}
+
+pub fn collect_read_variables(
+  expression: &SourceLanguageExpression,
+  read_variables: &mut HashSet<String>,
+) {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. } => {}
+    SourceLanguageExpression::VariableExpression {
+      line_number: _,
+      identifier,
+    } => {
+      read_variables.insert(identifier.clone());
+    }
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name: _,
+      function_arguments,
+    } => {
+      for argument_expression in function_arguments {
+        collect_read_variables(argument_expression, read_variables);
+      }
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => {
+      collect_read_variables(e1, read_variables);
+      collect_read_variables(e2, read_variables);
+    }
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => {
+      collect_read_variables(condition, read_variables);
+      collect_read_variables(e1, read_variables);
+      collect_read_variables(e2, read_variables);
+    }
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier: _,
+      assigned_expression,
+    } => collect_read_variables(assigned_expression, read_variables),
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => {
+      for sub_expression in expressions {
+        collect_read_variables(sub_expression, read_variables);
+      }
+    }
+  }
+}
+
+/// Replaces every read of a global variable that is never assigned in the fully inlined main
+/// expression and whose initializer is constant with its initial value, and folds the result. Such
+/// globals are removed from the returned definitions, so they no longer take a memory slot. The
+/// initializers of the remaining globals read the values of the removed ones too.
+pub fn propagate_constant_globals(
+  global_variable_definitions: &[SourceLanguageMutableGlobalVariableDefinition],
+  main_expression: &SourceLanguageExpression,
+) -> (
+  Vec<SourceLanguageMutableGlobalVariableDefinition>,
+  Box<SourceLanguageExpression>,
+) {
+  let mut assigned_variables = HashSet::new();
+  collect_assigned_variables(main_expression, &mut assigned_variables);
+
+  let mut remaining_global_variable_definitions = Vec::new();
+  let mut replacement_map = HashMap::new();
+  for global_variable_definition in global_variable_definitions {
+    // Initializers only read earlier globals, whose constant values are known by now.
+    let assigned_expression = compile_time_evaluation(&replace_variable_in_expression(
+      &global_variable_definition.assigned_expression,
+      &replacement_map,
+    ));
+    let is_constant = matches!(
+      *assigned_expression,
+      SourceLanguageExpression::LiteralExpression { .. }
+    );
+    if is_constant && !assigned_variables.contains(&global_variable_definition.identifier) {
+      replacement_map.insert(
+        global_variable_definition.identifier.clone(),
+        assigned_expression,
+      );
+    } else {
+      remaining_global_variable_definitions.push(SourceLanguageMutableGlobalVariableDefinition {
+        line_number: global_variable_definition.line_number,
+        identifier: global_variable_definition.identifier.clone(),
+        assigned_expression,
+        is_temporary: global_variable_definition.is_temporary,
+      });
+    }
+  }
+
+  (
+    remaining_global_variable_definitions,
+    compile_time_evaluation(&replace_variable_in_expression(
+      main_expression,
+      &replacement_map,
+    )),
+  )
+}
+
+/// Numbers the reads and writes of the temporaries in evaluation order, and widens the live range
+/// of each temporary to cover all of its accesses.
+fn collect_live_ranges(
+  expression: &SourceLanguageExpression,
+  temporaries: &HashSet<String>,
+  next_point: &mut usize,
+  live_ranges: &mut HashMap<String, (usize, usize)>,
+) {
+  let access = |identifier: &String,
+                next_point: &mut usize,
+                live_ranges: &mut HashMap<String, (usize, usize)>| {
+    if temporaries.contains(identifier) {
+      let point = *next_point;
+      *next_point += 1;
+      let live_range = live_ranges
+        .entry(identifier.clone())
+        .or_insert((point, point));
+      live_range.1 = point;
+    }
+  };
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. } => {}
+    SourceLanguageExpression::VariableExpression {
+      line_number: _,
+      identifier,
+    } => access(identifier, next_point, live_ranges),
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name: _,
+      function_arguments,
+    } => {
+      for argument_expression in function_arguments {
+        collect_live_ranges(argument_expression, temporaries, next_point, live_ranges);
+      }
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => {
+      collect_live_ranges(e1, temporaries, next_point, live_ranges);
+      collect_live_ranges(e2, temporaries, next_point, live_ranges);
+    }
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => {
+      collect_live_ranges(condition, temporaries, next_point, live_ranges);
+      collect_live_ranges(e1, temporaries, next_point, live_ranges);
+      collect_live_ranges(e2, temporaries, next_point, live_ranges);
+    }
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier,
+      assigned_expression,
+    } => {
+      collect_live_ranges(assigned_expression, temporaries, next_point, live_ranges);
+      access(identifier, next_point, live_ranges);
+    }
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => {
+      for sub_expression in expressions {
+        collect_live_ranges(sub_expression, temporaries, next_point, live_ranges);
+      }
+    }
+  }
+}
+
+/// Lets temporaries whose live ranges in the fully inlined main expression do not overlap share a
+/// memory slot. Every path through the expression visits its points in evaluation order, and the
+/// checker makes sure that each turn writes a temporary before reading it, so a temporary is dead
+/// outside of its range. Returns the remaining definitions, the renamed expression and the number
+/// of temporaries that took the slot of another one.
+pub fn share_temporary_slots(
+  global_variable_definitions: &[SourceLanguageMutableGlobalVariableDefinition],
+  main_expression: &SourceLanguageExpression,
+) -> (
+  Vec<SourceLanguageMutableGlobalVariableDefinition>,
+  Box<SourceLanguageExpression>,
+  usize,
+) {
+  let temporaries: HashSet<String> = global_variable_definitions
+    .iter()
+    .filter(|global_variable_definition| global_variable_definition.is_temporary)
+    .map(|global_variable_definition| global_variable_definition.identifier.clone())
+    .collect();
+  let mut live_ranges = HashMap::new();
+  collect_live_ranges(main_expression, &temporaries, &mut 0, &mut live_ranges);
+  let mut ordered_live_ranges: Vec<(String, (usize, usize))> = live_ranges.into_iter().collect();
+  ordered_live_ranges.sort_by_key(|(_, live_range)| *live_range);
+
+  // Each slot is named after its first temporary, and remembers where its last range ends.
+  let mut slots: Vec<(String, usize)> = Vec::new();
+  let mut renaming = HashMap::new();
+  for (identifier, (start, end)) in ordered_live_ranges {
+    match slots.iter_mut().find(|(_, slot_end)| *slot_end < start) {
+      Some(slot) => {
+        renaming.insert(identifier, slot.0.clone());
+        slot.1 = end;
+      }
+      None => slots.push((identifier, end)),
+    }
+  }
+
+  let remaining_global_variable_definitions = global_variable_definitions
+    .iter()
+    .filter(|global_variable_definition| {
+      !renaming.contains_key(&global_variable_definition.identifier)
+    })
+    .cloned()
+    .collect();
+  (
+    remaining_global_variable_definitions,
+    rename_variables(main_expression, &renaming),
+    renaming.len(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::renamer::erase_line_numbers;
+  use crate::test_utilities::checked_program;
+
+  /// Propagates the globals of a program into its `main`, and returns the names of the remaining
+  /// globals with the new `main`.
+  fn propagate(source: &str) -> (Vec<String>, Box<SourceLanguageExpression>) {
+    let program = checked_program(source);
+    let main_expression = &program.function_definitions.last().unwrap().body;
+    let (global_variable_definitions, main_expression) =
+      propagate_constant_globals(&program.global_variable_definitions, main_expression);
+    (
+      global_variable_definitions
+        .into_iter()
+        .map(|global_variable| global_variable.identifier)
+        .collect(),
+      erase_line_numbers(&main_expression),
+    )
+  }
+
+  fn main_body(source: &str) -> Box<SourceLanguageExpression> {
+    erase_line_numbers(
+      &checked_program(source)
+        .function_definitions
+        .last()
+        .unwrap()
+        .body,
+    )
+  }
+
+  #[test]
+  fn globals_that_are_never_assigned_are_folded() {
+    let (globals, main_expression) = propagate(
+      "var limit = 3;
+       var count = 0;
+       fun main(): void = if limit > 2 then ({ count = count + limit; forward() }) else left()",
+    );
+    assert_eq!(globals, vec!["count"]);
+    assert_eq!(
+      main_expression,
+      main_body("var count = 0;\nfun main(): void = ({ count = count + 3; forward() })")
+    );
+  }
+
+  #[test]
+  fn assignments_in_any_rule_block_propagation() {
+    // The assignment only happens on one path, and writes the initial value again, but another
+    // path still reads whatever an earlier turn left.
+    let (globals, _) = propagate(
+      "var a = 1;
+       fun main(): void = if nearby(0) > 0 then ({ a = 1; wait() }) else serve(a)",
+    );
+    assert_eq!(globals, vec!["a"]);
+    let (globals, _) = propagate(
+      "var a = 1;
+       var b = 2;
+       fun main(): void = if ahead(1) > 0 then ({ if a > 0 then ({ b = 3 }) else ({}); left() }) else serve(a + b)",
+    );
+    assert_eq!(globals, vec!["b"]);
+  }
+
+  /// Shares the slots of the temporaries of a program in its `main`, and returns the names of the
+  /// remaining globals with the new `main` and the number of temporaries that took another slot.
+  fn share(source: &str) -> (Vec<String>, Box<SourceLanguageExpression>, usize) {
+    let program = checked_program(source);
+    let main_expression = &program.function_definitions.last().unwrap().body;
+    let (global_variable_definitions, main_expression, shared_count) =
+      share_temporary_slots(&program.global_variable_definitions, main_expression);
+    (
+      global_variable_definitions
+        .into_iter()
+        .map(|global_variable| global_variable.identifier)
+        .collect(),
+      erase_line_numbers(&main_expression),
+      shared_count,
+    )
+  }
+
+  #[test]
+  fn temporaries_with_disjoint_live_ranges_share_a_slot() {
+    let (globals, main_expression, shared_count) = share(
+      "var x = 0;
+       tmp a;
+       tmp b;
+       fun main(): void = ({ a = nearby(0); x = a; b = nearby(1); x = x + b; wait() })",
+    );
+    assert_eq!(globals, vec!["x", "a"]);
+    assert_eq!(shared_count, 1);
+    assert_eq!(
+      main_expression,
+      main_body(
+        "var x = 0;
+         tmp a;
+         fun main(): void = ({ a = nearby(0); x = a; a = nearby(1); x = x + a; wait() })"
+      )
+    );
+  }
+
+  #[test]
+  fn temporaries_live_at_the_same_time_keep_their_slots() {
+    let (globals, _, shared_count) = share(
+      "var x = 0;
+       tmp a;
+       tmp b;
+       fun main(): void = ({ a = nearby(0); b = nearby(1); x = a + b; wait() })",
+    );
+    assert_eq!(globals, vec!["x", "a", "b"]);
+    assert_eq!(shared_count, 0);
+    // A temporary read in one branch stays live across every write of the other branch before it.
+    let (_, _, shared_count) = share(
+      "var x = 0;
+       tmp a;
+       tmp b;
+       fun main(): void = ({
+         a = nearby(0);
+         if x > 0 then ({ b = nearby(1); x = b }) else ({ x = a });
+         wait()
+       })",
+    );
+    assert_eq!(shared_count, 0);
+  }
+}
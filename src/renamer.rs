@@ -32,7 +32,7 @@ pub fn replace_variable_in_expression(
       let mut checked_function_arguments = Vec::new();
       for argument_expression in function_arguments {
         checked_function_arguments.push(replace_variable_in_expression(
-          &argument_expression,
+          argument_expression,
           expression_replacement_map,
         ));
       }
@@ -51,8 +51,8 @@ pub fn replace_variable_in_expression(
     } => Box::new(SourceLanguageExpression::BinaryExpression {
       line_number: *line_number,
       operator: *operator,
-      e1: replace_variable_in_expression(&e1, expression_replacement_map),
-      e2: replace_variable_in_expression(&e2, expression_replacement_map),
+      e1: replace_variable_in_expression(e1, expression_replacement_map),
+      e2: replace_variable_in_expression(e2, expression_replacement_map),
     }),
     SourceLanguageExpression::IfElseExpression {
       line_number,
@@ -61,9 +61,9 @@ pub fn replace_variable_in_expression(
       e2,
     } => Box::new(SourceLanguageExpression::IfElseExpression {
       line_number: *line_number,
-      condition: replace_variable_in_expression(&condition, expression_replacement_map),
-      e1: replace_variable_in_expression(&e1, expression_replacement_map),
-      e2: replace_variable_in_expression(&e2, expression_replacement_map),
+      condition: replace_variable_in_expression(condition, expression_replacement_map),
+      e1: replace_variable_in_expression(e1, expression_replacement_map),
+      e2: replace_variable_in_expression(e2, expression_replacement_map),
     }),
     SourceLanguageExpression::AssignmentExpression {
       line_number,
@@ -73,7 +73,7 @@ pub fn replace_variable_in_expression(
       line_number: *line_number,
       identifier: (*identifier).clone(),
       assigned_expression: replace_variable_in_expression(
-        &assigned_expression,
+        assigned_expression,
         expression_replacement_map,
       ),
     }),
@@ -84,7 +84,7 @@ pub fn replace_variable_in_expression(
       let mut replaced_expressions = Vec::new();
       for sub_expression in expressions {
         replaced_expressions.push(replace_variable_in_expression(
-          &sub_expression,
+          sub_expression,
           expression_replacement_map,
         ));
       }
@@ -95,3 +95,247 @@ pub fn replace_variable_in_expression(
     }
   }
 }
+
+/// Returns a copy of the expression with every line number set to 0, so that two expressions that
+/// only differ in where they come from compare and hash as equal.
+pub fn erase_line_numbers(expression: &SourceLanguageExpression) -> Box<SourceLanguageExpression> {
+  match &expression {
+    SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal,
+    } => Box::new(SourceLanguageExpression::LiteralExpression {
+      line_number: 0,
+      literal: *literal,
+    }),
+    SourceLanguageExpression::VariableExpression {
+      line_number: _,
+      identifier,
+    } => Box::new(SourceLanguageExpression::VariableExpression {
+      line_number: 0,
+      identifier: (*identifier).clone(),
+    }),
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type,
+      function_name,
+      function_arguments,
+    } => Box::new(SourceLanguageExpression::FunctionCallExpression {
+      line_number: 0,
+      static_type: *static_type,
+      function_name: (*function_name).clone(),
+      function_arguments: function_arguments
+        .iter()
+        .map(|e| erase_line_numbers(e))
+        .collect(),
+    }),
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator,
+      e1,
+      e2,
+    } => Box::new(SourceLanguageExpression::BinaryExpression {
+      line_number: 0,
+      operator: *operator,
+      e1: erase_line_numbers(e1),
+      e2: erase_line_numbers(e2),
+    }),
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => Box::new(SourceLanguageExpression::IfElseExpression {
+      line_number: 0,
+      condition: erase_line_numbers(condition),
+      e1: erase_line_numbers(e1),
+      e2: erase_line_numbers(e2),
+    }),
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier,
+      assigned_expression,
+    } => Box::new(SourceLanguageExpression::AssignmentExpression {
+      line_number: 0,
+      identifier: (*identifier).clone(),
+      assigned_expression: erase_line_numbers(assigned_expression),
+    }),
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => Box::new(SourceLanguageExpression::ChainExpression {
+      line_number: 0,
+      expressions: expressions.iter().map(|e| erase_line_numbers(e)).collect(),
+    }),
+  }
+}
+
+/// Renames the variables in `renaming` wherever they are read or assigned.
+pub fn rename_variables(
+  expression: &SourceLanguageExpression,
+  renaming: &HashMap<String, String>,
+) -> Box<SourceLanguageExpression> {
+  let rename = |identifier: &String| renaming.get(identifier).unwrap_or(identifier).clone();
+  match &expression {
+    SourceLanguageExpression::LiteralExpression { .. } => Box::new(expression.clone()),
+    SourceLanguageExpression::VariableExpression {
+      line_number,
+      identifier,
+    } => Box::new(SourceLanguageExpression::VariableExpression {
+      line_number: *line_number,
+      identifier: rename(identifier),
+    }),
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number,
+      static_type,
+      function_name,
+      function_arguments,
+    } => Box::new(SourceLanguageExpression::FunctionCallExpression {
+      line_number: *line_number,
+      static_type: *static_type,
+      function_name: (*function_name).clone(),
+      function_arguments: function_arguments
+        .iter()
+        .map(|e| rename_variables(e, renaming))
+        .collect(),
+    }),
+    SourceLanguageExpression::BinaryExpression {
+      line_number,
+      operator,
+      e1,
+      e2,
+    } => Box::new(SourceLanguageExpression::BinaryExpression {
+      line_number: *line_number,
+      operator: *operator,
+      e1: rename_variables(e1, renaming),
+      e2: rename_variables(e2, renaming),
+    }),
+    SourceLanguageExpression::IfElseExpression {
+      line_number,
+      condition,
+      e1,
+      e2,
+    } => Box::new(SourceLanguageExpression::IfElseExpression {
+      line_number: *line_number,
+      condition: rename_variables(condition, renaming),
+      e1: rename_variables(e1, renaming),
+      e2: rename_variables(e2, renaming),
+    }),
+    SourceLanguageExpression::AssignmentExpression {
+      line_number,
+      identifier,
+      assigned_expression,
+    } => Box::new(SourceLanguageExpression::AssignmentExpression {
+      line_number: *line_number,
+      identifier: rename(identifier),
+      assigned_expression: rename_variables(assigned_expression, renaming),
+    }),
+    SourceLanguageExpression::ChainExpression {
+      line_number,
+      expressions,
+    } => Box::new(SourceLanguageExpression::ChainExpression {
+      line_number: *line_number,
+      expressions: expressions
+        .iter()
+        .map(|e| rename_variables(e, renaming))
+        .collect(),
+    }),
+  }
+}
+
+pub fn expression_calls_function(
+  expression: &SourceLanguageExpression,
+  function_name: &str,
+) -> bool {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => false,
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name: called_function_name,
+      function_arguments,
+    } => {
+      called_function_name == function_name
+        || function_arguments
+          .iter()
+          .any(|e| expression_calls_function(e, function_name))
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => {
+      expression_calls_function(e1, function_name) || expression_calls_function(e2, function_name)
+    }
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => {
+      expression_calls_function(condition, function_name)
+        || expression_calls_function(e1, function_name)
+        || expression_calls_function(e2, function_name)
+    }
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier: _,
+      assigned_expression,
+    } => expression_calls_function(assigned_expression, function_name),
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => expressions
+      .iter()
+      .any(|e| expression_calls_function(e, function_name)),
+  }
+}
+
+pub fn count_expression_nodes(expression: &SourceLanguageExpression) -> usize {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => 1,
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name: _,
+      function_arguments,
+    } => {
+      1 + function_arguments
+        .iter()
+        .map(|e| count_expression_nodes(e))
+        .sum::<usize>()
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => 1 + count_expression_nodes(e1) + count_expression_nodes(e2),
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => {
+      1 + count_expression_nodes(condition)
+        + count_expression_nodes(e1)
+        + count_expression_nodes(e2)
+    }
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier: _,
+      assigned_expression,
+    } => 1 + count_expression_nodes(assigned_expression),
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => {
+      1 + expressions
+        .iter()
+        .map(|e| count_expression_nodes(e))
+        .sum::<usize>()
+    }
+  }
+}
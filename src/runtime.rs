@@ -30,3 +30,39 @@ pub fn get_critter_world_runtime() -> HashMap<String, FunctionType> {
     String::from("smell") => FunctionType { argument_types: vec![], return_type: ExpressionStaticType::IntType },
   }
 }
+
+/// Returns whether a runtime function always produces the same value when it is called with the
+/// same arguments within the same turn. Actions and `random` are not deterministic, and neither is
+/// `pass`, which counts the passes of the turn.
+pub fn is_deterministic_runtime_function(function_name: &str) -> bool {
+  matches!(
+    function_name,
+    "memsize"
+      | "defense"
+      | "offense"
+      | "size"
+      | "energy"
+      | "posture"
+      | "nearby"
+      | "ahead"
+      | "smell"
+  )
+}
+
+/// Returns whether a runtime function is a critter action, which ends the turn.
+pub fn is_runtime_action(function_name: &str) -> bool {
+  matches!(
+    function_name,
+    "wait"
+      | "forward"
+      | "backward"
+      | "left"
+      | "right"
+      | "eat"
+      | "attack"
+      | "grow"
+      | "bud"
+      | "mate"
+      | "serve"
+  )
+}
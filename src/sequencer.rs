@@ -0,0 +1,682 @@
+use crate::ast::{BinaryOperator, ExpressionStaticType, LiteralValue, SourceLanguageExpression};
+use crate::evaluator::{has_side_effect, is_deterministic};
+use crate::propagator::collect_read_variables;
+use std::collections::HashSet;
+
+// Critter tests all its rule conditions before it performs any update, and a turn ends with the
+// first action. Sequential code that branches on what it computed earlier, or that performs more
+// than one action, is split into phases. A compiler-owned global selects the phase that the next
+// pass runs: an update-only rule makes the critter evaluate its rules again within the same turn,
+// and a rule with an action resumes on the next turn.
+
+/// The global that selects the phase to run. 0 runs the main expression.
+pub const PHASE_VARIABLE: &str = "_phase";
+
+/// How a condition reads a value that code before it in the same chain assigned.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoweringMode {
+  /// Substitutes the assigned expression for the read when it can be computed again, so the
+  /// whole chain becomes a single rule. The substituted expressions may grow large.
+  Substitute,
+  /// Performs the assignments with an update-only rule, and reads the stored values on the next
+  /// pass of the same turn. Every dependent step costs a pass, but each value is computed once.
+  UpdateOnlyPasses,
+}
+
+/// Collects the variables read by the conditions of the ifs in an expression. Lowering hoists
+/// these reads above every update of the rule.
+pub fn collect_condition_read_variables(
+  expression: &SourceLanguageExpression,
+  read_variables: &mut HashSet<String>,
+) {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => {}
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name: _,
+      function_arguments,
+    } => {
+      for argument in function_arguments {
+        collect_condition_read_variables(argument, read_variables);
+      }
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => {
+      collect_condition_read_variables(e1, read_variables);
+      collect_condition_read_variables(e2, read_variables);
+    }
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => {
+      collect_read_variables(condition, read_variables);
+      collect_condition_read_variables(e1, read_variables);
+      collect_condition_read_variables(e2, read_variables);
+    }
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier: _,
+      assigned_expression,
+    } => collect_condition_read_variables(assigned_expression, read_variables),
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => {
+      for sub_expression in expressions {
+        collect_condition_read_variables(sub_expression, read_variables);
+      }
+    }
+  }
+}
+
+/// Returns whether `value` can be computed again at the start of the turn and give what it gave
+/// at its assignment, given the variables that hold values that cannot.
+pub fn is_recomputable(
+  value: &SourceLanguageExpression,
+  unrecomputable_variables: &HashSet<String>,
+) -> bool {
+  let mut read_variables = HashSet::new();
+  collect_read_variables(value, &mut read_variables);
+  is_deterministic(value)
+    && !has_side_effect(value)
+    && read_variables.is_disjoint(unrecomputable_variables)
+}
+
+/// What the code that ran earlier along a path leaves behind for the code after it.
+#[derive(Debug, Clone)]
+struct SplitState {
+  lowering_mode: LoweringMode,
+  /// The variables that may hold a value that later conditions cannot read in the same pass.
+  unrecomputable_variables: HashSet<String>,
+  /// Whether an action may have run, which ends the turn.
+  has_acted: bool,
+}
+
+impl SplitState {
+  fn new(lowering_mode: LoweringMode) -> SplitState {
+    SplitState {
+      lowering_mode,
+      unrecomputable_variables: HashSet::new(),
+      has_acted: false,
+    }
+  }
+
+  /// Records an assignment of `value` to `identifier`.
+  fn record_assignment(&mut self, identifier: &str, value: &SourceLanguageExpression) {
+    if self.lowering_mode == LoweringMode::UpdateOnlyPasses
+      || !is_recomputable(value, &self.unrecomputable_variables)
+    {
+      self.unrecomputable_variables.insert(identifier.to_string());
+    }
+  }
+
+  fn merge(&mut self, other: SplitState) {
+    self
+      .unrecomputable_variables
+      .extend(other.unrecomputable_variables);
+    self.has_acted = self.has_acted || other.has_acted;
+  }
+}
+
+fn is_action(expression: &SourceLanguageExpression) -> bool {
+  match expression {
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type,
+      function_name: _,
+      function_arguments: _,
+    } => *static_type == ExpressionStaticType::VoidType,
+    _ => false,
+  }
+}
+
+/// Returns whether an expression does nothing at all, like the `{}` left by a stubbed call.
+fn is_empty(expression: &SourceLanguageExpression) -> bool {
+  match expression {
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => expressions
+      .iter()
+      .all(|sub_expression| is_empty(sub_expression)),
+    _ => false,
+  }
+}
+
+/// Returns whether an update may move before an action that reads `action_read_variables`: it
+/// assigns values computed from memory alone, which the action does not change, to variables the
+/// action does not read.
+fn may_move_before_action(
+  expression: &SourceLanguageExpression,
+  action_read_variables: &HashSet<String>,
+) -> bool {
+  fn reads_only_memory(value: &SourceLanguageExpression) -> bool {
+    match value {
+      SourceLanguageExpression::LiteralExpression { .. }
+      | SourceLanguageExpression::VariableExpression { .. } => true,
+      SourceLanguageExpression::BinaryExpression {
+        line_number: _,
+        operator: _,
+        e1,
+        e2,
+      } => reads_only_memory(e1) && reads_only_memory(e2),
+      _ => false,
+    }
+  }
+  match expression {
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier,
+      assigned_expression,
+    } => !action_read_variables.contains(identifier) && reads_only_memory(assigned_expression),
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => expressions
+      .iter()
+      .all(|sub_expression| may_move_before_action(sub_expression, action_read_variables)),
+    _ => false,
+  }
+}
+
+/// Adds what an expression may leave behind to `state`: the variables it may assign a value that
+/// cannot be recomputed, and whether it may act.
+fn record_effects(expression: &SourceLanguageExpression, state: &mut SplitState) {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => {}
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name: _,
+      function_arguments,
+    } => {
+      for argument in function_arguments {
+        record_effects(argument, state);
+      }
+      if is_action(expression) {
+        state.has_acted = true;
+      }
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => {
+      record_effects(e1, state);
+      record_effects(e2, state);
+    }
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => {
+      record_effects(condition, state);
+      record_effects(e1, state);
+      record_effects(e2, state);
+    }
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier,
+      assigned_expression,
+    } => {
+      record_effects(assigned_expression, state);
+      state.record_assignment(identifier, assigned_expression);
+    }
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => {
+      for sub_expression in expressions {
+        record_effects(sub_expression, state);
+      }
+    }
+  }
+}
+
+/// Walks an expression in evaluation order, and returns whether some part of it cannot run in the
+/// same rule as what came before it: anything after an action, or a condition that reads a value
+/// that cannot be recomputed.
+fn needs_new_pass(expression: &SourceLanguageExpression, state: &mut SplitState) -> bool {
+  if state.has_acted && !is_empty(expression) {
+    return true;
+  }
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => false,
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number: _,
+      static_type: _,
+      function_name: _,
+      function_arguments,
+    } => {
+      if function_arguments
+        .iter()
+        .any(|argument| needs_new_pass(argument, state))
+      {
+        return true;
+      }
+      if is_action(expression) {
+        state.has_acted = true;
+      }
+      false
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: _,
+      e1,
+      e2,
+    } => needs_new_pass(e1, state) || needs_new_pass(e2, state),
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
+      condition,
+      e1,
+      e2,
+    } => {
+      let mut read_variables = HashSet::new();
+      collect_read_variables(condition, &mut read_variables);
+      if !read_variables.is_disjoint(&state.unrecomputable_variables)
+        || needs_new_pass(condition, state)
+      {
+        return true;
+      }
+      let mut else_state = state.clone();
+      let needs = needs_new_pass(e1, state) || needs_new_pass(e2, &mut else_state);
+      state.merge(else_state);
+      needs
+    }
+    SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
+      identifier,
+      assigned_expression,
+    } => {
+      if needs_new_pass(assigned_expression, state) {
+        return true;
+      }
+      state.record_assignment(identifier, assigned_expression);
+      false
+    }
+    SourceLanguageExpression::ChainExpression {
+      line_number: _,
+      expressions,
+    } => expressions
+      .iter()
+      .any(|sub_expression| needs_new_pass(sub_expression, state)),
+  }
+}
+
+fn phase_assignment(phase: usize) -> Box<SourceLanguageExpression> {
+  Box::new(SourceLanguageExpression::AssignmentExpression {
+    line_number: 0,
+    identifier: PHASE_VARIABLE.to_string(),
+    assigned_expression: Box::new(SourceLanguageExpression::LiteralExpression {
+      line_number: 0,
+      literal: LiteralValue::IntLiteral(phase as i32),
+    }),
+  })
+}
+
+struct PhaseSplitter {
+  lowering_mode: LoweringMode,
+  phases: Vec<SourceLanguageExpression>,
+}
+
+impl PhaseSplitter {
+  fn needs_split(expressions: &[Box<SourceLanguageExpression>], state: &SplitState) -> bool {
+    let mut state = state.clone();
+    expressions
+      .iter()
+      .any(|expression| needs_new_pass(expression, &mut state))
+  }
+
+  /// Returns an expression that runs `expressions` in order, after code that left `state`. At the
+  /// first point that needs a new pass, it selects a new phase that runs the rest instead. The ifs
+  /// before that point take the rest of the chain into their branches.
+  fn sequence(
+    &mut self,
+    expressions: &[Box<SourceLanguageExpression>],
+    state: &SplitState,
+  ) -> Box<SourceLanguageExpression> {
+    if !PhaseSplitter::needs_split(expressions, state) {
+      return Box::new(SourceLanguageExpression::ChainExpression {
+        line_number: 0,
+        expressions: expressions.to_vec(),
+      });
+    }
+    if state.has_acted {
+      return self.split(expressions);
+    }
+    let head = &expressions[0];
+    let tail = &expressions[1..];
+    let mut head_state = state.clone();
+    let head_needs_split = needs_new_pass(head, &mut head_state);
+    let mut head_effects = state.clone();
+    record_effects(head, &mut head_effects);
+    let mut read_variables = HashSet::new();
+    collect_condition_read_variables(head, &mut read_variables);
+    match &**head {
+      SourceLanguageExpression::ChainExpression {
+        line_number: _,
+        expressions: head_expressions,
+      } => self.sequence(
+        &head_expressions
+          .iter()
+          .chain(tail)
+          .cloned()
+          .collect::<Vec<_>>(),
+        state,
+      ),
+      SourceLanguageExpression::IfElseExpression {
+        line_number,
+        condition,
+        e1,
+        e2,
+      } if head_needs_split || head_effects.has_acted => {
+        let mut condition_read_variables = HashSet::new();
+        collect_read_variables(condition, &mut condition_read_variables);
+        if !condition_read_variables.is_disjoint(&state.unrecomputable_variables) {
+          return self.split(expressions);
+        }
+        Box::new(SourceLanguageExpression::IfElseExpression {
+          line_number: *line_number,
+          condition: condition.clone(),
+          e1: self.sequence(
+            &std::iter::once(e1.clone())
+              .chain(tail.iter().cloned())
+              .collect::<Vec<_>>(),
+            state,
+          ),
+          e2: self.sequence(
+            &std::iter::once(e2.clone())
+              .chain(tail.iter().cloned())
+              .collect::<Vec<_>>(),
+            state,
+          ),
+        })
+      }
+      // Other expressions move to the next phase as a whole.
+      _ if !read_variables.is_disjoint(&state.unrecomputable_variables) => self.split(expressions),
+      _ => {
+        // The action has to come last in the rule. The updates right after it move before it,
+        // like the state changes of a machine transition, and the selection of the next phase
+        // runs whatever is left.
+        let moved_count = if is_action(head) {
+          let mut action_read_variables = HashSet::new();
+          collect_read_variables(head, &mut action_read_variables);
+          tail
+            .iter()
+            .take_while(|expression| may_move_before_action(expression, &action_read_variables))
+            .count()
+        } else {
+          0
+        };
+        let rest = self.sequence(&tail[moved_count..], &head_effects);
+        let sequenced_expressions = if is_action(head) {
+          tail[..moved_count]
+            .iter()
+            .cloned()
+            .chain([rest, head.clone()])
+            .collect()
+        } else {
+          vec![head.clone(), rest]
+        };
+        Box::new(SourceLanguageExpression::ChainExpression {
+          line_number: 0,
+          expressions: sequenced_expressions,
+        })
+      }
+    }
+  }
+
+  /// Moves `expressions` into a new phase, and returns the update that selects it.
+  fn split(
+    &mut self,
+    expressions: &[Box<SourceLanguageExpression>],
+  ) -> Box<SourceLanguageExpression> {
+    self.phases.push(SourceLanguageExpression::ChainExpression {
+      line_number: 0,
+      expressions: Vec::new(),
+    });
+    let phase = self.phases.len();
+    // The phase resets the selection first, so that its action can still come last.
+    let phase_expressions: Vec<_> = std::iter::once(phase_assignment(0))
+      .chain(expressions.iter().cloned())
+      .collect();
+    self.phases[phase - 1] =
+      *self.sequence(&phase_expressions, &SplitState::new(self.lowering_mode));
+    phase_assignment(phase)
+  }
+}
+
+/// Splits the main expression into phases wherever its code cannot run in a single rule: after an
+/// action that more code follows, like `{ right(); right(); forward() }`, and before a condition
+/// that reads a value assigned earlier that cannot be recomputed, like
+/// `{ x = random(3); if x == 0 then ... }`. With `LoweringMode::UpdateOnlyPasses`, it also splits
+/// before every condition that reads a value assigned earlier. Lowering substitutes the other
+/// assigned values into later conditions instead. Returns the new main expression, which
+/// dispatches on `PHASE_VARIABLE`, and the number of phases added.
+pub fn split_into_phases(
+  expression: &SourceLanguageExpression,
+  lowering_mode: LoweringMode,
+) -> (Box<SourceLanguageExpression>, usize) {
+  let mut splitter = PhaseSplitter {
+    lowering_mode,
+    phases: Vec::new(),
+  };
+  let main_expression = splitter.sequence(
+    &[Box::new(expression.clone())],
+    &SplitState::new(lowering_mode),
+  );
+  if splitter.phases.is_empty() {
+    return (main_expression, 0);
+  }
+  let phase_count = splitter.phases.len();
+  let mut dispatch = main_expression;
+  for (index, phase_expression) in splitter.phases.into_iter().enumerate().rev() {
+    dispatch = Box::new(SourceLanguageExpression::IfElseExpression {
+      line_number: 0,
+      condition: Box::new(SourceLanguageExpression::BinaryExpression {
+        line_number: 0,
+        operator: BinaryOperator::EQ,
+        e1: Box::new(SourceLanguageExpression::VariableExpression {
+          line_number: 0,
+          identifier: PHASE_VARIABLE.to_string(),
+        }),
+        e2: Box::new(SourceLanguageExpression::LiteralExpression {
+          line_number: 0,
+          literal: LiteralValue::IntLiteral(index as i32 + 1),
+        }),
+      }),
+      e1: Box::new(phase_expression),
+      e2: dispatch,
+    });
+  }
+  (dispatch, phase_count)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utilities::{checked_expression, compile, compile_with_lowering_mode, rules};
+
+  fn phase_count(source: &str) -> usize {
+    split_into_phases(
+      &checked_expression("void", source),
+      LoweringMode::Substitute,
+    )
+    .1
+  }
+
+  #[test]
+  fn only_values_that_may_change_are_unrecomputable() {
+    let unrecomputable_variables: HashSet<String> = vec!["y".to_string()].into_iter().collect();
+    let recomputable = |source: &str| {
+      is_recomputable(
+        &checked_expression("int", source),
+        &unrecomputable_variables,
+      )
+    };
+    assert!(recomputable("x + 1"));
+    assert!(!recomputable("random(3)"));
+    assert!(!recomputable("x + y"));
+  }
+
+  #[test]
+  fn conditions_on_recomputable_writes_need_no_phase() {
+    assert_eq!(
+      0,
+      phase_count("{ x = x + 1; if x == 1 then forward() else wait() }")
+    );
+    assert_eq!(
+      0,
+      phase_count("{ x = random(3); if y == 1 then forward() else wait() }")
+    );
+  }
+
+  #[test]
+  fn conditions_on_random_draws_run_in_a_later_phase() {
+    assert_eq!(
+      1,
+      phase_count("{ x = random(3); if x == 1 then forward() else wait() }")
+    );
+    // Each branch that draws again needs a phase of its own.
+    assert_eq!(
+      2,
+      phase_count(
+        "if y == 0 then ({ x = random(3); if x == 1 then forward() else wait() }) \
+         else ({ x = random(2); if x == 1 then left() else right() })"
+      )
+    );
+  }
+
+  #[test]
+  fn phases_reset_the_selection_before_they_act() {
+    let code = compile(include_str!("../examples/sequential-conditions.txt")).unwrap();
+    assert_eq!(
+      rules(&code)[1..],
+      [
+        "{(mem[11] != 1) and ((mem[9] + 1) = 1)} --> mem[9] := (mem[9] + 1) forward;",
+        "(mem[11] != 1) --> mem[9] := (mem[9] + 1) mem[10] := random[4] mem[11] := 1;",
+        "(mem[10] = 0) --> mem[11] := 0 left;",
+        "(mem[10] = 1) --> mem[11] := 0 right;",
+        "1 = 1 --> mem[11] := 0 wait;",
+      ]
+    );
+  }
+
+  #[test]
+  fn code_without_a_second_action_needs_no_phases() {
+    let (_, phase_count) = split_into_phases(
+      &checked_expression(
+        "void",
+        "({ x = x + 1; if x > 2 then forward() else left() })",
+      ),
+      LoweringMode::Substitute,
+    );
+    assert_eq!(phase_count, 0);
+  }
+
+  #[test]
+  fn actions_after_an_action_resume_on_later_turns() {
+    let code = compile("fun main(): void = ({ forward(); forward(); forward() })").unwrap();
+    assert_eq!(
+      rules(&code),
+      vec![
+        "mem[8] = 0 --> mem[8] := 1 mem[9] := 0;",
+        "(mem[9] = 1) --> mem[9] := 2 forward;",
+        "(mem[9] = 2) --> mem[9] := 0 forward;",
+        "1 = 1 --> mem[9] := 1 forward;",
+      ]
+    );
+  }
+
+  #[test]
+  fn only_the_branch_with_more_actions_selects_a_phase() {
+    let code =
+      compile("fun main(): void = if nearby(0) > 0 then ({ right(); forward() }) else wait()")
+        .unwrap();
+    assert_eq!(
+      rules(&code),
+      vec![
+        "mem[8] = 0 --> mem[8] := 1 mem[9] := 0;",
+        "(mem[9] = 1) --> mem[9] := 0 forward;",
+        "(nearby[0] <= 0) --> wait;",
+        "1 = 1 --> mem[9] := 1 right;",
+      ]
+    );
+  }
+
+  #[test]
+  fn update_only_passes_store_values_that_conditions_read() {
+    let source = "({ x = x + 1; if x > 3 then left() else eat() })";
+    assert_eq!(
+      split_into_phases(
+        &checked_expression("void", source),
+        LoweringMode::Substitute
+      )
+      .1,
+      0
+    );
+    let code = compile_with_lowering_mode(
+      "var n = 0;
+       fun main(): void = ({ n = n + 1; if n > 3 then left() else eat() })",
+      LoweringMode::UpdateOnlyPasses,
+    )
+    .unwrap();
+    assert_eq!(
+      rules(&code),
+      vec![
+        "mem[8] = 0 --> mem[8] := 1 mem[9] := 0 mem[10] := 0;",
+        "(mem[10] != 1) --> mem[9] := (mem[9] + 1) mem[10] := 1;",
+        "(mem[9] <= 3) --> mem[10] := 0 eat;",
+        "1 = 1 --> mem[10] := 0 left;",
+      ]
+    );
+  }
+
+  #[test]
+  fn update_only_passes_are_not_needed_when_no_condition_reads_the_value() {
+    let code = compile_with_lowering_mode(
+      "var n = 0;
+       fun main(): void = ({ n = n + 1; if nearby(0) > 3 then left() else eat() })",
+      LoweringMode::UpdateOnlyPasses,
+    )
+    .unwrap();
+    assert_eq!(
+      rules(&code),
+      vec![
+        "mem[8] = 0 --> mem[8] := 1 mem[9] := 0;",
+        "(nearby[0] <= 3) --> mem[9] := (mem[9] + 1) eat;",
+        "1 = 1 --> mem[9] := (mem[9] + 1) left;",
+      ]
+    );
+  }
+
+  #[test]
+  fn updates_that_the_action_does_not_read_move_before_it() {
+    let code = compile(
+      "var n = 0;
+       fun main(): void = ({ n = n + 1; right(); n = n + 2; if n > 3 then left() else eat() })",
+    )
+    .unwrap();
+    assert_eq!(
+      rules(&code),
+      vec![
+        "mem[8] = 0 --> mem[8] := 1 mem[9] := 0 mem[10] := 0;",
+        "(mem[10] != 1) --> mem[9] := (mem[9] + 1) mem[9] := (mem[9] + 2) mem[10] := 1 right;",
+        "(mem[9] <= 3) --> mem[10] := 0 eat;",
+        "1 = 1 --> mem[10] := 0 left;",
+      ]
+    );
+  }
+}
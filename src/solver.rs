@@ -0,0 +1,449 @@
+use crate::ast::{BinaryOperator, LiteralValue, SourceLanguageExpression};
+use crate::evaluator::{has_side_effect, is_deterministic};
+use crate::renamer::erase_line_numbers;
+use std::collections::{BTreeSet, HashMap};
+
+// A small decision procedure for rule conditions. Conditions are and/or trees over comparisons.
+// A comparison of an int expression with a constant narrows the range of values that expression
+// may take. Any other comparison is treated as an opaque boolean atom. The procedure is sound but
+// incomplete: when it cannot decide within its budget, it answers that the conditions may hold.
+
+/// The maximum number of case splits a single query may explore.
+const SEARCH_BUDGET: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum IntConstraint {
+  Range(i64, i64),
+  NotEqual(i64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Formula {
+  Constant(bool),
+  IntAtom(SourceLanguageExpression, IntConstraint),
+  OpaqueAtom(SourceLanguageExpression, bool),
+  And(Vec<Formula>),
+  Or(Vec<Formula>),
+}
+
+fn negate_comparison_operator(operator: BinaryOperator) -> BinaryOperator {
+  match operator {
+    BinaryOperator::LT => BinaryOperator::GE,
+    BinaryOperator::LE => BinaryOperator::GT,
+    BinaryOperator::GT => BinaryOperator::LE,
+    BinaryOperator::GE => BinaryOperator::LT,
+    BinaryOperator::EQ => BinaryOperator::NE,
+    BinaryOperator::NE => BinaryOperator::EQ,
+    _ => operator,
+  }
+}
+
+/// Turns `c op e` into `e op' c`.
+fn mirror_comparison_operator(operator: BinaryOperator) -> BinaryOperator {
+  match operator {
+    BinaryOperator::LT => BinaryOperator::GT,
+    BinaryOperator::LE => BinaryOperator::GE,
+    BinaryOperator::GT => BinaryOperator::LT,
+    BinaryOperator::GE => BinaryOperator::LE,
+    _ => operator,
+  }
+}
+
+fn get_int_literal(expression: &SourceLanguageExpression) -> Option<i64> {
+  match expression {
+    SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal: LiteralValue::IntLiteral(i),
+    } => Some(*i as i64),
+    _ => None,
+  }
+}
+
+fn int_atom(term: &SourceLanguageExpression, operator: BinaryOperator, value: i64) -> Formula {
+  let (minimum, maximum) = (i32::MIN as i64, i32::MAX as i64);
+  let constraint = match operator {
+    BinaryOperator::LT => IntConstraint::Range(minimum, value - 1),
+    BinaryOperator::LE => IntConstraint::Range(minimum, value),
+    BinaryOperator::GT => IntConstraint::Range(value + 1, maximum),
+    BinaryOperator::GE => IntConstraint::Range(value, maximum),
+    BinaryOperator::EQ => IntConstraint::Range(value, value),
+    _ => IntConstraint::NotEqual(value),
+  };
+  Formula::IntAtom(*erase_line_numbers(term), constraint)
+}
+
+/// Builds the negation normal form of `expression` when `polarity` is true, or of its negation
+/// when `polarity` is false.
+fn to_formula(expression: &SourceLanguageExpression, polarity: bool) -> Formula {
+  if has_side_effect(expression) || !is_deterministic(expression) {
+    // Each evaluation may give a different answer, so nothing can be learned from it.
+    return Formula::Constant(true);
+  }
+  match expression {
+    SourceLanguageExpression::LiteralExpression {
+      line_number: _,
+      literal: LiteralValue::BoolLiteral(b),
+    } => Formula::Constant(*b == polarity),
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: BinaryOperator::AND,
+      e1,
+      e2,
+    } => {
+      let operands = vec![to_formula(e1, polarity), to_formula(e2, polarity)];
+      if polarity {
+        Formula::And(operands)
+      } else {
+        Formula::Or(operands)
+      }
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number: _,
+      operator: BinaryOperator::OR,
+      e1,
+      e2,
+    } => {
+      let operands = vec![to_formula(e1, polarity), to_formula(e2, polarity)];
+      if polarity {
+        Formula::Or(operands)
+      } else {
+        Formula::And(operands)
+      }
+    }
+    SourceLanguageExpression::BinaryExpression {
+      line_number,
+      operator,
+      e1,
+      e2,
+    } if matches!(
+      operator,
+      BinaryOperator::LT
+        | BinaryOperator::LE
+        | BinaryOperator::GT
+        | BinaryOperator::GE
+        | BinaryOperator::EQ
+        | BinaryOperator::NE
+    ) =>
+    {
+      let operator = if polarity {
+        *operator
+      } else {
+        negate_comparison_operator(*operator)
+      };
+      match (get_int_literal(e1), get_int_literal(e2)) {
+        (_, Some(value)) => int_atom(e1, operator, value),
+        (Some(value), None) => int_atom(e2, mirror_comparison_operator(operator), value),
+        (None, None) => {
+          // Canonicalize to `a == b` or `a < b` so that a comparison and its negation share a key.
+          let (canonical_operator, canonical_e1, canonical_e2, canonical_polarity) = match operator
+          {
+            BinaryOperator::EQ => (BinaryOperator::EQ, e1, e2, true),
+            BinaryOperator::NE => (BinaryOperator::EQ, e1, e2, false),
+            BinaryOperator::LT => (BinaryOperator::LT, e1, e2, true),
+            BinaryOperator::GE => (BinaryOperator::LT, e1, e2, false),
+            BinaryOperator::GT => (BinaryOperator::LT, e2, e1, true),
+            _ => (BinaryOperator::LT, e2, e1, false),
+          };
+          Formula::OpaqueAtom(
+            *erase_line_numbers(&SourceLanguageExpression::BinaryExpression {
+              line_number: *line_number,
+              operator: canonical_operator,
+              e1: canonical_e1.clone(),
+              e2: canonical_e2.clone(),
+            }),
+            canonical_polarity,
+          )
+        }
+      }
+    }
+    _ => Formula::OpaqueAtom(*erase_line_numbers(expression), polarity),
+  }
+}
+
+#[derive(Debug, Clone)]
+struct IntDomain {
+  lower: i64,
+  upper: i64,
+  excluded: BTreeSet<i64>,
+}
+
+impl IntDomain {
+  fn new() -> IntDomain {
+    IntDomain {
+      lower: i32::MIN as i64,
+      upper: i32::MAX as i64,
+      excluded: BTreeSet::new(),
+    }
+  }
+
+  /// Narrows the domain. Returns false when no value is left.
+  fn restrict(&mut self, constraint: &IntConstraint) -> bool {
+    match constraint {
+      IntConstraint::Range(lower, upper) => {
+        self.lower = self.lower.max(*lower);
+        self.upper = self.upper.min(*upper);
+      }
+      IntConstraint::NotEqual(value) => {
+        self.excluded.insert(*value);
+      }
+    }
+    while self.lower <= self.upper && self.excluded.contains(&self.lower) {
+      self.lower += 1;
+    }
+    while self.lower <= self.upper && self.excluded.contains(&self.upper) {
+      self.upper -= 1;
+    }
+    self.lower <= self.upper
+  }
+
+  fn evaluate(&self, constraint: &IntConstraint) -> Option<bool> {
+    match constraint {
+      IntConstraint::Range(lower, upper) => {
+        if *lower <= self.lower && self.upper <= *upper {
+          Some(true)
+        } else if *upper < self.lower
+          || self.upper < *lower
+          || (lower == upper && self.excluded.contains(lower))
+        {
+          Some(false)
+        } else {
+          None
+        }
+      }
+      IntConstraint::NotEqual(value) => {
+        if *value < self.lower || self.upper < *value || self.excluded.contains(value) {
+          Some(true)
+        } else if self.lower == *value && self.upper == *value {
+          Some(false)
+        } else {
+          None
+        }
+      }
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+struct SolverState {
+  int_domains: HashMap<SourceLanguageExpression, IntDomain>,
+  opaque_values: HashMap<SourceLanguageExpression, bool>,
+}
+
+impl SolverState {
+  fn evaluate(&self, formula: &Formula) -> Option<bool> {
+    match formula {
+      Formula::Constant(b) => Some(*b),
+      Formula::IntAtom(term, constraint) => match self.int_domains.get(term) {
+        Some(domain) => domain.evaluate(constraint),
+        None => IntDomain::new().evaluate(constraint),
+      },
+      Formula::OpaqueAtom(key, polarity) => self.opaque_values.get(key).map(|v| v == polarity),
+      Formula::And(operands) => {
+        let mut result = Some(true);
+        for operand in operands {
+          match self.evaluate(operand) {
+            Some(false) => return Some(false),
+            Some(true) => {}
+            None => result = None,
+          }
+        }
+        result
+      }
+      Formula::Or(operands) => {
+        let mut result = Some(false);
+        for operand in operands {
+          match self.evaluate(operand) {
+            Some(true) => return Some(true),
+            Some(false) => {}
+            None => result = None,
+          }
+        }
+        result
+      }
+    }
+  }
+
+  /// Records that an atom holds. Returns false on a contradiction.
+  fn assume_atom(&mut self, formula: &Formula) -> bool {
+    match formula {
+      Formula::IntAtom(term, constraint) => self
+        .int_domains
+        .entry(term.clone())
+        .or_insert_with(IntDomain::new)
+        .restrict(constraint),
+      Formula::OpaqueAtom(key, polarity) => match self.opaque_values.get(key) {
+        Some(value) => value == polarity,
+        None => {
+          self.opaque_values.insert(key.clone(), *polarity);
+          true
+        }
+      },
+      _ => true,
+    }
+  }
+}
+
+/// Decides whether all the formulas can hold together. Returns `None` when the budget runs out.
+fn solve(mut state: SolverState, formulas: Vec<Formula>, budget: &mut usize) -> Option<bool> {
+  if *budget == 0 {
+    return None;
+  }
+  *budget -= 1;
+
+  let mut worklist = formulas;
+  let mut disjunctions = Vec::new();
+  loop {
+    while let Some(formula) = worklist.pop() {
+      match formula {
+        Formula::Constant(true) => {}
+        Formula::Constant(false) => return Some(false),
+        Formula::And(operands) => worklist.extend(operands),
+        Formula::Or(operands) => disjunctions.push(operands),
+        atom => {
+          if !state.assume_atom(&atom) {
+            return Some(false);
+          }
+        }
+      }
+    }
+    // Drop satisfied disjunctions and disjuncts that can no longer hold. A disjunction with a
+    // single disjunct left must take it.
+    let mut remaining_disjunctions = Vec::new();
+    for operands in disjunctions {
+      let mut open_operands = Vec::new();
+      let mut satisfied = false;
+      for operand in operands {
+        match state.evaluate(&operand) {
+          Some(true) => {
+            satisfied = true;
+            break;
+          }
+          Some(false) => {}
+          None => open_operands.push(operand),
+        }
+      }
+      if satisfied {
+        continue;
+      }
+      match open_operands.len() {
+        0 => return Some(false),
+        1 => worklist.push(open_operands.pop().unwrap()),
+        _ => remaining_disjunctions.push(open_operands),
+      }
+    }
+    disjunctions = remaining_disjunctions;
+    if worklist.is_empty() {
+      break;
+    }
+  }
+
+  if disjunctions.is_empty() {
+    return Some(true);
+  }
+  let split_index = (0..disjunctions.len())
+    .min_by_key(|i| disjunctions[*i].len())
+    .unwrap();
+  let split_disjunction = disjunctions.swap_remove(split_index);
+  let mut result = Some(false);
+  for operand in split_disjunction {
+    let mut branch_formulas: Vec<Formula> = disjunctions.iter().cloned().map(Formula::Or).collect();
+    branch_formulas.push(operand);
+    match solve(state.clone(), branch_formulas, budget) {
+      Some(true) => return Some(true),
+      Some(false) => {}
+      None => result = None,
+    }
+  }
+  result
+}
+
+/// Returns whether the conjunction of the given conditions may hold in some state, where each
+/// condition is paired with whether it must hold (`true`) or must not hold (`false`).
+///
+/// A `false` result is a proof that the conditions can never hold together. A `true` result only
+/// means that no contradiction was found.
+pub fn may_hold_together(conditions: &[(&SourceLanguageExpression, bool)]) -> bool {
+  let formulas = conditions
+    .iter()
+    .map(|(condition, polarity)| to_formula(condition, *polarity))
+    .collect();
+  let state = SolverState {
+    int_domains: HashMap::new(),
+    opaque_values: HashMap::new(),
+  };
+  let mut budget = SEARCH_BUDGET;
+  solve(state, formulas, &mut budget) != Some(false)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::may_hold_together;
+  use crate::test_utilities::checked_condition;
+
+  fn may_hold(conditions: &[(&str, bool)]) -> bool {
+    let checked_conditions: Vec<_> = conditions
+      .iter()
+      .map(|(source, polarity)| (checked_condition(source), *polarity))
+      .collect();
+    let condition_references: Vec<_> = checked_conditions
+      .iter()
+      .map(|(condition, polarity)| (condition, *polarity))
+      .collect();
+    may_hold_together(&condition_references)
+  }
+
+  #[test]
+  fn contradictory_ranges_never_hold() {
+    assert!(!may_hold(&[("x > 5 && x < 3", true)]));
+    assert!(!may_hold(&[("x > 5", true), ("x > 3", false)]));
+    assert!(!may_hold(&[("3 < x", true), ("x <= 3", true)]));
+    assert!(may_hold(&[("x > 5 && x < 7", true)]));
+    assert!(may_hold(&[("x > 5", true), ("y > 3", false)]));
+  }
+
+  #[test]
+  fn equality_against_inequality() {
+    assert!(!may_hold(&[("x == 3", true), ("x != 3", true)]));
+    assert!(!may_hold(&[("x == 3", true), ("x == 3", false)]));
+    assert!(!may_hold(&[
+      ("x >= 5 && x <= 6", true),
+      ("x != 5 && x != 6", true)
+    ]));
+    assert!(may_hold(&[("x == 3", true), ("x != 4", true)]));
+    assert!(may_hold(&[
+      ("x >= 5 && x <= 7", true),
+      ("x != 5 && x != 6", true)
+    ]));
+  }
+
+  #[test]
+  fn disjunctions_split_into_cases() {
+    assert!(!may_hold(&[("(x < 0 || x > 10) && x == 5", true)]));
+    assert!(!may_hold(&[
+      ("x < 0 || y < 0", true),
+      ("x < 0", false),
+      ("y < 0", false)
+    ]));
+    assert!(may_hold(&[("(x < 0 || x > 10) && x == 11", true)]));
+  }
+
+  #[test]
+  fn boolean_atoms() {
+    assert!(!may_hold(&[("false", true)]));
+    assert!(!may_hold(&[("true", false)]));
+    assert!(may_hold(&[("true", true)]));
+    // Comparisons of two terms are opaque, but a comparison and its negation share an atom.
+    assert!(!may_hold(&[("x == y", true), ("x == y", false)]));
+    assert!(!may_hold(&[("x < y", true), ("y > x", false)]));
+    assert!(!may_hold(&[("x < y", true), ("x >= y", true)]));
+    assert!(may_hold(&[("x < y", true), ("y < x", false)]));
+  }
+
+  #[test]
+  fn nondeterministic_conditions_may_always_hold() {
+    assert!(may_hold(&[
+      ("random(3) == 1", true),
+      ("random(3) == 1", false)
+    ]));
+  }
+}
@@ -0,0 +1,126 @@
+use crate::ast::{LiteralValue, SourceLanguageExpression, SourceLanguageFunctionDefinition};
+use crate::evaluator::compile_time_evaluation;
+use crate::renamer::{count_expression_nodes, replace_variable_in_expression};
+use std::collections::HashMap;
+
+/// Specializes function bodies for the constant arguments of a call site before they are inlined,
+/// so that branches that are impossible for this call site are pruned before hoisting.
+///
+/// One specialized body is cached for each combination of constant arguments.
+pub struct Specializer {
+  cache: HashMap<(String, Vec<Option<LiteralValue>>), Box<SourceLanguageExpression>>,
+  /// The number of call sites that were specialized, including the ones served from the cache.
+  pub specialized_call_count: usize,
+  /// The number of expression nodes removed across all cached specialized bodies.
+  pub removed_node_count: usize,
+}
+
+impl Specializer {
+  pub fn new() -> Specializer {
+    Specializer {
+      cache: HashMap::new(),
+      specialized_call_count: 0,
+      removed_node_count: 0,
+    }
+  }
+
+  /// Returns the body of `function` where every parameter with a constant argument is replaced by
+  /// that constant and folded. Parameters whose argument is `None` are left untouched.
+  pub fn specialize(
+    &mut self,
+    function: &SourceLanguageFunctionDefinition,
+    constant_arguments: &[Option<LiteralValue>],
+  ) -> Box<SourceLanguageExpression> {
+    if constant_arguments.iter().all(|argument| argument.is_none()) {
+      return function.body.clone();
+    }
+    self.specialized_call_count += 1;
+    let key = (function.identifier.clone(), constant_arguments.to_vec());
+    if let Some(specialized_body) = self.cache.get(&key) {
+      return specialized_body.clone();
+    }
+
+    let mut replacement_map = HashMap::new();
+    for ((name, _), constant_argument) in function.function_arguments.iter().zip(constant_arguments)
+    {
+      if let Some(literal) = constant_argument {
+        replacement_map.insert(
+          name.clone(),
+          Box::new(SourceLanguageExpression::LiteralExpression {
+            line_number: function.line_number,
+            literal: *literal,
+          }),
+        );
+      }
+    }
+    let specialized_body = compile_time_evaluation(&replace_variable_in_expression(
+      &function.body,
+      &replacement_map,
+    ));
+    self.removed_node_count += count_expression_nodes(&function.body)
+      .saturating_sub(count_expression_nodes(&specialized_body));
+    self.cache.insert(key, specialized_body.clone());
+    specialized_body
+  }
+
+  /// The number of distinct specialized bodies that were created.
+  pub fn specialized_body_count(&self) -> usize {
+    self.cache.len()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ast::SourceLanguageProgram;
+  use crate::renamer::erase_line_numbers;
+  use crate::test_utilities::checked_program;
+
+  fn step_program() -> SourceLanguageProgram {
+    checked_program(
+      "var x = 0;\n\
+       fun step(n: int, m: int): int = if n == 0 then m else m + x\n\
+       fun main(): void = wait()",
+    )
+  }
+
+  #[test]
+  fn call_sites_with_the_same_constants_reuse_one_body() {
+    let program = step_program();
+    let step = &program.function_definitions[0];
+    let mut specializer = Specializer::new();
+    let first = specializer.specialize(step, &[Some(LiteralValue::IntLiteral(0)), None]);
+    let second = specializer.specialize(step, &[Some(LiteralValue::IntLiteral(0)), None]);
+    assert_eq!(first, second);
+    assert_eq!(2, specializer.specialized_call_count);
+    assert_eq!(1, specializer.specialized_body_count());
+    let identity = checked_program("fun g(m: int): int = m\nfun main(): void = wait()");
+    assert_eq!(
+      erase_line_numbers(&identity.function_definitions[0].body),
+      erase_line_numbers(&first)
+    );
+  }
+
+  #[test]
+  fn different_constants_get_separate_bodies() {
+    let program = step_program();
+    let step = &program.function_definitions[0];
+    let mut specializer = Specializer::new();
+    let zero = specializer.specialize(step, &[Some(LiteralValue::IntLiteral(0)), None]);
+    let one = specializer.specialize(step, &[Some(LiteralValue::IntLiteral(1)), None]);
+    assert_ne!(erase_line_numbers(&zero), erase_line_numbers(&one));
+    assert_eq!(2, specializer.specialized_call_count);
+    assert_eq!(2, specializer.specialized_body_count());
+    assert!(specializer.removed_node_count > 0);
+  }
+
+  #[test]
+  fn calls_without_constants_are_not_specialized() {
+    let program = step_program();
+    let step = &program.function_definitions[0];
+    let mut specializer = Specializer::new();
+    assert_eq!(step.body, specializer.specialize(step, &[None, None]));
+    assert_eq!(0, specializer.specialized_call_count);
+    assert_eq!(0, specializer.specialized_body_count());
+  }
+}
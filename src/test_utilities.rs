@@ -0,0 +1,77 @@
+use crate::ast::{
+  FullyInlinedProgram, IfElseBlock, SourceLanguageExpression, SourceLanguageProgram,
+};
+use crate::checker::get_type_checked_program;
+use crate::compiler::{compile_to_critter_lang, MemoryLayout};
+use crate::runtime::get_critter_world_runtime;
+use crate::sequencer::LoweringMode;
+
+/// Type checks `source` as the body of a function with the given return type, next to the int
+/// globals `x`, `y` and `z`, and returns the checked body.
+pub fn checked_expression(return_type: &str, source: &str) -> SourceLanguageExpression {
+  let program_source = format!(
+    "var x = 0;\nvar y = 0;\nvar z = 0;\nfun f(): {:} = {:}\nfun main(): void = wait()",
+    return_type, source
+  );
+  *checked_program(&program_source).function_definitions[0]
+    .body
+    .clone()
+}
+
+/// Type checks a whole program that must pass the checker.
+pub fn checked_program(source: &str) -> SourceLanguageProgram {
+  get_type_checked_program(get_critter_world_runtime(), source.to_string())
+    .unwrap_or_else(|errors| panic!("{:?}", errors))
+}
+
+/// Type checks `source` as a bool condition over the int globals `x`, `y` and `z`.
+pub fn checked_condition(source: &str) -> SourceLanguageExpression {
+  checked_expression("bool", source)
+}
+
+/// Builds a lowered program over the int globals `x`, `y` and `z` from pairs of condition and
+/// action sources.
+pub fn inlined_program(rules: &[(&str, &str)]) -> FullyInlinedProgram {
+  FullyInlinedProgram {
+    global_variable_definitions: checked_program(
+      "var x = 0;\nvar y = 0;\nvar z = 0;\nfun main(): void = wait()",
+    )
+    .global_variable_definitions,
+    if_else_blocks: rules
+      .iter()
+      .map(|(condition, action)| IfElseBlock {
+        condition: checked_condition(condition),
+        action: checked_expression("void", action),
+      })
+      .collect(),
+    notes: Vec::new(),
+  }
+}
+
+/// Returns the errors of the checker for a whole program, which are empty when it passes.
+pub fn check_errors(source: &str) -> Vec<String> {
+  match get_type_checked_program(get_critter_world_runtime(), source.to_string()) {
+    Ok(_) => Vec::new(),
+    Err(errors) => errors,
+  }
+}
+
+/// Compiles a whole program with the default options and returns its rules.
+pub fn compile(source: &str) -> Result<String, Vec<String>> {
+  compile_with_lowering_mode(source, LoweringMode::Substitute)
+}
+
+/// Compiles a whole program with the given lowering mode and returns its rules.
+pub fn compile_with_lowering_mode(
+  source: &str,
+  lowering_mode: LoweringMode,
+) -> Result<String, Vec<String>> {
+  let program = get_type_checked_program(get_critter_world_runtime(), source.to_string())?;
+  compile_to_critter_lang(&program, 20, lowering_mode, MemoryLayout::default())
+    .map(|compiled_program| compiled_program.code)
+}
+
+/// Returns the rules of compiled critter code, one per line.
+pub fn rules(code: &str) -> Vec<&str> {
+  code.lines().filter(|line| !line.is_empty()).collect()
+}
@@ -1,334 +1,371 @@
-use crate::ast::{BinaryOperator, IfElseBlock, LiteralValue, SourceLanguageExpression};
+use crate::ast::{BinaryOperator, IfElseBlock, SourceLanguageExpression};
+use crate::decision_diagram::{negate_condition, DecisionDiagram, NodeId};
+use crate::evaluator::{compile_time_evaluation, has_side_effect, is_deterministic};
+use crate::minimizer::join_conjuncts;
+use crate::propagator::collect_read_variables;
+use crate::renamer::replace_variable_in_expression;
+use crate::sequencer::is_recomputable;
+use std::collections::{HashMap, HashSet};
 
-fn hoist_if_else(expression: &SourceLanguageExpression) -> Box<SourceLanguageExpression> {
-  match &expression {
-    SourceLanguageExpression::LiteralExpression {
-      line_number,
-      literal,
-    } => Box::new(SourceLanguageExpression::LiteralExpression {
-      line_number: *line_number,
-      literal: *literal,
-    }),
-    SourceLanguageExpression::VariableExpression {
-      line_number,
+/// Rule lists longer than this are reported, since each rule adds to the complexity cost of the
+/// critter.
+pub const LARGE_RULE_COUNT: usize = 100;
+
+/// The values that the assignments made so far along a path leave in the variables they write, in
+/// terms of the values at the start of the turn. `None` marks a value that cannot be recomputed,
+/// because computing it again may give a different result.
+type PendingWrites = HashMap<String, Option<Box<SourceLanguageExpression>>>;
+
+/// The pending writes of some variables, sorted by variable.
+type PendingWriteList = Vec<(String, Option<Box<SourceLanguageExpression>>)>;
+
+/// Replaces the reads of the variables written earlier along the path with the values written.
+fn substitute_writes(
+  expression: &SourceLanguageExpression,
+  writes: &PendingWrites,
+) -> Box<SourceLanguageExpression> {
+  let replacement_map = writes
+    .iter()
+    .filter_map(|(identifier, value)| {
+      value
+        .as_ref()
+        .map(|value| (identifier.clone(), value.clone()))
+    })
+    .collect();
+  replace_variable_in_expression(expression, &replacement_map)
+}
+
+/// Extends `writes` with the assignments of a lowered, if-free chain.
+fn record_writes(chain: &SourceLanguageExpression, writes: &PendingWrites) -> PendingWrites {
+  let mut chain_writes = writes.clone();
+  let record = |expression: &SourceLanguageExpression, chain_writes: &mut PendingWrites| {
+    if let SourceLanguageExpression::AssignmentExpression {
+      line_number: _,
       identifier,
-    } => Box::new(SourceLanguageExpression::VariableExpression {
-      line_number: *line_number,
-      identifier: identifier.clone(),
-    }),
-    SourceLanguageExpression::FunctionCallExpression {
+      assigned_expression,
+    } = expression
+    {
+      let unrecomputable_variables: HashSet<String> = chain_writes
+        .iter()
+        .filter(|(_, value)| value.is_none())
+        .map(|(identifier, _)| identifier.clone())
+        .collect();
+      let value = if is_recomputable(assigned_expression, &unrecomputable_variables) {
+        Some(substitute_writes(assigned_expression, chain_writes))
+      } else {
+        None
+      };
+      chain_writes.insert(identifier.clone(), value);
+    }
+  };
+  match chain {
+    SourceLanguageExpression::ChainExpression {
       line_number: _,
-      static_type: _,
-      function_name: _,
-      function_arguments: _,
-    } => Box::new(expression.clone()), // Assume no more if-else inside leaf function calls.
+      expressions,
+    } => {
+      for sub_expression in expressions {
+        record(sub_expression, &mut chain_writes);
+      }
+    }
+    _ => record(chain, &mut chain_writes),
+  }
+  chain_writes
+}
+
+/// Rewrites the ifs that a condition evaluates to, like the inner one of `if (if a then b else c)
+/// then x else y`, into `(a && b) || (!a && c)`, with `!a` spelled by flipping the comparisons in
+/// `a`. The condition is then tested as a single and/or tree, instead of every path through it
+/// becoming rules of its own. An if whose condition may give a different answer when it is
+/// evaluated twice stays as it is.
+fn flatten_conditional_condition(
+  condition: &SourceLanguageExpression,
+) -> Box<SourceLanguageExpression> {
+  match condition {
     SourceLanguageExpression::BinaryExpression {
       line_number,
-      operator,
+      operator: operator @ (BinaryOperator::AND | BinaryOperator::OR),
+      e1,
+      e2,
+    } => Box::new(SourceLanguageExpression::BinaryExpression {
+      line_number: *line_number,
+      operator: *operator,
+      e1: flatten_conditional_condition(e1),
+      e2: flatten_conditional_condition(e2),
+    }),
+    SourceLanguageExpression::IfElseExpression {
+      line_number,
+      condition,
       e1,
       e2,
     } => {
-      let hoisted_e1 = hoist_if_else(e1);
-      let hoisted_e2 = hoist_if_else(e2);
-      match (*hoisted_e1, *hoisted_e2) {
-        (
-          SourceLanguageExpression::IfElseExpression {
-            line_number: _,
-            condition: e1c,
-            e1: e1e1,
-            e2: e1e2,
-          },
-          SourceLanguageExpression::IfElseExpression {
-            line_number: _,
-            condition: e2c,
-            e1: e2e1,
-            e2: e2e2,
-          },
-        ) => Box::new(SourceLanguageExpression::IfElseExpression {
-          line_number: *line_number,
-          condition: Box::new(SourceLanguageExpression::BinaryExpression {
-            line_number: *line_number,
-            operator: BinaryOperator::AND,
-            e1: e1c.clone(),
-            e2: e2c.clone(),
-          }),
-          e1: hoist_if_else(&SourceLanguageExpression::BinaryExpression {
-            line_number: *line_number,
-            operator: *operator,
-            e1: e1e1.clone(),
-            e2: e2e1.clone(),
-          }),
-          e2: Box::new(SourceLanguageExpression::IfElseExpression {
+      let condition = flatten_conditional_condition(condition);
+      let e1 = flatten_conditional_condition(e1);
+      let e2 = flatten_conditional_condition(e2);
+      let negated_condition = match negate_condition(&condition) {
+        Some(negated_condition)
+          if is_deterministic(&condition)
+            && !has_side_effect(&condition)
+            && !has_side_effect(&e1)
+            && !has_side_effect(&e2) =>
+        {
+          negated_condition
+        }
+        _ => {
+          return Box::new(SourceLanguageExpression::IfElseExpression {
             line_number: *line_number,
-            condition: e1c.clone(),
-            e1: hoist_if_else(&SourceLanguageExpression::BinaryExpression {
-              line_number: *line_number,
-              operator: *operator,
-              e1: e1e1.clone(),
-              e2: e2e2.clone(),
-            }),
-            e2: Box::new(SourceLanguageExpression::IfElseExpression {
-              line_number: *line_number,
-              condition: e2c.clone(),
-              e1: hoist_if_else(&SourceLanguageExpression::BinaryExpression {
-                line_number: *line_number,
-                operator: *operator,
-                e1: e1e2.clone(),
-                e2: e2e1.clone(),
-              }),
-              e2: hoist_if_else(&SourceLanguageExpression::BinaryExpression {
-                line_number: *line_number,
-                operator: *operator,
-                e1: e1e2.clone(),
-                e2: e2e2.clone(),
-              }),
-            }),
-          }),
-        }),
-        (
-          SourceLanguageExpression::IfElseExpression {
-            line_number: _,
-            condition: e1c,
-            e1: e1e1,
-            e2: e1e2,
-          },
-          hoisted_e2_unboxed,
-        ) => Box::new(SourceLanguageExpression::IfElseExpression {
+            condition,
+            e1,
+            e2,
+          })
+        }
+      };
+      compile_time_evaluation(&SourceLanguageExpression::BinaryExpression {
+        line_number: *line_number,
+        operator: BinaryOperator::OR,
+        e1: Box::new(SourceLanguageExpression::BinaryExpression {
           line_number: *line_number,
-          condition: e1c,
-          e1: hoist_if_else(&SourceLanguageExpression::BinaryExpression {
-            line_number: *line_number,
-            operator: *operator,
-            e1: e1e1,
-            e2: Box::new(hoisted_e2_unboxed.clone()),
-          }),
-          e2: hoist_if_else(&SourceLanguageExpression::BinaryExpression {
-            line_number: *line_number,
-            operator: *operator,
-            e1: e1e2,
-            e2: Box::new(hoisted_e2_unboxed.clone()),
-          }),
+          operator: BinaryOperator::AND,
+          e1: condition,
+          e2: e1,
         }),
-        (
-          hoisted_e1_unboxed,
-          SourceLanguageExpression::IfElseExpression {
-            line_number: _,
-            condition: e2c,
-            e1: e2e1,
-            e2: e2e2,
-          },
-        ) => Box::new(SourceLanguageExpression::IfElseExpression {
+        e2: Box::new(SourceLanguageExpression::BinaryExpression {
           line_number: *line_number,
-          condition: e2c,
-          e1: hoist_if_else(&SourceLanguageExpression::BinaryExpression {
-            line_number: *line_number,
-            operator: *operator,
-            e1: Box::new(hoisted_e1_unboxed.clone()),
-            e2: e2e1,
-          }),
-          e2: hoist_if_else(&SourceLanguageExpression::BinaryExpression {
-            line_number: *line_number,
-            operator: *operator,
-            e1: Box::new(hoisted_e1_unboxed.clone()),
-            e2: e2e2,
-          }),
+          operator: BinaryOperator::AND,
+          e1: negated_condition,
+          e2,
         }),
-        (hoisted_e1_unboxed, hoisted_e2_unboxed) => {
-          Box::new(SourceLanguageExpression::BinaryExpression {
+      })
+    }
+    _ => Box::new(condition.clone()),
+  }
+}
+
+/// Builds the decision diagram of an expression, whose leaves are the if-free expressions it may
+/// evaluate to.
+fn lower_to_diagram(
+  expression: &SourceLanguageExpression,
+  writes: &PendingWrites,
+  diagram: &mut DecisionDiagram,
+) -> NodeId {
+  match expression {
+    SourceLanguageExpression::LiteralExpression { .. }
+    | SourceLanguageExpression::VariableExpression { .. } => diagram.leaf(expression),
+    SourceLanguageExpression::FunctionCallExpression {
+      line_number,
+      static_type,
+      function_name,
+      function_arguments,
+    } => {
+      // Hoist the ifs out of the arguments, so that each path calls the function with if-free
+      // arguments.
+      let mut call_diagram = diagram.leaf(&SourceLanguageExpression::FunctionCallExpression {
+        line_number: *line_number,
+        static_type: *static_type,
+        function_name: function_name.clone(),
+        function_arguments: Vec::new(),
+      });
+      for argument in function_arguments {
+        let argument_diagram = lower_to_diagram(argument, writes, diagram);
+        call_diagram = diagram.combine(call_diagram, argument_diagram, &|call, leaf| {
+          let mut call_arguments = match call {
+            SourceLanguageExpression::FunctionCallExpression {
+              line_number: _,
+              static_type: _,
+              function_name: _,
+              function_arguments,
+            } => function_arguments.clone(),
+            _ => Vec::new(),
+          };
+          call_arguments.push(Box::new(leaf.clone()));
+          SourceLanguageExpression::FunctionCallExpression {
             line_number: *line_number,
-            operator: *operator,
-            e1: Box::new(hoisted_e1_unboxed),
-            e2: Box::new(hoisted_e2_unboxed),
-          })
-        }
+            static_type: *static_type,
+            function_name: function_name.clone(),
+            function_arguments: call_arguments,
+          }
+        });
       }
+      call_diagram
     }
-    SourceLanguageExpression::IfElseExpression {
+    SourceLanguageExpression::BinaryExpression {
       line_number,
+      operator,
+      e1,
+      e2,
+    } => {
+      let d1 = lower_to_diagram(e1, writes, diagram);
+      let d2 = lower_to_diagram(e2, writes, diagram);
+      diagram.combine(d1, d2, &|l1, l2| {
+        *compile_time_evaluation(&SourceLanguageExpression::BinaryExpression {
+          line_number: *line_number,
+          operator: *operator,
+          e1: Box::new(l1.clone()),
+          e2: Box::new(l2.clone()),
+        })
+      })
+    }
+    SourceLanguageExpression::IfElseExpression {
+      line_number: _,
       condition,
       e1,
       e2,
-    } => Box::new(SourceLanguageExpression::IfElseExpression {
-      line_number: *line_number,
-      condition: hoist_if_else(condition), // TODO ???
-      e1: hoist_if_else(e1),
-      e2: hoist_if_else(e2),
-    }),
+    } => {
+      // Conditions are tested before any update of the rule happens, so they must read the values
+      // written earlier along the path instead. Ifs that remain in the flattened condition lower
+      // to a diagram over the atoms of their branches.
+      let condition = flatten_conditional_condition(&substitute_writes(condition, writes));
+      let condition_values = lower_to_diagram(&condition, writes, diagram);
+      let condition_diagram = diagram.values_to_condition(condition_values);
+      let d1 = lower_to_diagram(e1, writes, diagram);
+      let d2 = lower_to_diagram(e2, writes, diagram);
+      diagram.if_then_else(condition_diagram, d1, d2)
+    }
     SourceLanguageExpression::AssignmentExpression {
       line_number,
       identifier,
       assigned_expression,
-    } => match *hoist_if_else(assigned_expression) {
-      SourceLanguageExpression::IfElseExpression {
-        line_number: _,
-        condition,
-        e1,
-        e2,
-      } => Box::new(SourceLanguageExpression::IfElseExpression {
-        line_number: *line_number,
-        condition,
-        e1: hoist_if_else(&SourceLanguageExpression::AssignmentExpression {
-          line_number: *line_number,
-          identifier: identifier.clone(),
-          assigned_expression: e1,
-        }),
-        e2: hoist_if_else(&SourceLanguageExpression::AssignmentExpression {
+    } => {
+      let assigned_diagram = lower_to_diagram(assigned_expression, writes, diagram);
+      diagram.map(assigned_diagram, &|leaf| {
+        SourceLanguageExpression::AssignmentExpression {
           line_number: *line_number,
           identifier: identifier.clone(),
-          assigned_expression: e2,
-        }),
-      }),
-      _ => Box::new((*expression).clone()),
-    },
+          assigned_expression: Box::new(leaf.clone()),
+        }
+      })
+    }
     SourceLanguageExpression::ChainExpression {
       line_number,
       expressions,
     } => {
-      if expressions.len() == 0 {
-        Box::new((*expression).clone())
-      } else if expressions.len() == 1 {
-        expressions[0].clone()
-      } else {
-        let mut mutable_expressions = expressions.clone();
-        let hoisted_e2 = hoist_if_else(&mutable_expressions.pop().unwrap());
-        let hoisted_e1 = hoist_if_else(&SourceLanguageExpression::ChainExpression {
-          line_number: *line_number,
-          expressions: mutable_expressions,
-        });
-        match (*hoisted_e1, *hoisted_e2) {
-          (
-            SourceLanguageExpression::IfElseExpression {
-              line_number: _,
-              condition: e1c,
-              e1: e1e1,
-              e2: e1e2,
-            },
-            SourceLanguageExpression::IfElseExpression {
-              line_number: _,
-              condition: e2c,
-              e1: e2e1,
-              e2: e2e2,
-            },
-          ) => Box::new(SourceLanguageExpression::IfElseExpression {
-            line_number: *line_number,
-            condition: Box::new(SourceLanguageExpression::BinaryExpression {
-              line_number: *line_number,
-              operator: BinaryOperator::AND,
-              e1: e1c.clone(),
-              e2: e2c.clone(),
-            }),
-            e1: hoist_if_else(&SourceLanguageExpression::ChainExpression {
-              line_number: *line_number,
-              expressions: vec![e1e1.clone(), e2e1.clone()],
-            }),
-            e2: Box::new(SourceLanguageExpression::IfElseExpression {
-              line_number: *line_number,
-              condition: e1c.clone(),
-              e1: hoist_if_else(&SourceLanguageExpression::ChainExpression {
-                line_number: *line_number,
-                expressions: vec![e1e1.clone(), e2e2.clone()],
-              }),
-              e2: Box::new(SourceLanguageExpression::IfElseExpression {
-                line_number: *line_number,
-                condition: e2c.clone(),
-                e1: hoist_if_else(&SourceLanguageExpression::ChainExpression {
-                  line_number: *line_number,
-                  expressions: vec![e1e2.clone(), e2e1.clone()],
-                }),
-                e2: hoist_if_else(&SourceLanguageExpression::ChainExpression {
-                  line_number: *line_number,
-                  expressions: vec![e1e2.clone(), e2e2.clone()],
-                }),
-              }),
-            }),
-          }),
-          (
-            SourceLanguageExpression::IfElseExpression {
-              line_number: _,
-              condition: e1c,
-              e1: e1e1,
-              e2: e1e2,
-            },
-            hoisted_e2_unboxed,
-          ) => Box::new(SourceLanguageExpression::IfElseExpression {
-            line_number: *line_number,
-            condition: e1c,
-            e1: hoist_if_else(&SourceLanguageExpression::ChainExpression {
-              line_number: *line_number,
-              expressions: vec![e1e1, Box::new(hoisted_e2_unboxed.clone())],
-            }),
-            e2: hoist_if_else(&SourceLanguageExpression::ChainExpression {
-              line_number: *line_number,
-              expressions: vec![e1e2, Box::new(hoisted_e2_unboxed.clone())],
-            }),
-          }),
-          (
-            hoisted_e1_unboxed,
-            SourceLanguageExpression::IfElseExpression {
-              line_number: _,
-              condition: e2c,
-              e1: e2e1,
-              e2: e2e2,
-            },
-          ) => Box::new(SourceLanguageExpression::IfElseExpression {
-            line_number: *line_number,
-            condition: e2c,
-            e1: hoist_if_else(&SourceLanguageExpression::ChainExpression {
-              line_number: *line_number,
-              expressions: vec![Box::new(hoisted_e1_unboxed.clone()), e2e1],
-            }),
-            e2: hoist_if_else(&SourceLanguageExpression::ChainExpression {
-              line_number: *line_number,
-              expressions: vec![Box::new(hoisted_e1_unboxed.clone()), e2e2],
-            }),
-          }),
-          (hoisted_e1_unboxed, hoisted_e2_unboxed) => {
-            Box::new(SourceLanguageExpression::ChainExpression {
+      if expressions.len() == 1 {
+        return lower_to_diagram(&expressions[0], writes, diagram);
+      }
+      let mut chain_diagram = diagram.leaf(&SourceLanguageExpression::ChainExpression {
+        line_number: *line_number,
+        expressions: Vec::new(),
+      });
+      for sub_expression in expressions {
+        let mut read_variables = HashSet::new();
+        collect_read_variables(sub_expression, &mut read_variables);
+        // Paths that leave the same values in the variables the sub-expression reads share its
+        // diagram.
+        let mut lowered_sub_expressions: HashMap<PendingWriteList, NodeId> = HashMap::new();
+        chain_diagram = diagram.flat_map(chain_diagram, &mut |diagram, chain| {
+          let chain_writes = record_writes(chain, writes);
+          let mut relevant_writes: PendingWriteList = chain_writes
+            .iter()
+            .filter(|(identifier, _)| read_variables.contains(*identifier))
+            .map(|(identifier, value)| (identifier.clone(), value.clone()))
+            .collect();
+          relevant_writes.sort_by(|w1, w2| w1.0.cmp(&w2.0));
+          let sub_expression_diagram = match lowered_sub_expressions.get(&relevant_writes) {
+            Some(node) => *node,
+            None => {
+              let node = lower_to_diagram(sub_expression, &chain_writes, diagram);
+              lowered_sub_expressions.insert(relevant_writes, node);
+              node
+            }
+          };
+          diagram.map(sub_expression_diagram, &|leaf| {
+            let mut chain_expressions = match chain {
+              SourceLanguageExpression::ChainExpression {
+                line_number: _,
+                expressions,
+              } => expressions.clone(),
+              _ => vec![Box::new(chain.clone())],
+            };
+            chain_expressions.push(Box::new(leaf.clone()));
+            SourceLanguageExpression::ChainExpression {
               line_number: *line_number,
-              expressions: vec![Box::new(hoisted_e1_unboxed), Box::new(hoisted_e2_unboxed)],
-            })
-          }
-        }
+              expressions: chain_expressions,
+            }
+          })
+        });
       }
+      chain_diagram
     }
   }
 }
 
+/// Lowers an expression to a list of rules, one for every path through its decision diagram. The
+/// diagram tests every distinct condition once along a path and drops the paths that contradict
+/// themselves, so the number of rules grows with the number of distinct feasible paths rather than
+/// with the number of ways to nest the ifs.
 pub fn transform_to_if_else_blocks(expression: &SourceLanguageExpression) -> Vec<IfElseBlock> {
-  match &*hoist_if_else(&expression) {
-    SourceLanguageExpression::IfElseExpression {
-      line_number: _,
-      condition,
-      e1,
-      e2,
-    } => {
-      let e1_list = transform_to_if_else_blocks(e1);
-      let mut e2_list = transform_to_if_else_blocks(e2).clone();
-      let mut list = Vec::new();
-      for IfElseBlock {
-        condition: c,
-        action,
-      } in e1_list
-      {
-        list.push(IfElseBlock {
-          condition: SourceLanguageExpression::BinaryExpression {
-            line_number: 1,
-            operator: BinaryOperator::AND,
-            e1: condition.clone(),
-            e2: Box::new(c),
-          },
-          action,
-        });
-      }
-      list.append(&mut e2_list);
-      list
-    }
-    hoisted => vec![IfElseBlock {
-      condition: SourceLanguageExpression::LiteralExpression {
-        line_number: 1,
-        literal: LiteralValue::BoolLiteral(true),
-      },
-      action: (*hoisted).clone(),
-    }],
+  let mut diagram = DecisionDiagram::new();
+  let lowered_root = lower_to_diagram(expression, &HashMap::new(), &mut diagram);
+  let root = diagram.prune_infeasible_paths(lowered_root);
+  diagram
+    .paths(root)
+    .into_iter()
+    .map(|(conjuncts, action)| IfElseBlock {
+      condition: join_conjuncts(&conjuncts),
+      action,
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::renamer::erase_line_numbers;
+  use crate::test_utilities::{checked_condition, checked_expression, compile, rules};
+
+  fn flattened(source: &str) -> SourceLanguageExpression {
+    *erase_line_numbers(&flatten_conditional_condition(&checked_condition(source)))
+  }
+
+  fn expected(source: &str) -> SourceLanguageExpression {
+    *erase_line_numbers(&checked_condition(source))
+  }
+
+  #[test]
+  fn conditional_conditions_become_and_or_trees() {
+    assert_eq!(
+      flattened("if x == 1 then y == 2 else z > 0"),
+      expected("(x == 1 && y == 2) || (x != 1 && z > 0)")
+    );
+    assert_eq!(
+      flattened("if x < 1 || y >= 2 then z == 0 else false"),
+      expected("(x < 1 || y >= 2) && z == 0")
+    );
+  }
+
+  #[test]
+  fn nested_conditional_conditions_are_flattened() {
+    assert_eq!(
+      flattened("if x > 0 then (if y > 0 then true else z > 5) else false"),
+      expected("x > 0 && (y > 0 || (y <= 0 && z > 5))")
+    );
+    assert_eq!(
+      flattened("(if (if x == 1 then y == 2 else z > 0) then y < 3 else true) && x < 5"),
+      expected(
+        "((((x == 1 && y == 2) || (x != 1 && z > 0)) && y < 3) \
+         || ((x != 1 || y != 2) && (x == 1 || z <= 0))) && x < 5"
+      )
+    );
+  }
+
+  #[test]
+  fn nondeterministic_conditions_stay_conditional() {
+    let condition = "if random(2) == 0 then x > 0 else y > 0";
+    assert_eq!(flattened(condition), expected(condition));
+  }
+
+  #[test]
+  fn flattened_conditions_become_a_single_rule() {
+    let if_else_blocks = transform_to_if_else_blocks(&checked_expression(
+      "void",
+      "if (if x == 1 then y == 2 else (if z > 0 then y < 1 else false)) then forward() else left()",
+    ));
+    assert_eq!(if_else_blocks.len(), 2);
+  }
+
+  #[test]
+  fn conditional_conditions_example_has_one_rule_per_branch() {
+    let code = compile(include_str!("../examples/conditional-conditions.txt")).unwrap();
+    // The initialization rule, then one rule for each of the four branches of `main`.
+    assert_eq!(rules(&code).len(), 5);
   }
 }